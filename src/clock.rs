@@ -0,0 +1,75 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+lazy_static! {
+    // Shared by all the default options, which then compare equal
+    static ref SYSTEM_CLOCK: SharedClock = SharedClock::new(SystemClock);
+}
+
+// Tells the time and waits for it to pass, the built-in implementation
+// goes by the system's clock; tests get to swap in one that never sleeps
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new<C>(clock: C) -> SharedClock
+    where
+        C: Clock + 'static,
+    {
+        SharedClock(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        self.0.system_time()
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> SharedClock {
+        SYSTEM_CLOCK.clone()
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedClock")
+    }
+}
+
+impl PartialEq for SharedClock {
+    fn eq(&self, other: &SharedClock) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
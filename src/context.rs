@@ -0,0 +1,17 @@
+use stats::ArchiveStats;
+use std::collections::HashMap;
+
+// State shared across the whole walk, nested documents included
+#[derive(Debug, Default)]
+pub struct Context {
+    // Retrieved assets, keyed by what was asked for, see retrieve_cached_asset()
+    pub asset_cache: HashMap<String, String>,
+    pub embedded_size: usize,
+    pub frames_size: usize,
+    // HTML imports being embedded, so that documents importing each other don't loop
+    pub imports: Vec<String>,
+    // Integrity metadata assets are expected to match, by their URL, see verify_integrity()
+    pub integrity: HashMap<String, String>,
+    // Tallied up as assets get embedded or fail to, see walk_and_embed_assets()
+    pub stats: ArchiveStats,
+}
@@ -0,0 +1,461 @@
+use context::Context;
+use http::{is_data_url, resolve_url, retrieve_cached_asset};
+use opts::{AssetAction, AssetCategory, Options};
+use regex::{Captures, Regex};
+use url::ParseError;
+use utils::{decode_entities, guess_mimetype, TRANSPARENT_PIXEL};
+
+lazy_static! {
+    static ref CSS_REFERENCE: Regex = Regex::new(
+        r#"(?i)@import\s+(?P<import>url\(\s*(?:"[^"]*"|'[^']*'|[^)\s]*)\s*\)|"[^"]*"|'[^']*')\s*(?P<media>[^;]*);|(?P<image_set>(?:-webkit-)?image-set\((?:[^()]|\([^()]*\))*\))|url\(\s*(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)'|(?P<bare>[^)\s]*))\s*\)"#
+    ).unwrap();
+    static ref IMAGE_SET_CANDIDATE: Regex = Regex::new(
+        r#"(?i)(?P<type>type\([^)]*\))|url\(\s*(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)'|(?P<bare>[^)\s]*))\s*\)|"(?P<string_dq>[^"]*)"|'(?P<string_sq>[^']*)'"#
+    ).unwrap();
+    // Both the current /*# and the deprecated /*@ form
+    static ref SOURCE_MAP: Regex = Regex::new(
+        r"/\*[#@][ \t]*sourceMappingURL[ \t]*=[ \t]*(?P<url>[^\s*]+)[ \t]*\*/"
+    ).unwrap();
+}
+
+pub fn embed_css(url: &str, css: &str, opts: &Options, ctx: &mut Context) -> String {
+    embed_stylesheet(url, css, opts, &mut vec![url.to_string()], ctx)
+}
+
+fn embed_stylesheet(
+    url: &str,
+    css: &str,
+    opts: &Options,
+    ancestors: &mut Vec<String>,
+    ctx: &mut Context,
+) -> String {
+    let css = embed_source_map(url, css, opts, ctx);
+
+    CSS_REFERENCE
+        .replace_all(&css, |caps: &Captures| {
+            if let Some(import) = caps.name("import") {
+                let media = caps.name("media").map(|media| media.as_str()).unwrap_or("");
+                embed_import(url, import.as_str(), media.trim(), opts, ancestors, ctx)
+                    .unwrap_or_else(|| caps[0].to_string())
+            } else if let Some(image_set) = caps.name("image_set") {
+                if opts.removes_images() {
+                    "none".to_string()
+                } else {
+                    embed_image_set(url, image_set.as_str(), opts, ctx)
+                }
+            } else {
+                embed_url_function(url, caps, None, opts, ctx)
+            }
+        })
+        .to_string()
+}
+
+fn embed_import(
+    url: &str,
+    import: &str,
+    media: &str,
+    opts: &Options,
+    ancestors: &mut Vec<String>,
+    ctx: &mut Context,
+) -> Option<String> {
+    let reference = unquote(import.trim_start_matches("url(").trim_end_matches(')'));
+    let full_url = resolve_reference(url, reference).ok()?;
+
+    // Sheets importing one another would otherwise have us going in circles
+    if ancestors.contains(&full_url) {
+        return Some(String::new());
+    }
+
+    let remote_import = |full_url: &str| {
+        if media.is_empty() {
+            format!("@import url(\"{}\");", full_url)
+        } else {
+            format!("@import url(\"{}\") {};", full_url, media)
+        }
+    };
+
+    let css = match opts.action_for(AssetCategory::Style) {
+        AssetAction::Drop => return Some(String::new()),
+        AssetAction::Keep => return Some(remote_import(&full_url)),
+        AssetAction::Embed => match retrieve_cached_asset(&full_url, false, "text/css", opts, ctx) {
+            Ok(css) => css,
+            Err(_) => return Some(remote_import(&full_url)),
+        },
+    };
+
+    ancestors.push(full_url.clone());
+    let css = embed_stylesheet(&full_url, &css, opts, ancestors, ctx);
+    ancestors.pop();
+
+    if media.is_empty() {
+        Some(css)
+    } else {
+        Some(format!("@media {} {{ {} }}", media, css))
+    }
+}
+
+// Same as with scripts, maps point to remote files which won't be there offline
+fn embed_source_map(url: &str, css: &str, opts: &Options, ctx: &mut Context) -> String {
+    SOURCE_MAP
+        .replace_all(css, |caps: &Captures| {
+            let full_url = match resolve_reference(url, &caps["url"]) {
+                Ok(full_url) => full_url,
+                Err(_) => return String::new(),
+            };
+
+            match opts.source_maps {
+                AssetAction::Drop => String::new(),
+                AssetAction::Keep => format!("/*# sourceMappingURL={} */", full_url),
+                AssetAction::Embed => format!(
+                    "/*# sourceMappingURL={} */",
+                    retrieve_cached_asset(&full_url, true, "application/json", opts, ctx)
+                        .unwrap_or(full_url)
+                ),
+            }
+        })
+        .to_string()
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+fn embed_image_set(url: &str, image_set: &str, opts: &Options, ctx: &mut Context) -> String {
+    // Each candidate is either url() or a bare string, followed by its descriptors
+    IMAGE_SET_CANDIDATE
+        .replace_all(image_set, |caps: &Captures| {
+            if let Some(image_type) = caps.name("type") {
+                image_type.as_str().to_string()
+            } else if caps.name("string_dq").is_some() || caps.name("string_sq").is_some() {
+                let reference = get_reference(caps, &["string_dq", "string_sq"]);
+                let embedded = embed_css_asset(url, reference, AssetCategory::Image, opts, ctx);
+                format!("\"{}\"", embedded)
+            } else {
+                embed_url_function(url, caps, Some(AssetCategory::Image), opts, ctx)
+            }
+        })
+        .to_string()
+}
+
+fn embed_url_function(
+    url: &str,
+    caps: &Captures,
+    category: Option<AssetCategory>,
+    opts: &Options,
+    ctx: &mut Context,
+) -> String {
+    let reference = get_reference(caps, &["dq", "sq", "bare"]);
+    let category = category.unwrap_or_else(|| AssetCategory::from_url(reference));
+
+    // Declarations left with none for an image don't draw anything, which is
+    // as good as if they were gone, as opposed to drawing a transparent pixel
+    if category == AssetCategory::Image && is_embeddable(reference) && opts.removes_images() {
+        return "none".to_string();
+    }
+
+    format!("url(\"{}\")", embed_css_asset(url, reference, category, opts, ctx))
+}
+
+// References to SVG fragments and already embedded assets are left alone
+fn is_embeddable(reference: &str) -> bool {
+    !reference.is_empty() && !reference.starts_with('#') && !is_data_url(reference).unwrap_or(false)
+}
+
+fn get_reference<'t>(caps: &Captures<'t>, groups: &[&str]) -> &'t str {
+    groups
+        .iter()
+        .filter_map(|group| caps.name(group))
+        .map(|reference| reference.as_str())
+        .next()
+        .unwrap_or("")
+}
+
+// Stylesheets pieced together out of markup can carry character references, e.g. "&amp;"
+// in query strings, which would otherwise get escaped once more and end up as "&amp;amp;"
+fn resolve_reference(url: &str, reference: &str) -> Result<String, ParseError> {
+    resolve_url(url, &decode_entities(reference))
+}
+
+fn embed_css_asset(
+    url: &str,
+    reference: &str,
+    category: AssetCategory,
+    opts: &Options,
+    ctx: &mut Context,
+) -> String {
+    if !is_embeddable(reference) {
+        return reference.to_string();
+    }
+
+    let full_url = match resolve_reference(url, reference) {
+        Ok(full_url) => full_url,
+        Err(_) => return reference.to_string(),
+    };
+
+    match opts.action_for_type(category, guess_mimetype(reference)) {
+        AssetAction::Drop if category == AssetCategory::Image => TRANSPARENT_PIXEL.to_string(),
+        AssetAction::Drop => String::new(),
+        AssetAction::Keep => full_url,
+        // An absolute URL is still better than a broken reference
+        AssetAction::Embed => {
+            // Browsers skip fonts of a type other than the one format() promises
+            let font_type = guess_mimetype(reference);
+            let as_mime = if category == AssetCategory::Font && font_type.starts_with("font/") {
+                font_type
+            } else {
+                ""
+            };
+
+            retrieve_cached_asset(&full_url, true, as_mime, opts, ctx).unwrap_or(full_url)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{response, serve};
+    use utils::data_to_dataurl;
+
+    #[test]
+    fn test_embed_css_url() {
+        let base_url = serve(vec![("/img/bg.png", response("image/png", b"background"))]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let css = "body { background: url('img/bg.png') no-repeat; } \
+                   svg { filter: url(#blur); } \
+                   div { background: url(data:image/png;base64,AAAA); }";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                "body {{ background: url(\"{}\") no-repeat; }} \
+                 svg {{ filter: url(\"#blur\"); }} \
+                 div {{ background: url(\"data:image/png;base64,AAAA\"); }}",
+                data_to_dataurl("image/png", b"background")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_custom_property() {
+        let base_url = serve(vec![("/img/bg.png", response("image/png", b"background"))]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let css = ":root { --bg: url(img/bg.png); --fallback:url( 'img/bg.png' ) } \
+                   body { background: var(--bg, var(--fallback)); }";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                ":root {{ --bg: url(\"{0}\"); --fallback:url(\"{0}\") }} \
+                 body {{ background: var(--bg, var(--fallback)); }}",
+                data_to_dataurl("image/png", b"background")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_image_set() {
+        let base_url = serve(vec![
+            ("/a.png", response("image/png", b"small")),
+            ("/b.png", response("image/png", b"large")),
+            ("/c.png", response("image/png", b"huge")),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let css = "div { background-image: image-set(url(a.png) 1x, \"b.png\" 2x); } \
+                   p { background-image: -webkit-image-set(url(\"c.png\") type(\"image/png\") 3x); }";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                "div {{ background-image: image-set(url(\"{}\") 1x, \"{}\" 2x); }} \
+                 p {{ background-image: -webkit-image-set(url(\"{}\") type(\"image/png\") 3x); }}",
+                data_to_dataurl("image/png", b"small"),
+                data_to_dataurl("image/png", b"large"),
+                data_to_dataurl("image/png", b"huge")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_source_map() {
+        let base_url = serve(vec![(
+            "/css/main.css.map",
+            response("application/json", b"{\"version\":3}"),
+        )]);
+        let url = format!("{}/css/main.css", base_url);
+        let css = "p{color:red}\n/*# sourceMappingURL=main.css.map */\n";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(embed_css(&url, css, &opts, &mut Context::default()), "p{color:red}\n\n");
+        assert_eq!(
+            embed_css(
+                &url,
+                css,
+                &Options {
+                    source_maps: AssetAction::Keep,
+                    ..opts.clone()
+                },
+                &mut Context::default()
+            ),
+            format!("p{{color:red}}\n/*# sourceMappingURL={}/css/main.css.map */\n", base_url)
+        );
+        assert_eq!(
+            embed_css(
+                &url,
+                css,
+                &Options {
+                    source_maps: AssetAction::Embed,
+                    ..opts
+                },
+                &mut Context::default()
+            ),
+            format!(
+                "p{{color:red}}\n/*# sourceMappingURL={} */\n",
+                data_to_dataurl("application/json", b"{\"version\":3}")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_import() {
+        let base_url = serve(vec![
+            ("/string.css", response("text/css", b"h1{color:red}")),
+            ("/url.css", response("text/css", b"h2{color:green}")),
+            ("/media.css", response("text/css", b"h3{color:blue}")),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let css = "@import \"string.css\";\n\
+                   @import url(url.css);\n\
+                   @import url('media.css') screen and (min-width: 600px);\n\
+                   @import \"missing.css\" print;\n\
+                   p{color:black}";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                "h1{{color:red}}\n\
+                 h2{{color:green}}\n\
+                 @media screen and (min-width: 600px) {{ h3{{color:blue}} }}\n\
+                 @import url(\"{}/missing.css\") print;\n\
+                 p{{color:black}}",
+                base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_import_nested() {
+        let base_url = serve(vec![
+            (
+                "/css/main.css",
+                response("text/css", b"@import \"theme/dark.css\";main{}"),
+            ),
+            (
+                "/css/theme/dark.css",
+                response("text/css", b"@import url(../main.css);dark{}"),
+            ),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        // The second level imports its own parent, which is left out
+        assert_eq!(
+            embed_css(
+                &format!("{}/", base_url),
+                "@import 'css/main.css';body{}",
+                &opts,
+                &mut Context::default()
+            ),
+            "dark{}main{}body{}"
+        );
+    }
+
+    #[test]
+    fn test_embed_css_font_face() {
+        let base_url = serve(vec![
+            (
+                "/icons.woff2",
+                response("application/octet-stream", b"wOF2icons"),
+            ),
+            ("/mono", response("application/octet-stream", b"wOF2mono")),
+            ("/sans.ttf", response("application/octet-stream", b"sans")),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let css = "@font-face { font-family: Icons; \
+                   src: url('icons.woff2') format('woff2'), url(mono) format('woff2'); } \
+                   @font-face { font-family: Sans; src: url(\"sans.ttf\") format(\"truetype\"); }";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                "@font-face {{ font-family: Icons; \
+                 src: url(\"{}\") format('woff2'), url(\"{}\") format('woff2'); }} \
+                 @font-face {{ font-family: Sans; src: url(\"{}\") format(\"truetype\"); }}",
+                data_to_dataurl("font/woff2", b"wOF2icons"),
+                data_to_dataurl("font/woff2", b"wOF2mono"),
+                data_to_dataurl("font/ttf", b"sans")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_policy() {
+        let base_url = serve(vec![
+            ("/bg.png", response("image/png", b"background")),
+            ("/icons.woff2", response("image/png", b"font")),
+        ]);
+        let opts = Options::builder()
+            .policy(AssetCategory::Image, AssetAction::Embed)
+            .policy(AssetCategory::Font, AssetAction::Keep)
+            .silent(true)
+            .build()
+            .unwrap();
+        let css = "@font-face { src: url(icons.woff2) format('woff2'); } \
+                   body { background: url(bg.png); }";
+
+        assert_eq!(
+            embed_css(&format!("{}/", base_url), css, &opts, &mut Context::default()),
+            format!(
+                "@font-face {{ src: url(\"{}/icons.woff2\") format('woff2'); }} \
+                 body {{ background: url(\"{}\"); }}",
+                base_url,
+                data_to_dataurl("image/png", b"background")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_css_entities() {
+        let opts = Options::builder()
+            .policy(AssetCategory::Image, AssetAction::Keep)
+            .silent(true)
+            .build()
+            .unwrap();
+        let css = "body { background: image-set(url(bg.png?w=64&amp;h=64) 1x); }";
+
+        assert_eq!(
+            embed_css("https://www.kernel.org/", css, &opts, &mut Context::default()),
+            "body { background: image-set(url(\"https://www.kernel.org/bg.png?w=64&h=64\") 1x); }"
+        );
+    }
+}
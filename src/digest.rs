@@ -0,0 +1,69 @@
+extern crate base64;
+
+use self::base64::encode;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+// Weakest first, which is how the strongest one listed gets picked
+const INTEGRITY_ALGORITHMS: [&str; 3] = ["sha256", "sha384", "sha512"];
+
+// Subresource Integrity metadata, e.g. "sha384-oqVuAfXRKap7fdgcCY5uykM6+R9GqQ8K/uxy9rx7HNQ=",
+// only hashes made with the strongest algorithm listed count; metadata which lists none
+// that are known doesn't rule anything out
+pub fn matches_integrity(metadata: &str, data: &[u8]) -> bool {
+    let hashes: Vec<(usize, &str)> = metadata
+        .split_whitespace()
+        .filter_map(|hash| {
+            // Options which may follow the hash have no meaning as of yet
+            let hash = hash.split('?').next().unwrap_or("");
+            let mut parts = hash.splitn(2, '-');
+            let algorithm = parts.next().unwrap_or("").to_lowercase();
+            let value = parts.next()?;
+            let strength = INTEGRITY_ALGORITHMS
+                .iter()
+                .position(|known| *known == algorithm)?;
+
+            Some((strength, value))
+        })
+        .collect();
+
+    let strongest = match hashes.iter().map(|&(strength, _)| strength).max() {
+        Some(strongest) => strongest,
+        None => return true,
+    };
+    let digest = match INTEGRITY_ALGORITHMS[strongest] {
+        "sha256" => encode(&Sha256::digest(data)),
+        "sha384" => encode(&Sha384::digest(data)),
+        _ => encode(&Sha512::digest(data)),
+    };
+
+    hashes
+        .iter()
+        .filter(|&&(strength, _)| strength == strongest)
+        .any(|&(_, value)| digest.trim_end_matches('=') == value.trim_end_matches('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_integrity() {
+        let sha256_abc = "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+        let sha384_abc = "sha384-ywB1P0WjXou1oD1pmsZQBycsMqsO3tFjGotgWkP/W+2AhgcroefMI1i67KE0yCWn";
+
+        assert!(matches_integrity(sha256_abc, b"abc"));
+        assert!(matches_integrity(sha384_abc, b"abc"));
+        assert!(!matches_integrity(sha384_abc, b"abd"));
+        // Weaker hashes don't count once there's a stronger one
+        assert!(!matches_integrity(
+            &format!("{} sha384-AAAA", sha256_abc),
+            b"abc"
+        ));
+        assert!(matches_integrity(
+            &format!("sha384-AAAA {}?ct=text/plain", sha384_abc),
+            b"abc"
+        ));
+        assert!(matches_integrity("md5-kAFQmDzST7DWlj99KOF/cg==", b"abc"));
+        assert!(matches_integrity("", b"abc"));
+    }
+}
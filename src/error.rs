@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fmt;
+use url::ParseError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MonolithError {
+    ParseError { url: String, source: ParseError },
+    FetchError { url: String, reason: String },
+    Timeout { url: String },
+    TooLarge { url: String, size: usize, limit: usize },
+    BlockedHost { host: String },
+    OutOfScope { url: String },
+    IntegrityMismatch { url: String },
+    DisallowedByRobots { url: String },
+    InvalidOptions(String),
+    WriteError(String),
+}
+
+impl fmt::Display for MonolithError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonolithError::ParseError { url, source } => {
+                write!(f, "unable to parse URL {}: {}", url, source)
+            }
+            MonolithError::FetchError { url, reason } => {
+                write!(f, "unable to retrieve {}: {}", url, reason)
+            }
+            MonolithError::Timeout { url } => write!(f, "timed out retrieving {}", url),
+            MonolithError::TooLarge { url, size, limit } => write!(
+                f,
+                "{} is too large ({} bytes, limit is {} bytes)",
+                url, size, limit
+            ),
+            MonolithError::BlockedHost { host } => write!(f, "host {} is blocked", host),
+            MonolithError::OutOfScope { url } => write!(f, "{} is out of scope", url),
+            MonolithError::IntegrityMismatch { url } => {
+                write!(f, "{} doesn't match its integrity metadata", url)
+            }
+            MonolithError::DisallowedByRobots { url } => {
+                write!(f, "robots.txt disallows retrieving {}", url)
+            }
+            MonolithError::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            MonolithError::WriteError(reason) => write!(f, "unable to write output: {}", reason),
+        }
+    }
+}
+
+impl Error for MonolithError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MonolithError::ParseError { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monolith_error_display() {
+        assert_eq!(
+            MonolithError::FetchError {
+                url: "https://example.com/".to_string(),
+                reason: "server responded with 404 Not Found".to_string(),
+            }
+            .to_string(),
+            "unable to retrieve https://example.com/: server responded with 404 Not Found"
+        );
+        assert_eq!(
+            MonolithError::ParseError {
+                url: "kernel.org".to_string(),
+                source: ParseError::RelativeUrlWithoutBase,
+            }
+            .to_string(),
+            "unable to parse URL kernel.org: relative URL without a base"
+        );
+    }
+}
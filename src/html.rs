@@ -1,12 +1,35 @@
-use html5ever::parse_document;
-use html5ever::rcdom::{Handle, NodeData, RcDom};
+use context::Context;
+use css::embed_css;
+use error::MonolithError;
+use futures::Future;
+use html5ever::{parse_document, parse_fragment, Attribute, LocalName, QualName};
+use html5ever::rcdom::{Handle, Node, NodeData, RcDom};
 use html5ever::serialize::{serialize, SerializeOpts};
 use html5ever::tendril::TendrilSink;
-use http::{is_valid_url, resolve_url, retrieve_asset};
+use image::image_dimensions;
+use http::{
+    is_data_url, is_in_scope, is_valid_url, prefetch_assets, resolve_url, retrieve_asset_size,
+    retrieve_cached_asset, PrefetchedFetcher,
+};
+use js::{embed_module_imports, embed_source_map, embed_workers};
+use opts::{
+    AssetAction, AssetCategory, CustomFetcher, ElementHandler, EmbedElement, MediaSource, Options,
+};
+use pretty::serialize_pretty;
 use regex::Regex;
+use shell::write_self_extracting;
+use stats::{count_embedded_assets, embedded_size, ArchiveStats};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
-use std::io;
-use utils::data_to_dataurl;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+use url::{ParseError, Url};
+use utils::{
+    data_to_dataurl_with, dataurl_to_data, format_timestamp, guess_mimetype, TRANSPARENT_PIXEL,
+};
+use whitespace::WhitespaceCollapser;
 
 lazy_static! {
     static ref EMPTY_STRING: String = String::new();
@@ -16,9 +39,6 @@ lazy_static! {
     ).unwrap();
 }
 
-const TRANSPARENT_PIXEL: &str = "data:image/png;base64,\
-iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
-
 const JS_DOM_EVENT_ATTRS: [&str; 21] = [
     // Input
     "onfocus",
@@ -47,9 +67,59 @@ const JS_DOM_EVENT_ATTRS: [&str; 21] = [
     "onresize",
 ];
 
+// Hints which only make browsers reach out to remote hosts
+const NETWORK_HINTS: [&str; 2] = ["dns-prefetch", "preconnect"];
+
+// Parameters of plugins which take the URL of what they're to play or show
+const OBJECT_URL_PARAMS: [&str; 5] = ["data", "filename", "movie", "src", "url"];
+
+// Attributes which point to an image outside of the usual places, mostly ones left over
+// from before there was CSS, by the element they belong to
+const IMAGE_ATTRIBUTES: [(&str, &str); 5] = [
+    ("body", "background"),
+    ("table", "background"),
+    ("td", "background"),
+    ("th", "background"),
+    ("video", "poster"),
+];
+
+// Microdata properties which point to an image, as opposed to any other URL
+const MICRODATA_IMAGE_PROPS: [&str; 4] = ["image", "logo", "photo", "thumbnailUrl"];
+const MICRODATA_URL_PROPS: [&str; 4] = ["contentUrl", "embedUrl", "sameAs", "url"];
+
+const FEED_MIME_TYPES: [&str; 3] = [
+    "application/atom+xml",
+    "application/feed+json",
+    "application/rss+xml",
+];
+
+// Directives of a CSP which embedded assets and inlined stylesheets are subject to
+const CSP_FETCH_DIRECTIVES: [&str; 14] = [
+    "child-src",
+    "default-src",
+    "font-src",
+    "frame-src",
+    "img-src",
+    "manifest-src",
+    "media-src",
+    "object-src",
+    "script-src",
+    "script-src-elem",
+    "style-src",
+    "style-src-attr",
+    "style-src-elem",
+    "worker-src",
+];
+
 fn get_parent_node_name(node: &Handle) -> String {
-    let parent = node.parent.take().clone();
-    let parent_node = parent.and_then(|node| node.upgrade()).unwrap();
+    let parent = node.parent.take();
+    node.parent.set(parent.clone());
+
+    // Detached nodes and the document root have no parent to speak of
+    let parent_node = match parent.and_then(|node| node.upgrade()) {
+        Some(parent_node) => parent_node,
+        None => return EMPTY_STRING.clone(),
+    };
 
     match &parent_node.data {
         NodeData::Document => { EMPTY_STRING.clone() }
@@ -63,478 +133,5716 @@ fn get_parent_node_name(node: &Handle) -> String {
     }
 }
 
-pub fn walk_and_embed_assets(
-    url: &str,
-    node: &Handle,
-    opt_no_js: bool,
-    opt_no_images: bool,
-    opt_user_agent: &str,
-    opt_silent: bool,
-    opt_insecure: bool,
-) {
-    match node.data {
-        NodeData::Document => {
-            // Dig deeper
-            for child in node.children.borrow().iter() {
-                walk_and_embed_assets(
-                        &url, child,
-                        opt_no_js,
-                        opt_no_images,
-                        opt_user_agent,
-                        opt_silent,
-                        opt_insecure,
-                    );
-            }
-        }
-        NodeData::Doctype { .. } => {}
-        NodeData::Text { .. } => {}
-        NodeData::Comment { .. } => {
-            // Note: in case of opt_no_js being set to true, there's no need to worry about
-            //       getting rid of comments that may contain scripts, e.g. <!--[if IE]><script>...
-            //       since that's not part of W3C standard and therefore gets ignored
-            //       by browsers other than IE [5, 9]
-        }
-        NodeData::Element {
-            ref name,
-            ref attrs,
-            ..
-        } => {
-            let attrs_mut = &mut attrs.borrow_mut();
+// Post-processes the document before it gets serialized, e.g. to take out ads
+// or put in a banner; visitors run in the order they were added in
+pub trait DomVisitor: Send {
+    fn visit(&mut self, document: &Handle, url: &str);
+}
 
-            match name.local.as_ref() {
-                "link" => {
-                    let mut link_type = "";
-
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "rel" {
-                            if is_icon(&attr.value.to_string()) {
-                                link_type = "icon";
-                                break;
-                            } else if attr.value.to_string() == "stylesheet" {
-                                link_type = "stylesheet";
-                                break;
-                            }
-                        }
-                    }
+// Statistics are kept for each walk, nested documents included, and handed back once it's over
+pub fn walk_and_embed_assets(url: &str, node: &Handle, opts: &Options) -> ArchiveStats {
+    let url = &with_default_scheme(url, opts);
+    let mut ctx = Context::default();
 
-                    if link_type == "icon" {
-                        for attr in attrs_mut.iter_mut() {
-                            if &attr.name.local == "href" {
-                                if opt_no_images {
-                                    attr.value.clear();
-                                    attr.value.push_slice(TRANSPARENT_PIXEL);
-                                } else {
-                                    let href_full_url: String = resolve_url(
-                                            &url,
-                                            &attr.value.to_string()
-                                        )
-                                        .unwrap_or(EMPTY_STRING.clone());
-                                    let favicon_datauri = retrieve_asset(
-                                            &href_full_url,
-                                            true,
-                                            "",
-                                            opt_user_agent,
-                                            opt_silent,
-                                            opt_insecure,
-                                        )
-                                        .unwrap_or(EMPTY_STRING.clone());
-                                    attr.value.clear();
-                                    attr.value.push_slice(favicon_datauri.as_str());
-                                }
-                            }
-                        }
-                    } else if link_type == "stylesheet" {
-                        for attr in attrs_mut.iter_mut() {
-                            if &attr.name.local == "href" {
-                                let href_full_url: String = resolve_url(
-                                        &url,
-                                        &attr.value.to_string(),
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                let css_datauri = retrieve_asset(
-                                        &href_full_url,
-                                        true,
-                                        "text/css",
-                                        opt_user_agent,
-                                        opt_silent,
-                                        opt_insecure,
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                attr.value.clear();
-                                attr.value.push_slice(css_datauri.as_str());
-                            }
-                        }
-                    } else {
-                        for attr in attrs_mut.iter_mut() {
-                            if &attr.name.local == "href" {
-                                let href_full_url: String = resolve_url(
-                                        &url,
-                                        &attr.value.to_string(),
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                attr.value.clear();
-                                attr.value.push_slice(&href_full_url.as_str());
-                            }
-                        }
-                    }
-                }
-                "img" => {
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "src" {
-                            let value = attr.value.to_string();
-
-                            // Ignore images with empty source (they're hopelessly broken)
-                            if value == EMPTY_STRING.clone() {
-                                continue;
-                            }
-
-                            if opt_no_images {
-                                attr.value.clear();
-                                attr.value.push_slice(TRANSPARENT_PIXEL);
-                            } else {
-                                let src_full_url: String = resolve_url(
-                                        &url,
-                                        &value,
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                let img_datauri = retrieve_asset(
-                                        &src_full_url,
-                                        true,
-                                        "",
-                                        opt_user_agent,
-                                        opt_silent,
-                                        opt_insecure,
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                attr.value.clear();
-                                attr.value.push_slice(img_datauri.as_str());
-                            }
-                        }
-                    }
-                }
-                "source" => {
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "srcset" {
-                            if get_parent_node_name(&node) == "picture" {
-                                if opt_no_images {
-                                    attr.value.clear();
-                                    attr.value.push_slice(TRANSPARENT_PIXEL);
-                                } else {
-                                    let srcset_full_url: String = resolve_url(
-                                            &url,
-                                            &attr.value.to_string(),
-                                        )
-                                        .unwrap_or(EMPTY_STRING.clone());
-                                    let source_datauri = retrieve_asset(
-                                            &srcset_full_url,
-                                            true,
-                                            "",
-                                            opt_user_agent,
-                                            opt_silent,
-                                            opt_insecure,
-                                        )
-                                        .unwrap_or(EMPTY_STRING.clone());
-                                    attr.value.clear();
-                                    attr.value.push_slice(source_datauri.as_str());
-                                }
-                            }
-                        }
-                    }
-                }
-                "a" => {
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "href" {
-                            // Don't touch email links or hrefs which begin with a hash sign
-                            if attr.value.starts_with('#') || has_protocol(&attr.value) {
-                                continue;
-                            }
-
-                            let href_full_url: String = resolve_url(&url, &attr.value.to_string())
-                                .unwrap_or(EMPTY_STRING.clone());
-                            attr.value.clear();
-                            attr.value.push_slice(href_full_url.as_str());
-                        }
-                    }
-                }
-                "script" => {
-                    if opt_no_js {
-                        // Get rid of src and inner content of SCRIPT tags
-                        for attr in attrs_mut.iter_mut() {
-                            if &attr.name.local == "src" {
-                                attr.value.clear();
-                            }
-                        }
-                        node.children.borrow_mut().clear();
-                    } else {
-                        for attr in attrs_mut.iter_mut() {
-                            if &attr.name.local == "src" {
-                                let src_full_url: String = resolve_url(
-                                        &url,
-                                        &attr.value.to_string(),
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                let js_datauri = retrieve_asset(
-                                        &src_full_url,
-                                        true,
-                                        "application/javascript",
-                                        opt_user_agent,
-                                        opt_silent,
-                                        opt_insecure,
-                                    )
-                                    .unwrap_or(EMPTY_STRING.clone());
-                                attr.value.clear();
-                                attr.value.push_slice(js_datauri.as_str());
-                            }
-                        }
-                    }
-                }
-                "form" => {
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "action" {
-                            // Don't modify action that's already a full URL
-                            if is_valid_url(&attr.value) {
-                                continue;
-                            }
-
-                            let href_full_url: String = resolve_url(&url, &attr.value.to_string())
-                                .unwrap_or(EMPTY_STRING.clone());
-                            attr.value.clear();
-                            attr.value.push_slice(href_full_url.as_str());
-                        }
-                    }
-                }
-                "iframe" => {
-                    for attr in attrs_mut.iter_mut() {
-                        if &attr.name.local == "src" {
-                            let value = attr.value.to_string();
-
-                            // Ignore iframes with empty source (they cause infinite loops)
-                            if value == EMPTY_STRING.clone() {
-                                continue;
-                            }
-
-                            let src_full_url: String = resolve_url(&url, &value)
-                                .unwrap_or(EMPTY_STRING.clone());
-                            let iframe_data = retrieve_asset(
-                                    &src_full_url,
-                                    false,
-                                    "text/html",
-                                    opt_user_agent,
-                                    opt_silent,
-                                    opt_insecure,
-                                )
-                                .unwrap_or(EMPTY_STRING.clone());
-                            let dom = html_to_dom(&iframe_data);
-                            walk_and_embed_assets(
-                                    &src_full_url,
-                                    &dom.document,
-                                    opt_no_js,
-                                    opt_no_images,
-                                    opt_user_agent,
-                                    opt_silent,
-                                    opt_insecure,
-                                );
-                            let mut buf: Vec<u8> = Vec::new();
-                            serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
-                            let iframe_datauri = data_to_dataurl("text/html", &buf);
-                            attr.value.clear();
-                            attr.value.push_slice(iframe_datauri.as_str());
-                        }
-                    }
-                }
-                _ => {}
-            }
+    // Whatever got recorded for a previous page has nothing to do with this one
+    opts.manifest.clear();
 
-            if opt_no_js {
-                // Get rid of JS event attributes
-                for attr in attrs_mut.iter_mut() {
-                    if JS_DOM_EVENT_ATTRS.contains(&attr.name.local.to_lowercase().as_str()) {
-                        attr.value.clear();
-                    }
-                }
-            }
+    walk(&resolve_base(url, opts), node, opts, &mut ctx);
+    count_embedded_assets(node, &mut ctx.stats);
 
-            // Dig deeper
-            for child in node.children.borrow().iter() {
-                walk_and_embed_assets(
-                        &url,
-                        child,
-                        opt_no_js,
-                        opt_no_images,
-                        opt_user_agent,
-                        opt_silent,
-                        opt_insecure,
-                    );
-            }
+    // A policy allowing nothing but data URLs would keep assets left remote from loading
+    if opts.inject_csp {
+        if !opts.keeps_assets_remote() && fits_total_budget(opts, &ctx) {
+            inject_csp(node, opts);
+        } else if !opts.silent {
+            eprintln!("Content-Security-Policy left out, some assets stay remote");
         }
-        NodeData::ProcessingInstruction { .. } => unreachable!()
     }
-}
 
-fn has_protocol(url: &str) -> bool {
-    HAS_PROTOCOL.is_match(&url.to_lowercase())
+    if let Some(ref default_lang) = opts.default_lang {
+        add_default_lang(node, default_lang);
+    }
+
+    for visitor in opts.dom_visitors.iter() {
+        visitor.visit(node, url);
+    }
+
+    // Last, so that whatever the visitors put in gets collapsed as well
+    if opts.collapse_whitespace {
+        WhitespaceCollapser.visit(node, url);
+    }
+
+    if opts.normalize {
+        normalize_attributes(node);
+    }
+
+    if opts.capture_metadata {
+        let captured_at = opts
+            .clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        add_capture_comment(url, node, captured_at);
+    }
+
+    ctx.stats
 }
 
-pub fn html_to_dom(data: &str) -> html5ever::rcdom::RcDom {
-    parse_document(RcDom::default(), Default::default())
-        .from_utf8()
-        .read_from(&mut data.as_bytes())
-        .unwrap()
+// Saved pages tend to get passed around on their own, this tells where and when they
+// came from; being a comment outside of <html>, it gets in the way of no <meta> tags
+fn add_capture_comment(url: &str, node: &Handle, captured_at: u64) {
+    let comment = Node::new(NodeData::Comment {
+        contents: format!(
+            " Saved from {} at {} using monolith {} ",
+            url.replace("--", "-%2D"),
+            format_timestamp(captured_at),
+            env!("CARGO_PKG_VERSION")
+        )
+        .into(),
+    });
+    let mut children = node.children.borrow_mut();
+    // Right after the doctype, anything in front of it would put browsers in quirks mode
+    let position = children
+        .iter()
+        .position(|child| matches!(child.data, NodeData::Element { .. }))
+        .unwrap_or(children.len());
+
+    comment.parent.set(Some(Rc::downgrade(node)));
+    children.insert(position, comment);
 }
 
-pub fn print_dom(handle: &Handle) {
-    serialize(&mut io::stdout(), handle, SerializeOpts::default()).unwrap();
+// Pages fetched through a proxy or a mirror still reference assets of the original site
+fn resolve_base(url: &str, opts: &Options) -> String {
+    let base = opts.base_url.as_deref().unwrap_or(url);
+
+    with_default_scheme(base, opts)
 }
 
-fn is_icon(attr_value: &str) -> bool {
-    ICON_VALUES.is_match(&attr_value.to_lowercase())
+// Pages given as e.g. localhost/page leave nothing for assets to be resolved against
+// until they get a scheme, whichever one the options have for such cases
+fn with_default_scheme(url: &str, opts: &Options) -> String {
+    let url = url.trim();
+    let lacks_scheme = match Url::parse(url) {
+        Err(ParseError::RelativeUrlWithoutBase) => !url.is_empty(),
+        // A host followed by a port, e.g. localhost:8080/page, reads as a scheme and a path
+        Ok(parsed_url) => {
+            parsed_url.cannot_be_a_base()
+                && parsed_url.path().starts_with(|c: char| c.is_ascii_digit())
+        }
+        Err(_) => false,
+    };
+
+    if lacks_scheme {
+        format!("{}://{}", opts.default_scheme, url.trim_start_matches('/'))
+    } else {
+        url.to_string()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Visits the node and everything within it, each parent before its children; those get
+// looked at only once the callback is done with the parent, which is free to rearrange them
+fn for_each_node<F: FnMut(&Handle)>(node: &Handle, f: &mut F) {
+    f(node);
 
-    #[test]
-    fn test_is_icon() {
-        assert_eq!(is_icon("icon"), true);
-        assert_eq!(is_icon("Shortcut Icon"), true);
-        assert_eq!(is_icon("ICON"), true);
-        assert_eq!(is_icon("mask-icon"), true);
-        assert_eq!(is_icon("fluid-icon"), true);
-        assert_eq!(is_icon("stylesheet"), false);
-        assert_eq!(is_icon(""), false);
+    for child in node.children.borrow().iter() {
+        for_each_node(child, f);
     }
+}
 
-    #[test]
-    fn test_has_protocol() {
-        assert_eq!(has_protocol("mailto:somebody@somewhere.com?subject=hello"), true);
-        assert_eq!(has_protocol("tel:5551234567"), true);
-        assert_eq!(has_protocol("ftp:user:password@some-ftp-server.com"), true);
-        assert_eq!(has_protocol("javascript:void(0)"), true);
-        assert_eq!(has_protocol("http://news.ycombinator.com"), true);
-        assert_eq!(has_protocol("https://github.com"), true);
-        assert_eq!(has_protocol("//some-hostname.com/some-file.html"), false);
-        assert_eq!(has_protocol("some-hostname.com/some-file.html"), false);
-        assert_eq!(has_protocol("/some-file.html"), false);
-        assert_eq!(has_protocol(""), false);
-        assert_eq!(has_protocol("MAILTO:somebody@somewhere.com?subject=hello"), true);
+fn for_each_node_post_order<F: FnMut(&Handle)>(node: &Handle, f: &mut F) {
+    for child in node.children.borrow().iter() {
+        for_each_node_post_order(child, f);
     }
 
-    #[test]
-    fn test_get_parent_node_name() {
-        let html = "<!doctype html><html><HEAD></HEAD><body><div><P></P></div></body></html>";
-        let dom = html_to_dom(&html);
-        let mut count = 0;
+    f(node);
+}
 
-        fn test_walk(node: &Handle, i: &mut i8) {
-            *i += 1;
+pub fn for_each_element<F: FnMut(&Handle)>(node: &Handle, mut f: F) {
+    for_each_node(node, &mut |node| {
+        if let NodeData::Element { .. } = node.data {
+            f(node);
+        }
+    });
+}
 
-            match &node.data {
-                NodeData::Document => {
-                    for child in node.children.borrow().iter() {
-                        test_walk(child, &mut *i);
-                    }
-                }
-                NodeData::Doctype { .. } => (),
-                NodeData::Text { .. } => (),
-                NodeData::Comment { .. } => (),
-                NodeData::Element { ref name, attrs: _, .. } => {
-                    let node_name = name.local.as_ref().to_string();
-                    let parent_node_name = get_parent_node_name(node);
-                    if node_name == "head" || node_name == "body" {
-                        assert_eq!(parent_node_name, "html");
-                    } else if node_name == "div" {
-                        assert_eq!(parent_node_name, "body");
-                    } else if node_name == "p" {
-                        assert_eq!(parent_node_name, "div");
-                    }
+// Children before their parents, for passes which need to know what's within first
+pub fn for_each_element_post_order<F: FnMut(&Handle)>(node: &Handle, mut f: F) {
+    for_each_node_post_order(node, &mut |node| {
+        if let NodeData::Element { .. } = node.data {
+            f(node);
+        }
+    });
+}
 
-                    println!("{}", node_name);
+fn walk(url: &str, node: &Handle, opts: &Options, ctx: &mut Context) {
+    let mut remote_opts: Option<Options> = None;
 
-                    for child in node.children.borrow().iter() {
-                        test_walk(child, &mut *i);
-                    }
+    for_each_node(node, &mut |node| {
+        let size_before = ctx.embedded_size;
+
+        if fits_total_budget(opts, ctx) {
+            embed_node(url, node, opts, ctx);
+        } else {
+            // Over budget, whatever else the page references stays on the web
+            if !opts.silent {
+                for asset_url in element_asset_urls(url, node, opts) {
+                    eprintln!("[ {} ] left remote, archive size limit reached", asset_url);
                 }
-                NodeData::ProcessingInstruction { .. } => unreachable!()
-            };
+            }
+
+            let remote_opts = remote_opts.get_or_insert_with(|| keep_remote_assets(opts));
+            embed_node(url, node, remote_opts, ctx);
         }
 
-        test_walk(&dom.document, &mut count);
+        // Assets of frames end up in the frame's own data URL, which is all that counts
+        ctx.embedded_size = size_before + embedded_size(node);
+    });
+}
 
-        assert_eq!(count, 7);
-    }
+// Elements the walker knows how to embed the assets of, these share the table of handlers
+// with the ones registered by library users, who may replace any of them
+pub fn built_in_element_handlers() -> HashMap<String, ElementHandler> {
+    let handlers: [(&str, EmbedElement); 18] = [
+        ("link", embed_link_element),
+        ("img", embed_img_element),
+        ("image", embed_svg_image_element),
+        ("source", embed_source_element),
+        ("video", embed_media_element),
+        ("audio", embed_media_element),
+        ("meta", embed_meta_element),
+        ("a", embed_anchor_element),
+        ("area", embed_anchor_element),
+        ("script", embed_script_element),
+        ("form", embed_form_element),
+        ("object", embed_object_element),
+        ("param", embed_param_element),
+        ("button", embed_button_element),
+        ("input", embed_button_element),
+        ("style", embed_style_element),
+        ("frame", embed_frame_element),
+        ("iframe", embed_frame_element),
+    ];
 
-    #[test]
-    fn test_walk_and_embed_assets() {
-        let html = "<div><P></P></div>";
-        let dom = html_to_dom(&html);
-        let url = "http://localhost";
+    handlers
+        .iter()
+        .map(|(element_name, embed_element)| {
+            (element_name.to_string(), ElementHandler::BuiltIn(*embed_element))
+        })
+        .collect()
+}
 
-        walk_and_embed_assets(&url, &dom.document, true, true, "", true, true);
+fn embed_link_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let mut link_type = "";
 
-        let mut buf: Vec<u8> = Vec::new();
-        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "rel" {
+            if attr.value.trim().eq_ignore_ascii_case("mask-icon") {
+                link_type = "mask-icon";
+                break;
+            } else if is_icon(attr.value.as_ref()) {
+                link_type = "icon";
+                break;
+            } else if attr.value.to_string() == "stylesheet" {
+                link_type = "stylesheet";
+                break;
+            } else if attr.value.to_string() == "preload" {
+                link_type = "preload";
+                break;
+            } else if attr.value.to_string() == "alternate" {
+                link_type = "alternate";
+                break;
+            } else if attr.value.to_string() == "prefetch" {
+                link_type = "prefetch";
+                break;
+            } else if attr.value.trim().eq_ignore_ascii_case("modulepreload") {
+                link_type = "modulepreload";
+                break;
+            } else if attr.value.trim().eq_ignore_ascii_case("import") {
+                link_type = "import";
+                break;
+            }
+        }
+    }
 
-        assert_eq!(
-            buf.iter().map(|&c| c as char).collect::<String>(),
-            "<html><head></head><body><div><p></p></div></body></html>"
-        );
+    // Stylesheets which don't apply to the page aren't worth embedding
+    if is_inactive_stylesheet(attrs) {
+        link_type = "inactive-stylesheet";
     }
 
-    #[test]
-    fn test_walk_and_embed_assets_iframe() {
-        let html = "<div><P></P><iframe src=\"\"></iframe></div>";
-        let dom = html_to_dom(&html);
-        let url = "http://localhost";
+    let mut preload_type = EMPTY_STRING.clone();
+    let mut link_mime_type = EMPTY_STRING.clone();
 
-        walk_and_embed_assets(&url, &dom.document, true, true, "", true, true);
+    for attr in attrs.iter() {
+        if &attr.name.local == "as" {
+            preload_type = attr.value.to_lowercase();
+        } else if &attr.name.local == "type" {
+            link_mime_type = attr.value.trim().to_lowercase();
+        }
+    }
 
-        let mut buf: Vec<u8> = Vec::new();
-        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+    if link_type == "icon" || link_type == "mask-icon" {
+        // Safari's pinned tab icons are SVG masks tinted by the color
+        // attribute, whatever type the server happens to claim for them
+        let icon_mime_type = if link_type == "mask-icon" {
+            "image/svg+xml"
+        } else {
+            ""
+        };
+        let mut failed = false;
 
-        assert_eq!(
-            buf.iter().map(|&c| c as char).collect::<String>(),
-            "<html><head></head><body><div><p></p><iframe src=\"\"></iframe></div></body></html>"
-        );
-    }
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href = attr.value.to_string();
+                match embed_image_as(url, &href, icon_mime_type, opts, ctx) {
+                    Some(favicon_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(favicon_datauri.as_str());
+                    }
+                    None => failed = true,
+                }
+            }
+        }
 
-    #[test]
-    fn test_walk_and_embed_assets_img() {
-        let html = "<div><img src=\"http://localhost/assets/mono_lisa.png\" /></div>";
-        let dom = html_to_dom(&html);
-        let url = "http://localhost";
+        // An empty href would point browsers back at the page itself
+        if failed {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "inactive-stylesheet" {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_reference(url, &attr.value);
+                attr.value.clear();
+                attr.value.push_slice(href_full_url.as_str());
+            }
+        }
+    } else if link_type == "stylesheet" {
+        let mut failed = false;
 
-        walk_and_embed_assets(&url, &dom.document, true, true, "", true, true);
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_url(
+                        url,
+                        attr.value.as_ref(),
+                    )
+                    .unwrap_or(EMPTY_STRING.clone());
+                let css_datauri = match opts.action_for(AssetCategory::Style) {
+                    AssetAction::Drop => None,
+                    AssetAction::Keep => Some(href_full_url),
+                    _ if !is_in_scope(&href_full_url, opts) => Some(href_full_url),
+                    AssetAction::Embed => retrieve_cached_asset(
+                        &href_full_url,
+                        false,
+                        "text/css",
+                        opts,
+                        ctx,
+                    )
+                    .map(|css| {
+                        to_dataurl(
+                            &href_full_url,
+                            "text/css;charset=utf-8",
+                            embed_css(&href_full_url, &css, opts, ctx).as_bytes(),
+                            opts,
+                        )
+                    })
+                    .ok(),
+                };
 
-        let mut buf: Vec<u8> = Vec::new();
-        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+                match css_datauri {
+                    Some(css_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(css_datauri.as_str());
+                    }
+                    None => failed = true,
+                }
+            }
+        }
 
-        assert_eq!(
-            buf.iter().map(|&c| c as char).collect::<String>(),
-            "<html><head></head><body><div>\
-             <img src=\"data:image/png;base64,\
-             iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0\
-             lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=\">\
-             </div></body></html>"
-        );
-    }
+        if failed {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "modulepreload" {
+        let mut failed = false;
 
-    #[test]
-    fn test_walk_and_embed_assets_js() {
-        let html = "<div><script src=\"http://localhost/assets/some.js\"></script>\
-                    <script>alert(1)</script></div>";
-        let dom = html_to_dom(&html);
-        let url = "http://localhost";
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_url(
+                        url,
+                        attr.value.as_ref(),
+                    )
+                    .unwrap_or(EMPTY_STRING.clone());
+                let module_datauri = match opts.action_for(AssetCategory::Script) {
+                    AssetAction::Drop => None,
+                    AssetAction::Keep => Some(href_full_url),
+                    _ if !is_in_scope(&href_full_url, opts) => Some(href_full_url),
+                    AssetAction::Embed => retrieve_cached_asset(
+                        &href_full_url,
+                        false,
+                        "application/javascript",
+                        opts,
+                        ctx,
+                    )
+                    .map(|js| {
+                        to_dataurl(
+                            &href_full_url,
+                            "application/javascript",
+                            embed_module_imports(&href_full_url, &js, opts, ctx)
+                                .as_bytes(),
+                            opts,
+                        )
+                    })
+                    .ok(),
+                };
+
+                match module_datauri {
+                    Some(module_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(module_datauri.as_str());
+                    }
+                    None => failed = true,
+                }
+            }
+        }
 
-        walk_and_embed_assets(&url, &dom.document, true, true, "", true, true);
+        if failed {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "preload" && preload_type == "image" {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let image_datauri =
+                    embed_image(url, attr.value.as_ref(), opts, ctx)
+                        .unwrap_or(TRANSPARENT_PIXEL.to_string());
+                attr.value.clear();
+                attr.value.push_slice(image_datauri.as_str());
+            } else if &attr.name.local == "imagesrcset" {
+                let imagesrcset = embed_srcset(
+                        url,
+                        attr.value.as_ref(),
+                        opts,
+                        ctx,
+                    );
+                attr.value.clear();
+                attr.value.push_slice(imagesrcset.as_str());
+            }
+        }
+    } else if link_type == "preload" && preload_type == "fetch" {
+        let data_action = opts.action_for(AssetCategory::Other);
 
-        let mut buf: Vec<u8> = Vec::new();
-        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+        // Data URLs get loaded whatever the CORS mode, crossorigin can stay as is;
+        // data which can't be retrieved is still better off preloaded from the web
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_reference(url, &attr.value);
+                let data_datauri = match data_action {
+                    AssetAction::Embed => retrieve_cached_asset(
+                        &href_full_url,
+                        true,
+                        &link_mime_type,
+                        opts,
+                        ctx,
+                    )
+                    .unwrap_or(href_full_url),
+                    _ => href_full_url,
+                };
+                attr.value.clear();
+                attr.value.push_slice(data_datauri.as_str());
+            }
+        }
 
-        assert_eq!(
-            buf.iter().map(|&c| c as char).collect::<String>(),
-            "<html><head></head><body><div><script src=\"\"></script>\
-             <script></script></div></body></html>"
+        if data_action == AssetAction::Drop {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "preload"
+        && (preload_type == "video" || preload_type == "audio")
+    {
+        let mut media_action = AssetAction::Embed;
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let media_mime_type = if link_mime_type.is_empty() {
+                    guess_mimetype(&attr.value).to_string()
+                } else {
+                    link_mime_type.clone()
+                };
+                media_action =
+                    opts.action_for_type(AssetCategory::Media, &media_mime_type);
+
+                let href_full_url: String = resolve_reference(url, &attr.value);
+                let media_datauri = match media_action {
+                    AssetAction::Embed => retrieve_cached_asset(
+                        &href_full_url,
+                        true,
+                        &link_mime_type,
+                        opts,
+                        ctx,
+                    )
+                    .unwrap_or(href_full_url),
+                    _ => href_full_url,
+                };
+                attr.value.clear();
+                attr.value.push_slice(media_datauri.as_str());
+            }
+        }
+
+        if media_action == AssetAction::Drop {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "alternate"
+        && opts.embed_feeds
+        && FEED_MIME_TYPES.contains(&link_mime_type.as_str())
+    {
+        let mut failed = false;
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_url(
+                        url,
+                        attr.value.as_ref(),
+                    )
+                    .unwrap_or(EMPTY_STRING.clone());
+                match retrieve_cached_asset(
+                    &href_full_url,
+                    true,
+                    &link_mime_type,
+                    opts,
+                    ctx,
+                ) {
+                    Ok(feed_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(feed_datauri.as_str());
+                    }
+                    Err(_) => failed = true,
+                }
+            }
+        }
+
+        if failed {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "prefetch" && opts.embed_prefetch {
+        let mut failed = false;
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_url(
+                        url,
+                        attr.value.as_ref(),
+                    )
+                    .unwrap_or(EMPTY_STRING.clone());
+                match retrieve_cached_asset(&href_full_url, true, "", opts, ctx) {
+                    Ok(prefetch_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(prefetch_datauri.as_str());
+                    }
+                    Err(_) => failed = true,
+                }
+            }
+        }
+
+        if failed {
+            remove_attr(attrs, "href");
+        }
+    } else if link_type == "import" && opts.embed_imports {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                let href_full_url: String = resolve_reference(url, &attr.value);
+                let import_datauri = embed_import(&href_full_url, opts, ctx)
+                    .unwrap_or(href_full_url);
+                attr.value.clear();
+                attr.value.push_slice(import_datauri.as_str());
+            }
+        }
+    } else {
+        // That includes canonical links, which would otherwise point
+        // at nothing once the page is opened from the disk
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "href" {
+                // Leave whatever can't be resolved as it is
+                let href_full_url: String = resolve_reference(url, &attr.value);
+                attr.value.clear();
+                attr.value.push_slice(href_full_url.as_str());
+            }
+        }
+    }
+}
+
+fn embed_img_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    if opts.single_image {
+        collapse_srcset(attrs, opts.viewport_width);
+    }
+
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "src" {
+            let value = attr.value.to_string();
+
+            // Ignore images with empty source (they're hopelessly broken)
+            if value == EMPTY_STRING.clone() {
+                continue;
+            }
+
+            let img_datauri = embed_image(url, &value, opts, ctx)
+                .unwrap_or(TRANSPARENT_PIXEL.to_string());
+            attr.value.clear();
+            attr.value.push_slice(img_datauri.as_str());
+        }
+    }
+}
+
+// SVG's own image, HTML parsers turn <image> into <img> everywhere else
+fn embed_svg_image_element(
+    url: &str,
+    _node: &Handle,
+    name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    if name.ns != ns!(svg) {
+        return;
+    }
+
+    let mut dimensions: Option<(u32, u32)> = None;
+
+    for attr in attrs.iter_mut() {
+        // Either plain href or the older xlink:href
+        if &attr.name.local == "href" {
+            let image_datauri = match embed_image(url, attr.value.as_ref(), opts, ctx) {
+                Some(image_datauri) => {
+                    // The placeholder of images which failed to embed says nothing about them
+                    dimensions = dataurl_to_data(&image_datauri)
+                        .and_then(|(_, data)| image_dimensions(&data));
+                    image_datauri
+                }
+                None => TRANSPARENT_PIXEL.to_string(),
+            };
+            attr.value.clear();
+            attr.value.push_slice(image_datauri.as_str());
+        }
+    }
+
+    // Unlike <img>, SVG images without dimensions take up no space at all
+    if let (true, Some((width, height))) = (opts.size_svg_images, dimensions) {
+        for (attr_name, value) in [("width", width), ("height", height)].iter() {
+            if !attrs.iter().any(|attr| &attr.name.local == *attr_name) {
+                attrs.push(Attribute {
+                    name: QualName::new(None, ns!(), LocalName::from(*attr_name)),
+                    value: value.to_string().into(),
+                });
+            }
+        }
+    }
+}
+
+fn embed_source_element(
+    url: &str,
+    node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let parent_node_name = get_parent_node_name(node);
+
+    // Embed every candidate of every source, leaving media and type
+    // intact so that the browser still picks the right one offline
+    if parent_node_name == "picture"
+        && opts.action_for(AssetCategory::Image) == AssetAction::Drop
+    {
+        // Whichever source wins, it shows the same placeholder as the <img>
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "srcset" {
+                attr.value.clear();
+                attr.value.push_slice(TRANSPARENT_PIXEL);
+            }
+        }
+        remove_attr(attrs, "sizes");
+    } else if parent_node_name == "picture" {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "srcset" {
+                let srcset = embed_srcset(url, attr.value.as_ref(), opts, ctx);
+                attr.value.clear();
+                attr.value.push_slice(srcset.as_str());
+            }
+        }
+    } else if (parent_node_name == "video" || parent_node_name == "audio")
+        && opts.media_source.is_some()
+    {
+        let media_action =
+            opts.action_for_type(AssetCategory::Media, &media_type(attrs));
+        // Pages tend to know better than servers, which often
+        // call whatever media they serve application/octet-stream
+        let declared_type = attrs
+            .iter()
+            .find(|attr| &attr.name.local == "type")
+            .map(|attr| {
+                attr.value.split(';').next().unwrap_or("").trim().to_lowercase()
+            })
+            .unwrap_or_default();
+
+        // The other sources are gone by now, this is the one to keep
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "src" {
+                let src_full_url: String = resolve_reference(url, &attr.value);
+                let src = if media_action == AssetAction::Embed {
+                    retrieve_cached_asset(
+                        &src_full_url,
+                        true,
+                        &declared_type,
+                        opts,
+                        ctx,
+                    )
+                    .unwrap_or(src_full_url)
+                } else {
+                    src_full_url
+                };
+                attr.value.clear();
+                attr.value.push_slice(src.as_str());
+            }
+        }
+    } else if parent_node_name == "video" || parent_node_name == "audio" {
+        absolutize_kept_media(url, attrs, opts);
+    }
+}
+
+fn embed_media_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    _ctx: &mut Context,
+) {
+    absolutize_kept_media(url, attrs, opts);
+
+    // Megabytes of embedded media shouldn't start playing (or loading)
+    // the moment the archive gets opened
+    if opts.no_autoplay {
+        remove_attr(attrs, "autoplay");
+        remove_attr(attrs, "preload");
+        attrs.push(Attribute {
+            name: QualName::new(None, ns!(), LocalName::from("preload")),
+            value: "none".into(),
+        });
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn embed_meta_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let is_csp = attrs.iter().any(|attr| {
+        &attr.name.local == "http-equiv"
+            && attr.value.trim().eq_ignore_ascii_case("content-security-policy")
+    });
+
+    let is_refresh = attrs.iter().any(|attr| {
+        &attr.name.local == "http-equiv"
+            && attr.value.trim().eq_ignore_ascii_case("refresh")
+    });
+
+    if is_refresh {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "content" {
+                let refresh = resolve_refresh(url, &attr.value);
+                attr.value.clear();
+                attr.value.push_slice(refresh.as_str());
+            }
+        }
+    }
+
+    // The page's own policy would block the very assets embedded into it
+    if is_csp {
+        let scripts_kept =
+            opts.action_for(AssetCategory::Script) != AssetAction::Drop;
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "content" {
+                let policy = relax_csp(&attr.value, scripts_kept);
+                attr.value.clear();
+                attr.value.push_slice(policy.as_str());
+            }
+        }
+    }
+
+    let itemprop: String = attrs
+        .iter()
+        .find(|attr| &attr.name.local == "itemprop")
+        .map(|attr| attr.value.to_string())
+        .unwrap_or_default();
+    let is_image_prop = itemprop
+        .split_whitespace()
+        .any(|prop| MICRODATA_IMAGE_PROPS.contains(&prop));
+    let is_url_prop = itemprop
+        .split_whitespace()
+        .any(|prop| MICRODATA_URL_PROPS.contains(&prop));
+
+    if is_image_prop || is_url_prop {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "content" {
+                let content = if is_image_prop && opts.embed_microdata {
+                    embed_image(url, &attr.value, opts, ctx)
+                        .unwrap_or_else(|| resolve_reference(url, &attr.value))
+                } else {
+                    resolve_reference(url, &attr.value)
+                };
+                attr.value.clear();
+                attr.value.push_slice(content.as_str());
+            }
+        }
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn embed_anchor_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    _ctx: &mut Context,
+) {
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "href" {
+            // Don't touch email links or hrefs which begin with a hash sign
+            if attr.value.starts_with('#') {
+                continue;
+            }
+
+            // Pages archived along with this one get linked to where they're saved
+            if let Some(local_path) = get_local_path(url, &attr.value, opts) {
+                attr.value.clear();
+                attr.value.push_slice(local_path.as_str());
+                continue;
+            }
+
+            if has_protocol(&attr.value) {
+                continue;
+            }
+
+            let mut href_full_url: String = resolve_reference(url, &attr.value);
+
+            // Keep links to other parts of this very page working offline
+            if opts.local_links {
+                if let Some(fragment) = get_local_fragment(url, &href_full_url) {
+                    href_full_url = format!("#{}", fragment);
+                }
+            }
+
+            attr.value.clear();
+            attr.value.push_slice(href_full_url.as_str());
+        }
+    }
+}
+
+fn embed_script_element(
+    url: &str,
+    node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let script_action = opts.action_for(AssetCategory::Script);
+
+    if script_action == AssetAction::Drop && opts.inert_scripts {
+        // Keep SCRIPT tags along with their contents, but make them non-executable
+        let mut script_type = EMPTY_STRING.clone();
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "src" {
+                let src_full_url: String = resolve_reference(url, &attr.value);
+                attr.value.clear();
+                attr.value.push_slice(src_full_url.as_str());
+            } else if &attr.name.local == "type" {
+                script_type = attr.value.to_string();
+            }
+        }
+
+        if is_executable_script_type(&script_type) {
+            attrs.retain(|attr| &attr.name.local != "type");
+            attrs.push(Attribute {
+                name: QualName::new(None, ns!(), local_name!("type")),
+                value: "text/plain".into(),
+            });
+        }
+    } else if script_action == AssetAction::Drop {
+        // Get rid of src and inner content of SCRIPT tags
+        remove_attr(attrs, "src");
+        node.children.borrow_mut().clear();
+    } else if script_action == AssetAction::Keep {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "src" {
+                let src_full_url: String = resolve_reference(url, &attr.value);
+                attr.value.clear();
+                attr.value.push_slice(src_full_url.as_str());
+            }
+        }
+    } else {
+        let mut failed = false;
+        let script_type = attrs
+            .iter()
+            .find(|attr| &attr.name.local == "type")
+            .map(|attr| attr.value.trim().to_lowercase())
+            .unwrap_or_default();
+        let is_executable = is_executable_script_type(&script_type);
+        // Data blocks such as JSON keep their own type, scripts are all JavaScript
+        let script_mime = if is_executable {
+            "application/javascript".to_string()
+        } else {
+            script_type
+        };
+
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "src" {
+                let src_full_url: String = resolve_url(
+                        url,
+                        attr.value.as_ref(),
+                    )
+                    .unwrap_or(EMPTY_STRING.clone());
+                // Code gets looked into for source maps and workers
+                let js_datauri = if is_executable
+                    && is_in_scope(&src_full_url, opts)
+                {
+                    retrieve_cached_asset(
+                        &src_full_url,
+                        false,
+                        &script_mime,
+                        opts,
+                        ctx,
+                    )
+                    .map(|js| {
+                        let mut js =
+                            embed_source_map(&src_full_url, &js, opts, ctx);
+                        if opts.embed_workers {
+                            js = embed_workers(url, &js, opts, ctx);
+                        }
+                        to_dataurl(&src_full_url, &script_mime, js.as_bytes(), opts)
+                    })
+                } else {
+                    retrieve_cached_asset(
+                        &src_full_url,
+                        true,
+                        &script_mime,
+                        opts,
+                        ctx,
+                    )
+                };
+
+                match js_datauri {
+                    Ok(js_datauri) => {
+                        attr.value.clear();
+                        attr.value.push_slice(js_datauri.as_str());
+                    }
+                    Err(_) => failed = true,
+                }
+            }
+        }
+
+        if failed {
+            remove_attr(attrs, "src");
+        }
+
+        if opts.embed_workers && is_executable {
+            for child in node.children.borrow().iter() {
+                if let NodeData::Text { ref contents } = child.data {
+                    let js = embed_workers(url, &contents.borrow(), opts, ctx);
+                    contents.replace(js.into());
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn embed_form_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    _opts: &Options,
+    _ctx: &mut Context,
+) {
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "action" {
+            let href_full_url: String = resolve_reference(url, &attr.value);
+            attr.value.clear();
+            attr.value.push_slice(href_full_url.as_str());
+        }
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn embed_object_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "data" {
+            let data_url = embed_object_asset(url, &attr.value, opts, ctx);
+            attr.value.clear();
+            attr.value.push_slice(data_url.as_str());
+        }
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn embed_param_element(
+    url: &str,
+    node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    if get_parent_node_name(node) != "object" {
+        return;
+    }
+
+    let is_url_param = attrs.iter().any(|attr| {
+        let param_name = attr.value.trim().to_lowercase();
+        &attr.name.local == "name" && OBJECT_URL_PARAMS.contains(&param_name.as_str())
+    });
+
+    if is_url_param {
+        for attr in attrs.iter_mut() {
+            if &attr.name.local == "value" {
+                let value = embed_object_asset(url, &attr.value, opts, ctx);
+                attr.value.clear();
+                attr.value.push_slice(value.as_str());
+            }
+        }
+    }
+}
+
+// Submit buttons may send the form elsewhere, image ones are images too
+#[allow(clippy::ptr_arg)]
+fn embed_button_element(
+    url: &str,
+    _node: &Handle,
+    name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let is_image_input = name.local.as_ref() == "input"
+        && attrs.iter().any(|attr| {
+            &attr.name.local == "type"
+                && attr.value.trim().eq_ignore_ascii_case("image")
+        });
+
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "formaction" {
+            let href_full_url: String = resolve_reference(url, &attr.value);
+            attr.value.clear();
+            attr.value.push_slice(href_full_url.as_str());
+        } else if &attr.name.local == "src" && is_image_input {
+            let image_datauri =
+                embed_image(url, attr.value.as_ref(), opts, ctx)
+                    .unwrap_or(TRANSPARENT_PIXEL.to_string());
+            attr.value.clear();
+            attr.value.push_slice(image_datauri.as_str());
+        }
+    }
+}
+
+fn embed_style_element(
+    url: &str,
+    node: &Handle,
+    _name: &QualName,
+    _attrs_mut: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    for child in node.children.borrow().iter() {
+        if let NodeData::Text { ref contents } = child.data {
+            let css = embed_css(url, &contents.borrow(), opts, ctx);
+            contents.replace(css.into());
+        }
+    }
+}
+
+fn embed_frame_element(
+    url: &str,
+    _node: &Handle,
+    _name: &QualName,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let mut failed = false;
+
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "src" {
+            let value = attr.value.to_string();
+
+            // Ignore frames with empty source (they cause infinite loops)
+            if value == EMPTY_STRING.clone() {
+                continue;
+            }
+
+            // Frames aren't subject to the policy, the size limit
+            // and the scope apply still
+            let remote_url: String = resolve_reference(url, &value);
+            if !fits_total_budget(opts, ctx) || !is_in_scope(&remote_url, opts) {
+                attr.value.clear();
+                attr.value.push_slice(remote_url.as_str());
+                continue;
+            }
+
+            // Nested documents may have a stricter timeout of their own
+            let frame_opts = Options {
+                timeout: opts.frame_timeout.unwrap_or(opts.timeout),
+                ..opts.clone()
+            };
+            let src_full_url: String = resolve_url(url, &value)
+                .unwrap_or(EMPTY_STRING.clone());
+            let frame_data = match retrieve_cached_asset(
+                &src_full_url,
+                false,
+                "text/html",
+                &frame_opts,
+                ctx,
+            ) {
+                Ok(frame_data) => frame_data,
+                Err(_) => {
+                    failed = true;
+                    continue;
+                }
+            };
+
+            // Don't bother embedding assets of documents already over budget
+            if !fits_frames_budget(opts, ctx, frame_data.len()) {
+                failed = true;
+                continue;
+            }
+
+            let dom = html_to_dom(&frame_data);
+            walk(&src_full_url, &dom.document, &frame_opts, ctx);
+            let mut buf: Vec<u8> = Vec::new();
+            serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+            if !fits_frames_budget(opts, ctx, buf.len()) {
+                failed = true;
+                continue;
+            }
+
+            ctx.frames_size += buf.len();
+            let frame_datauri = to_dataurl(&src_full_url, "text/html", &buf, opts);
+            attr.value.clear();
+            attr.value.push_slice(frame_datauri.as_str());
+        }
+    }
+
+    if failed {
+        remove_attr(attrs, "src");
+    }
+}
+
+fn fits_total_budget(opts: &Options, ctx: &Context) -> bool {
+    match opts.max_total_size {
+        Some(max_total_size) => ctx.embedded_size < max_total_size,
+        None => true,
+    }
+}
+
+fn keep_remote_assets(opts: &Options) -> Options {
+    let mut remote_opts = opts.clone();
+
+    for &category in &[
+        AssetCategory::Image,
+        AssetCategory::Style,
+        AssetCategory::Script,
+        AssetCategory::Font,
+        AssetCategory::Media,
+        AssetCategory::Other,
+    ] {
+        if opts.action_for(category) == AssetAction::Embed {
+            remote_opts.policy.insert(category, AssetAction::Keep);
+        }
+    }
+
+    remote_opts
+}
+
+fn embed_node(url: &str, node: &Handle, opts: &Options, ctx: &mut Context) {
+    match node.data {
+        NodeData::Document => {
+            if opts.no_comments {
+                remove_comments(node);
+            }
+        }
+        NodeData::Doctype { .. } => {}
+        NodeData::Text { .. } => {}
+        NodeData::Comment { .. } => {
+            // Note: comments are kept unless opts.no_comments is set, in which case
+            //       they get removed by their parent node before it digs deeper;
+            //       that includes conditional comments which may contain scripts,
+            //       e.g. <!--[if IE]><script>... (only ever honored by IE [5, 9])
+        }
+        NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } => {
+            let element_handler = opts.element_handlers.get(name.local.as_ref());
+            // Handlers registered by library users take the place of the built-in ones
+            let is_custom = match element_handler {
+                Some(element_handler @ &ElementHandler::Custom(_)) => {
+                    element_handler.apply(node, ctx);
+                    true
+                }
+                _ => false,
+            };
+
+            let attrs_mut = &mut attrs.borrow_mut();
+
+            if opts.refetch_stale {
+                restore_stale_source(attrs_mut);
+            }
+
+            // Checked while the asset is still exactly what the hash was made for
+            if opts.verify_integrity && !is_custom {
+                verify_integrity(url, name.local.as_ref(), attrs_mut, opts, ctx);
+            }
+
+            if !is_custom {
+                embed_image_attributes(url, name.local.as_ref(), attrs_mut, opts, ctx);
+            }
+
+            if let Some(&ElementHandler::BuiltIn(embed_element)) = element_handler {
+                embed_element(url, node, name, attrs_mut, opts, ctx);
+            }
+
+            for attr in attrs_mut.iter_mut() {
+                if &attr.name.local == "style" {
+                    let css = embed_css(url, attr.value.as_ref(), opts, ctx);
+                    attr.value.clear();
+                    attr.value.push_slice(css.as_str());
+                }
+            }
+
+            let no_js = opts.action_for(AssetCategory::Script) == AssetAction::Drop;
+
+            if no_js {
+                // Get rid of JS event attributes
+                for attr in attrs_mut.iter_mut() {
+                    if JS_DOM_EVENT_ATTRS.contains(&attr.name.local.to_lowercase().as_str()) {
+                        attr.value.clear();
+                    }
+                }
+            }
+
+            // Contents of templates live in a fragment of their own, which neither
+            // gets walked nor serialized, so they're brought into the element itself
+            adopt_template_contents(node);
+
+            if opts.no_comments {
+                remove_comments(node);
+            }
+
+            // Saved pages shouldn't give away that they're being viewed
+            if !opts.keep_network_hints {
+                remove_links(node, &NETWORK_HINTS);
+            }
+
+            // Whatever the page expected to be needed next is of no use unless it comes along
+            if !opts.embed_prefetch {
+                remove_links(node, &["prefetch"]);
+            }
+
+            if opts.strip_canonical {
+                remove_canonical_links(node);
+            }
+
+            if opts.strip_meta {
+                remove_meta_tags(node, opts);
+            }
+
+            // An archive which navigates away as soon as it's opened is of little use
+            if opts.strip_refresh {
+                remove_refresh(node);
+            }
+
+            if opts.strip_inactive_stylesheets {
+                remove_inactive_stylesheets(node);
+            }
+
+            if opts.removes_images() {
+                remove_images(node);
+            }
+
+            if opts.flatten_pictures {
+                flatten_pictures(node);
+            }
+
+            if opts.inline_stylesheets {
+                inline_stylesheets(url, node, opts, ctx);
+            }
+
+            if let Some(ref media_source) = opts.media_source {
+                if name.local.as_ref() == "video" || name.local.as_ref() == "audio" {
+                    select_media_source(url, node, media_source, opts);
+                }
+            }
+
+            if no_js {
+                // Fallback content becomes the real content once scripts are gone
+                promote_noscript_contents(node);
+            } else if opts.strip_noscript {
+                remove_noscripts(node);
+            }
+        }
+        NodeData::ProcessingInstruction { .. } => unreachable!()
+    }
+}
+
+pub fn discover_assets(url: &str, node: &Handle, opts: &Options) -> Vec<String> {
+    let mut assets: Vec<String> = Vec::new();
+
+    collect_asset_urls(&resolve_base(url, opts), node, opts, &mut assets);
+
+    assets
+}
+
+fn collect_asset_urls(url: &str, node: &Handle, opts: &Options, assets: &mut Vec<String>) {
+    assets.extend(element_asset_urls(url, node, opts));
+
+    for child in node.children.borrow().iter() {
+        collect_asset_urls(url, child, opts, assets);
+    }
+
+    if let NodeData::Element { template_contents: Some(ref contents), .. } = node.data {
+        collect_asset_urls(url, contents, opts, assets);
+    }
+}
+
+// Assets referenced by the element itself, those of its descendants aside
+fn element_asset_urls(url: &str, node: &Handle, opts: &Options) -> Vec<String> {
+    let mut assets: Vec<String> = Vec::new();
+
+    if let NodeData::Element { ref name, ref attrs, .. } = node.data {
+        let attrs = attrs.borrow();
+        let get_attr = |attr_name: &str| {
+            attrs
+                .iter()
+                .find(|attr| &attr.name.local == attr_name)
+                .map(|attr| attr.value.to_string())
+                .unwrap_or(EMPTY_STRING.clone())
+        };
+        let mut asset_urls: Vec<String> = Vec::new();
+
+        for &(element, attribute) in IMAGE_ATTRIBUTES.iter() {
+            if element == name.local.as_ref()
+                && opts.action_for(AssetCategory::Image) == AssetAction::Embed
+            {
+                asset_urls.push(get_attr(attribute));
+            }
+        }
+
+        match name.local.as_ref() {
+            "link" => {
+                let rel = get_attr("rel");
+
+                if rel == "stylesheet" && !is_inactive_stylesheet(&attrs) {
+                    if opts.action_for(AssetCategory::Style) == AssetAction::Embed {
+                        asset_urls.push(get_attr("href"));
+                    }
+                } else if rel == "preload" && get_attr("as").eq_ignore_ascii_case("fetch") {
+                    if opts.action_for(AssetCategory::Other) == AssetAction::Embed {
+                        asset_urls.push(get_attr("href"));
+                    }
+                } else if rel == "preload"
+                    && (get_attr("as").eq_ignore_ascii_case("video")
+                        || get_attr("as").eq_ignore_ascii_case("audio"))
+                {
+                    let href = get_attr("href");
+                    let media_mime_type = match get_attr("type") {
+                        ref mime_type if mime_type.is_empty() => guess_mimetype(&href).to_string(),
+                        mime_type => mime_type,
+                    };
+
+                    if opts.action_for_type(AssetCategory::Media, &media_mime_type)
+                        == AssetAction::Embed
+                    {
+                        asset_urls.push(href);
+                    }
+                } else if rel.trim().eq_ignore_ascii_case("modulepreload") {
+                    if opts.action_for(AssetCategory::Script) == AssetAction::Embed {
+                        asset_urls.push(get_attr("href"));
+                    }
+                } else if rel.trim().eq_ignore_ascii_case("import") {
+                    if opts.embed_imports {
+                        asset_urls.push(get_attr("href"));
+                    }
+                } else if (is_icon(&rel) || (rel == "preload" && get_attr("as") == "image"))
+                    && opts.action_for(AssetCategory::Image) == AssetAction::Embed
+                {
+                    asset_urls.push(get_attr("href"));
+
+                    for (candidate_url, _) in parse_srcset(&get_attr("imagesrcset")) {
+                        asset_urls.push(candidate_url);
+                    }
+                }
+            }
+            "img" if opts.action_for(AssetCategory::Image) == AssetAction::Embed => {
+                let srcset = get_attr("srcset");
+
+                if opts.single_image && !srcset.trim().is_empty() {
+                    let (sizes, src) = (get_attr("sizes"), get_attr("src"));
+                    asset_urls.extend(select_srcset_candidate(
+                        &srcset,
+                        &sizes,
+                        &src,
+                        opts.viewport_width,
+                    ));
+                } else {
+                    asset_urls.push(get_attr("src"));
+                }
+            }
+            "image"
+                if name.ns == ns!(svg)
+                    && opts.action_for(AssetCategory::Image) == AssetAction::Embed =>
+            {
+                asset_urls.push(get_attr("href"));
+            }
+            "input"
+                if get_attr("type").trim().eq_ignore_ascii_case("image")
+                    && opts.action_for(AssetCategory::Image) == AssetAction::Embed =>
+            {
+                asset_urls.push(get_attr("src"));
+            }
+            "source"
+                if opts.action_for(AssetCategory::Image) == AssetAction::Embed
+                    && !opts.flatten_pictures
+                    && get_parent_node_name(node) == "picture" =>
+            {
+                for (candidate_url, _) in parse_srcset(&get_attr("srcset")) {
+                    asset_urls.push(candidate_url);
+                }
+            }
+            "script" if opts.action_for(AssetCategory::Script) == AssetAction::Embed => {
+                asset_urls.push(get_attr("src"));
+            }
+            "frame" | "iframe" => {
+                asset_urls.push(get_attr("src"));
+            }
+            _ => {}
+        }
+
+        for asset_url in asset_urls {
+            // Empty, already embedded and remote-only references don't need to be fetched
+            if asset_url.is_empty()
+                || is_data_url(&asset_url).unwrap_or(false)
+                || opts.keeps_remote(guess_mimetype(&asset_url))
+            {
+                continue;
+            }
+
+            if let Ok(asset_full_url) = resolve_url(url, &asset_url) {
+                assets.push(asset_full_url);
+            }
+        }
+    }
+
+    assets
+}
+
+pub fn estimate_output_size(url: &str, html: &str, opts: &Options) -> Result<usize, MonolithError> {
+    let dom = html_to_dom(html);
+    let mut total_size: usize = html.len();
+
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for asset_url in discover_assets(url, &dom.document, opts) {
+        // Assets referenced more than once only get embedded once
+        if !seen.insert(asset_url.clone()) {
+            continue;
+        }
+
+        // Assets of unknown size are left out of the estimate, so are those which can't be had
+        if let Ok(Some(asset_size)) = retrieve_asset_size(&asset_url, opts) {
+            // Account for base64 overhead of data URLs
+            total_size += asset_size.div_ceil(3) * 4;
+        }
+    }
+
+    Ok(total_size)
+}
+
+fn parse_srcset(srcset: &str) -> Vec<(String, String)> {
+    let mut candidates: Vec<(String, String)> = Vec::new();
+    let mut rest = srcset;
+
+    loop {
+        // Skip leading whitespace and separators
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+
+        if rest.is_empty() {
+            break;
+        }
+
+        // URLs may contain commas (e.g. data URLs), but can't end with one
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        rest = &rest[url_end..];
+
+        if url.ends_with(',') {
+            candidates.push((url.trim_end_matches(',').to_string(), EMPTY_STRING.clone()));
+            continue;
+        }
+
+        let descriptor_end = rest.find(',').unwrap_or(rest.len());
+        let descriptor = rest[..descriptor_end].trim();
+        rest = &rest[descriptor_end..];
+
+        candidates.push((url.to_string(), descriptor.to_string()));
+    }
+
+    candidates
+}
+
+fn embed_image(url: &str, src: &str, opts: &Options, ctx: &mut Context) -> Option<String> {
+    embed_image_as(url, src, "", opts, ctx)
+}
+
+fn embed_image_as(
+    url: &str,
+    src: &str,
+    as_mime: &str,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Option<String> {
+    let mime_type = if as_mime.is_empty() {
+        guess_mimetype(src)
+    } else {
+        as_mime
+    };
+
+    match opts.action_for_type(AssetCategory::Image, mime_type) {
+        AssetAction::Drop => Some(TRANSPARENT_PIXEL.to_string()),
+        AssetAction::Keep => resolve_url(url, src).ok(),
+        AssetAction::Embed => {
+            let src_full_url: String = resolve_url(url, src).ok()?;
+            retrieve_cached_asset(&src_full_url, true, as_mime, opts, ctx).ok()
+        }
+    }
+}
+
+fn embed_image_attributes(
+    url: &str,
+    element_name: &str,
+    attrs: &mut [Attribute],
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    for attr in attrs.iter_mut() {
+        let is_image = IMAGE_ATTRIBUTES.iter().any(|&(element, attribute)| {
+            element == element_name && &attr.name.local == attribute
+        });
+
+        if is_image {
+            let image = embed_image(url, &attr.value, opts, ctx)
+                .unwrap_or_else(|| resolve_reference(url, &attr.value));
+            attr.value.clear();
+            attr.value.push_slice(image.as_str());
+        }
+    }
+}
+
+// Plugins are long gone from browsers, what they used to load is kept around
+// mostly for the record; which is why it only gets embedded when asked to
+fn embed_object_asset(url: &str, reference: &str, opts: &Options, ctx: &mut Context) -> String {
+    let full_url = resolve_reference(url, reference);
+
+    if opts.embed_objects && opts.action_for(AssetCategory::Other) == AssetAction::Embed {
+        retrieve_cached_asset(&full_url, true, "", opts, ctx).unwrap_or(full_url)
+    } else {
+        full_url
+    }
+}
+
+fn embed_srcset(url: &str, srcset: &str, opts: &Options, ctx: &mut Context) -> String {
+    let mut result: Vec<String> = Vec::new();
+
+    for (candidate_url, descriptor) in parse_srcset(srcset) {
+        let candidate_datauri = embed_image(url, &candidate_url, opts, ctx)
+            .unwrap_or(TRANSPARENT_PIXEL.to_string());
+
+        if descriptor.is_empty() {
+            result.push(candidate_datauri);
+        } else {
+            result.push(format!("{} {}", candidate_datauri, descriptor));
+        }
+    }
+
+    result.join(", ")
+}
+
+// Whichever candidate a browser with the given viewport would go for at 1x,
+// i.e. the least dense one that still covers the slot; src only joins in as
+// the 1x candidate when srcset doesn't describe widths
+fn select_srcset_candidate(
+    srcset: &str,
+    sizes: &str,
+    src: &str,
+    viewport_width: u32,
+) -> Option<String> {
+    let slot_width = source_size(sizes, viewport_width).max(1.0);
+    let mut describes_widths = false;
+    let mut candidates: Vec<(String, f64)> = Vec::new();
+
+    for (candidate_url, descriptor) in parse_srcset(srcset) {
+        let descriptor = descriptor.split_whitespace().next().unwrap_or("");
+        let value = descriptor
+            .trim_end_matches(['w', 'x'])
+            .parse::<f64>()
+            .ok();
+        let density = if descriptor.is_empty() {
+            Some(1.0)
+        } else if descriptor.ends_with('w') {
+            describes_widths = true;
+            value.map(|width| width / slot_width)
+        } else if descriptor.ends_with('x') {
+            value
+        } else {
+            None
+        };
+
+        // Anything but a positive number, e.g. "NaNx" or "0w", can't be compared or chosen
+        if let Some(density) = density.filter(|density| density.is_finite() && *density > 0.0) {
+            candidates.push((candidate_url, density));
+        }
+    }
+
+    if !describes_widths
+        && !src.trim().is_empty()
+        && !candidates.iter().any(|&(_, density)| density == 1.0)
+    {
+        candidates.push((src.trim().to_string(), 1.0));
+    }
+
+    let compare = |a: &&(String, f64), b: &&(String, f64)| a.1.partial_cmp(&b.1).unwrap();
+
+    candidates
+        .iter()
+        .filter(|&&(_, density)| density >= 1.0)
+        .min_by(compare)
+        .or_else(|| candidates.iter().max_by(compare))
+        .map(|(candidate_url, _)| candidate_url.clone())
+}
+
+// Width of the slot the image gets laid out in, as told by the first entry of
+// sizes whose media condition holds; the whole viewport when none does
+fn source_size(sizes: &str, viewport_width: u32) -> f64 {
+    for entry in sizes.to_lowercase().split(',') {
+        let entry = entry.trim();
+        let (condition, length) = match entry.rfind(')') {
+            Some(end) if entry.starts_with('(') => (&entry[..=end], entry[end + 1..].trim()),
+            _ => ("", entry),
+        };
+
+        if !condition.is_empty() && !media_condition_matches(condition, viewport_width) {
+            continue;
+        }
+
+        if let Some(width) = css_length(length, viewport_width) {
+            return width;
+        }
+    }
+
+    viewport_width as f64
+}
+
+// Only widths are of any concern here, anything else is taken as not matching
+fn media_condition_matches(condition: &str, viewport_width: u32) -> bool {
+    condition.split(" and ").all(|feature| {
+        let feature = feature.trim().trim_start_matches('(').trim_end_matches(')');
+        let mut parts = feature.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().and_then(|value| css_length(value, viewport_width));
+
+        match (name, value) {
+            ("min-width", Some(width)) => viewport_width as f64 >= width,
+            ("max-width", Some(width)) => viewport_width as f64 <= width,
+            _ => false,
+        }
+    })
+}
+
+fn css_length(length: &str, viewport_width: u32) -> Option<f64> {
+    let length = length.trim();
+    let (number, unit_size) = if let Some(number) = length.strip_suffix("rem") {
+        (number, 16.0)
+    } else if let Some(number) = length.strip_suffix("em") {
+        (number, 16.0)
+    } else if let Some(number) = length.strip_suffix("px") {
+        (number, 1.0)
+    } else if let Some(number) = length.strip_suffix("vw") {
+        (number, viewport_width as f64 / 100.0)
+    } else {
+        return None;
+    };
+
+    number.trim().parse::<f64>().ok().map(|number| number * unit_size)
+}
+
+// Leaves the image with nothing but the one candidate to embed as its src
+fn collapse_srcset(attrs: &mut Vec<Attribute>, viewport_width: u32) {
+    let get_attr = |attr_name: &str| {
+        attrs
+            .iter()
+            .find(|attr| &attr.name.local == attr_name)
+            .map(|attr| attr.value.to_string())
+            .unwrap_or_default()
+    };
+    let srcset = get_attr("srcset");
+
+    if srcset.trim().is_empty() {
+        return;
+    }
+
+    let selected =
+        select_srcset_candidate(&srcset, &get_attr("sizes"), &get_attr("src"), viewport_width);
+
+    if let Some(candidate_url) = selected {
+        remove_attr(attrs, "src");
+        attrs.push(Attribute {
+            name: QualName::new(None, ns!(), local_name!("src")),
+            value: candidate_url.into(),
+        });
+    }
+
+    remove_attr(attrs, "srcset");
+    remove_attr(attrs, "sizes");
+}
+
+// Declared by the type attribute of sources, otherwise guessed from the URL
+fn media_type(attrs: &[Attribute]) -> String {
+    let get_attr = |attr_name: &str| {
+        attrs
+            .iter()
+            .find(|attr| &attr.name.local == attr_name)
+            .map(|attr| attr.value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let media_type = get_attr("type");
+
+    if media_type.is_empty() {
+        guess_mimetype(&get_attr("src")).to_string()
+    } else {
+        media_type
+    }
+}
+
+// Media only ever gets embedded once a source has been picked for it; until then,
+// whatever is meant to stay remote still needs an absolute URL to be found offline
+fn absolutize_kept_media(url: &str, attrs: &mut [Attribute], opts: &Options) {
+    if opts.action_for_type(AssetCategory::Media, &media_type(attrs)) != AssetAction::Keep {
+        return;
+    }
+
+    for attr in attrs.iter_mut() {
+        if &attr.name.local == "src" {
+            let src_full_url: String = resolve_reference(url, &attr.value);
+            attr.value.clear();
+            attr.value.push_slice(src_full_url.as_str());
+        }
+    }
+}
+
+fn get_local_fragment(url: &str, href_full_url: &str) -> Option<String> {
+    let mut page_url = Url::parse(url).ok()?;
+    let mut href_url = Url::parse(href_full_url).ok()?;
+    let fragment = href_url.fragment()?.to_string();
+
+    page_url.set_fragment(None);
+    href_url.set_fragment(None);
+
+    if page_url == href_url {
+        Some(fragment)
+    } else {
+        None
+    }
+}
+
+// Relative to where the page itself is saved, as long as it's one of the pages archived too
+fn get_local_path(url: &str, href: &str, opts: &Options) -> Option<String> {
+    if opts.local_paths.is_empty() {
+        return None;
+    }
+
+    let mut href_url = Url::parse(&resolve_url(url, href).ok()?).ok()?;
+    let fragment = href_url.fragment().map(|fragment| fragment.to_string());
+    href_url.set_fragment(None);
+    let path = opts.local_paths.get(href_url.as_str())?;
+
+    let mut page_url = Url::parse(url).ok()?;
+    page_url.set_fragment(None);
+    let local_path = match opts.local_paths.get(page_url.as_str()) {
+        Some(page_path) => relative_path(page_path, path),
+        None => path.clone(),
+    };
+
+    match fragment {
+        Some(fragment) => Some(format!("{}#{}", local_path, fragment)),
+        None => Some(local_path),
+    }
+}
+
+fn relative_path(from: &str, to: &str) -> String {
+    let mut from_dirs: Vec<&str> = from.split('/').collect();
+    from_dirs.pop();
+    let to_parts: Vec<&str> = to.split('/').collect();
+
+    // The file name itself is never shared, however the directories are named
+    let common = from_dirs
+        .iter()
+        .zip(to_parts[..to_parts.len() - 1].iter())
+        .take_while(|(from_dir, to_dir)| from_dir == to_dir)
+        .count();
+
+    let mut parts: Vec<&str> = vec![".."; from_dirs.len() - common];
+    parts.extend_from_slice(&to_parts[common..]);
+    parts.join("/")
+}
+
+fn is_executable_script_type(script_type: &str) -> bool {
+    let script_type = script_type.trim().to_lowercase();
+
+    script_type.is_empty()
+        || script_type == "module"
+        || script_type.contains("javascript")
+        || script_type.contains("ecmascript")
+        || script_type.contains("jscript")
+}
+
+fn fits_frames_budget(opts: &Options, ctx: &Context, size: usize) -> bool {
+    match opts.max_frames_size {
+        Some(max_frames_size) => ctx.frames_size + size <= max_frames_size,
+        None => true,
+    }
+}
+
+// Imported documents get their own assets embedded the same way frames do,
+// ones which can't be retrieved are better off still pointing at the web
+fn embed_import(href_full_url: &str, opts: &Options, ctx: &mut Context) -> Option<String> {
+    if ctx.imports.iter().any(|import| import == href_full_url)
+        || !fits_total_budget(opts, ctx)
+        || !is_in_scope(href_full_url, opts)
+    {
+        return None;
+    }
+
+    let import_data = retrieve_cached_asset(href_full_url, false, "text/html", opts, ctx).ok()?;
+    let dom = html_to_dom(&import_data);
+
+    ctx.imports.push(href_full_url.to_string());
+    walk(href_full_url, &dom.document, opts, ctx);
+    ctx.imports.pop();
+
+    let mut buf: Vec<u8> = Vec::new();
+    serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+    Some(to_dataurl(href_full_url, "text/html", &buf, opts))
+}
+
+// Assets which were already embedded and haven't changed are kept exactly as they were
+fn to_dataurl(source_url: &str, mime: &str, data: &[u8], opts: &Options) -> String {
+    match dataurl_to_data(source_url) {
+        Some((_, ref original_data)) if &original_data[..] == data => source_url.to_string(),
+        _ => data_to_dataurl_with(mime, data, opts.dataurl_encoding_for(mime)),
+    }
+}
+
+// Embedded assets marked as stale, e.g. <img src="data:..." data-stale="/logo.png">,
+// get their data URL swapped for where they are to be retrieved from once again
+fn restore_stale_source(attrs: &mut Vec<Attribute>) {
+    let source = match attrs.iter().find(|attr| &attr.name.local == "data-stale") {
+        Some(attr) => attr.value.to_string(),
+        None => return,
+    };
+
+    for attr in attrs.iter_mut() {
+        if ["data", "href", "poster", "src"].contains(&attr.name.local.as_ref())
+            && is_data_url(&attr.value).unwrap_or(false)
+        {
+            attr.value.clear();
+            attr.value.push_slice(&source);
+        }
+    }
+
+    remove_attr(attrs, "data-stale");
+}
+
+fn remove_attr(attrs: &mut Vec<Attribute>, attr_name: &str) {
+    attrs.retain(|attr| &attr.name.local != attr_name);
+}
+
+// Takes out links of any of the given types, e.g. ones with rel="preconnect dns-prefetch"
+fn remove_links(node: &Handle, link_types: &[&str]) {
+    node.children.borrow_mut().retain(|child| !is_link_of_type(child, link_types));
+}
+
+fn is_link_of_type(node: &Handle, link_types: &[&str]) -> bool {
+    match node.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "link" => attrs
+            .borrow()
+            .iter()
+            .filter(|attr| &attr.name.local == "rel")
+            .any(|attr| {
+                attr.value
+                    .to_lowercase()
+                    .split_whitespace()
+                    .any(|rel| link_types.contains(&rel))
+            }),
+        _ => false,
+    }
+}
+
+// Keeps the saved page from reaching out to the network, inline and embedded assets are
+// all it's left with; nonces and integrity hashes of the original page mean nothing anymore
+fn inject_csp(node: &Handle, opts: &Options) {
+    for_each_element(node, |node| {
+        if let NodeData::Element { ref name, ref attrs, .. } = node.data {
+            let mut attrs = attrs.borrow_mut();
+            remove_attr(&mut attrs, "nonce");
+            remove_attr(&mut attrs, "integrity");
+
+            if name.local.as_ref() == "head" {
+                let policy = if opts.action_for(AssetCategory::Script) == AssetAction::Drop {
+                    "default-src 'unsafe-inline' data:; script-src 'none';"
+                } else {
+                    "default-src 'unsafe-inline' data:;"
+                };
+                let meta = Node::new(NodeData::Element {
+                    name: QualName::new(None, ns!(html), local_name!("meta")),
+                    attrs: RefCell::new(vec![
+                        Attribute {
+                            name: QualName::new(None, ns!(), local_name!("http-equiv")),
+                            value: "Content-Security-Policy".into(),
+                        },
+                        Attribute {
+                            name: QualName::new(None, ns!(), local_name!("content")),
+                            value: policy.into(),
+                        },
+                    ]),
+                    template_contents: None,
+                    mathml_annotation_xml_integration_point: false,
+                });
+                meta.parent.set(Some(Rc::downgrade(node)));
+                node.children.borrow_mut().insert(0, meta);
+            }
+        }
+    });
+}
+
+// Lets directives which allowed assets to be retrieved allow them in the form of data URLs,
+// and styles (scripts too, unless they're gone) inline; whatever the page had no business
+// loading in the first place, i.e. 'none', stays that way
+fn relax_csp(policy: &str, scripts_kept: bool) -> String {
+    policy
+        .split(';')
+        .filter_map(|directive| {
+            let mut tokens = directive.split_whitespace();
+            let name = tokens.next()?.to_lowercase();
+            let mut sources: Vec<String> = tokens.map(String::from).collect();
+            let is_script = name.starts_with("script-src");
+            let is_style = name.starts_with("style-src");
+
+            if !CSP_FETCH_DIRECTIVES.contains(&name.as_str())
+                || (is_script && !scripts_kept)
+                || sources.iter().any(|source| source.eq_ignore_ascii_case("'none'"))
+            {
+                return Some(directive.trim().to_string());
+            }
+
+            // Browsers ignore 'unsafe-inline' as soon as there's a nonce or a hash around,
+            // and with 'strict-dynamic' they ignore everything but those
+            sources.retain(|source| {
+                let source = source.to_lowercase();
+                !source.starts_with("'nonce-")
+                    && !source.starts_with("'sha")
+                    && source != "'strict-dynamic'"
+            });
+
+            if !sources.iter().any(|source| source.eq_ignore_ascii_case("data:")) {
+                sources.push("data:".to_string());
+            }
+
+            if (name == "default-src" || is_style || is_script)
+                && !sources.iter().any(|source| source.eq_ignore_ascii_case("'unsafe-inline'"))
+            {
+                sources.push("'unsafe-inline'".to_string());
+            }
+
+            Some(format!("{} {}", name, sources.join(" ")))
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+// Screen readers, hyphenation and fonts all go by the language, which the page
+// is better off declaring itself; whatever it does declare is left as it is
+fn add_default_lang(node: &Handle, default_lang: &str) {
+    for_each_element(node, |node| match node.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "html" => {
+            let mut attrs = attrs.borrow_mut();
+
+            if !attrs.iter().any(|attr| &attr.name.local == "lang") {
+                attrs.push(Attribute {
+                    name: QualName::new(None, ns!(), local_name!("lang")),
+                    value: default_lang.into(),
+                });
+            }
+        }
+        _ => {}
+    });
+}
+
+// Identical input and options make for byte-identical output no matter
+// which order the parser happened to keep attributes in
+fn normalize_attributes(node: &Handle) {
+    for_each_element(node, |node| {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            attrs.borrow_mut().sort_by(|a, b| {
+                (a.name.local.as_ref(), a.name.ns.as_ref())
+                    .cmp(&(b.name.local.as_ref(), b.name.ns.as_ref()))
+            });
+        }
+    });
+}
+
+// Sources of the same clip differ only in quality or format,
+// one of them is all that's worth the space it takes up
+fn select_media_source(url: &str, node: &Handle, media_source: &MediaSource, opts: &Options) {
+    // (index, src, type)
+    let sources: Vec<(usize, String, String)> = node
+        .children
+        .borrow()
+        .iter()
+        .enumerate()
+        .filter_map(|(index, child)| match child.data {
+            NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "source" => {
+                let attrs = attrs.borrow();
+                let get_attr = |attr_name: &str| {
+                    attrs
+                        .iter()
+                        .find(|attr| &attr.name.local == attr_name)
+                        .map(|attr| attr.value.to_string())
+                        .unwrap_or_default()
+                };
+
+                Some((index, get_attr("src"), get_attr("type")))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let selected = match *media_source {
+        // Sources of unknown size can't be told apart, the first of equals wins
+        MediaSource::Smallest => sources
+            .iter()
+            .filter_map(|&(index, ref src, _)| {
+                let src_full_url = resolve_url(url, src).ok()?;
+                let size = retrieve_asset_size(&src_full_url, opts).ok()??;
+
+                Some((size, index))
+            })
+            .min()
+            .map(|(_, index)| index),
+        MediaSource::Types(ref types) => types
+            .iter()
+            .filter_map(|preferred_type| {
+                sources.iter().find(|&(_, _, source_type)| {
+                    source_type
+                        .split(';')
+                        .next()
+                        .unwrap_or("")
+                        .trim()
+                        .eq_ignore_ascii_case(preferred_type)
+                })
+            })
+            .map(|&(index, _, _)| index)
+            .next(),
+    }
+    .or(sources.first().map(|&(index, _, _)| index));
+
+    let children: Vec<Handle> = node
+        .children
+        .borrow()
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| {
+            Some(index) == selected || !sources.iter().any(|source| source.0 == index)
+        })
+        .map(|(_, child)| child.clone())
+        .collect();
+    *node.children.borrow_mut() = children;
+}
+
+fn remove_canonical_links(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "link" => !attrs
+            .borrow()
+            .iter()
+            .filter(|attr| &attr.name.local == "rel")
+            .any(|attr| attr.value.to_lowercase().split_whitespace().any(|rel| rel == "canonical")),
+        _ => true,
+    });
+}
+
+fn remove_refresh(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "meta" => {
+            !attrs.borrow().iter().any(|attr| {
+                &attr.name.local == "http-equiv"
+                    && attr.value.trim().eq_ignore_ascii_case("refresh")
+            })
+        }
+        _ => true,
+    });
+}
+
+// Refreshes look like "5; url=next.html", the URL being optional and possibly quoted
+fn resolve_refresh(url: &str, content: &str) -> String {
+    let separator = match content.find([';', ',']) {
+        Some(separator) => separator,
+        None => return content.to_string(),
+    };
+    let delay = content[..separator].trim();
+    let mut target = content[separator + 1..].trim();
+
+    if target.get(..3).is_some_and(|prefix| prefix.eq_ignore_ascii_case("url")) {
+        let rest = target[3..].trim_start();
+
+        if let Some(rest) = rest.strip_prefix('=') {
+            target = rest.trim_start();
+        }
+    }
+
+    let target = target.trim_matches(|c| c == '"' || c == '\'');
+
+    if target.is_empty() {
+        return content.to_string();
+    }
+
+    format!("{}; url={}", delay, resolve_reference(url, target))
+}
+
+fn remove_inactive_stylesheets(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "link" => {
+            !is_inactive_stylesheet(&attrs.borrow())
+        }
+        _ => true,
+    });
+}
+
+// Assets which don't hash to what the page expects could well have been tampered with,
+// those are left out as if they couldn't be retrieved; the ones which do match lose the hash,
+// they rarely end up embedded exactly the way they were served
+fn verify_integrity(
+    url: &str,
+    element_name: &str,
+    attrs: &mut Vec<Attribute>,
+    opts: &Options,
+    ctx: &mut Context,
+) {
+    let get_attr = |attr_name: &str| {
+        attrs
+            .iter()
+            .find(|attr| &attr.name.local == attr_name)
+            .map(|attr| attr.value.trim().to_string())
+    };
+    let integrity = match get_attr("integrity") {
+        Some(integrity) => integrity,
+        None => return,
+    };
+    let rel = get_attr("rel").unwrap_or_default().to_lowercase();
+    // Same as what the asset gets embedded as, so that it's only ever retrieved once
+    let (url_attr, category, as_mime) = match element_name {
+        // Data blocks don't get loaded, their integrity doesn't matter
+        "script" if is_executable_script_type(&get_attr("type").unwrap_or_default()) => {
+            ("src", AssetCategory::Script, "application/javascript")
+        }
+        "link" if rel == "stylesheet" && !is_inactive_stylesheet(attrs) => {
+            ("href", AssetCategory::Style, "text/css")
+        }
+        "link" if rel == "modulepreload" => {
+            ("href", AssetCategory::Script, "application/javascript")
+        }
+        _ => return,
+    };
+
+    // Assets which aren't about to be embedded are still checked by the browser
+    if opts.action_for(category) != AssetAction::Embed {
+        return;
+    }
+
+    let asset_full_url = match get_attr(url_attr).map(|asset_url| resolve_url(url, &asset_url)) {
+        Some(Ok(asset_full_url)) => asset_full_url,
+        _ => return,
+    };
+
+    match integrity_matches(&asset_full_url, &integrity, as_mime, opts, ctx) {
+        Some(true) => remove_attr(attrs, "integrity"),
+        Some(false) => {
+            if !opts.silent {
+                eprintln!("{}", MonolithError::IntegrityMismatch { url: asset_full_url });
+            }
+
+            remove_attr(attrs, url_attr);
+        }
+        // Whatever can't be retrieved gets dealt with the usual way
+        None => {}
+    }
+}
+
+// Hashes are made from what the server sent, e.g. before legacy charsets get converted to UTF-8,
+// so they get checked as the asset is retrieved, which leaves it cached for what embeds it next
+fn integrity_matches(
+    url: &str,
+    integrity: &str,
+    as_mime: &str,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Option<bool> {
+    ctx.integrity.insert(url.to_string(), integrity.to_string());
+
+    match retrieve_cached_asset(url, false, as_mime, opts, ctx) {
+        Ok(_) => Some(true),
+        Err(MonolithError::IntegrityMismatch { .. }) => Some(false),
+        Err(_) => None,
+    }
+}
+
+// Alternate stylesheets only apply once picked by the user, disabled ones not at all
+fn is_inactive_stylesheet(attrs: &[Attribute]) -> bool {
+    let rel = attrs
+        .iter()
+        .find(|attr| &attr.name.local == "rel")
+        .map(|attr| attr.value.to_lowercase())
+        .unwrap_or_default();
+    let rels: Vec<&str> = rel.split_whitespace().collect();
+
+    let is_disabled = attrs.iter().any(|attr| &attr.name.local == "disabled");
+
+    rels.contains(&"stylesheet") && (rels.contains(&"alternate") || is_disabled)
+}
+
+fn remove_images(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, .. } => {
+            name.local.as_ref() != "img" && name.local.as_ref() != "picture"
+        }
+        _ => true,
+    });
+}
+
+// Metadata means nothing to a saved page, except for what it takes to decode it
+// and, unless that's unwanted as well, to lay it out on mobile devices
+fn remove_meta_tags(node: &Handle, opts: &Options) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "meta" => {
+            let attrs = attrs.borrow();
+            let has_attr = |attr_name: &str, value: &str| {
+                attrs.iter().any(|attr| {
+                    &attr.name.local == attr_name && attr.value.trim().eq_ignore_ascii_case(value)
+                })
+            };
+            let is_charset = attrs.iter().any(|attr| &attr.name.local == "charset")
+                || has_attr("http-equiv", "content-type");
+
+            is_charset || (has_attr("name", "viewport") && !opts.strip_viewport)
+        }
+        _ => true,
+    });
+}
+
+// Every candidate of every source embedded makes for a lot of bytes to show one image,
+// so each <picture> gets replaced by its own <img>, which then gets embedded as usual
+fn flatten_pictures(node: &Handle) {
+    for child in node.children.borrow_mut().iter_mut() {
+        let img = match child.data {
+            NodeData::Element { ref name, .. } if name.local.as_ref() == "picture" => {
+                picture_to_img(child)
+            }
+            _ => None,
+        };
+
+        if let Some(img) = img {
+            img.parent.set(Some(Rc::downgrade(node)));
+            *child = img;
+        }
+    }
+}
+
+// The <img> is what browsers fall back to, its src is the one candidate which has to work
+// everywhere; only if there's none the first candidate of the first source is used instead
+fn picture_to_img(picture: &Handle) -> Option<Handle> {
+    let children = picture.children.borrow();
+    let img = children
+        .iter()
+        .find(|child| match child.data {
+            NodeData::Element { ref name, .. } => name.local.as_ref() == "img",
+            _ => false,
+        })?
+        .clone();
+    let source_candidate = children
+        .iter()
+        .filter_map(|child| match child.data {
+            NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "source" => {
+                attrs
+                    .borrow()
+                    .iter()
+                    .find(|attr| &attr.name.local == "srcset")
+                    .and_then(|attr| parse_srcset(&attr.value).into_iter().next())
+                    .map(|(candidate_url, _)| candidate_url)
+            }
+            _ => None,
+        })
+        .next();
+
+    if let NodeData::Element { ref attrs, .. } = img.data {
+        let attrs_mut = &mut attrs.borrow_mut();
+        let has_src = attrs_mut
+            .iter()
+            .any(|attr| &attr.name.local == "src" && !attr.value.trim().is_empty());
+
+        if let (false, Some(candidate_url)) = (has_src, source_candidate) {
+            remove_attr(attrs_mut, "src");
+            attrs_mut.push(Attribute {
+                name: QualName::new(None, ns!(), local_name!("src")),
+                value: candidate_url.into(),
+            });
+        }
+
+        // Otherwise all of the candidates would get embedded all over again
+        remove_attr(attrs_mut, "srcset");
+        remove_attr(attrs_mut, "sizes");
+    }
+
+    Some(img)
+}
+
+fn adopt_template_contents(node: &Handle) {
+    if let NodeData::Element { template_contents: Some(ref contents), .. } = node.data {
+        for child in contents.children.borrow_mut().drain(..) {
+            child.parent.set(Some(Rc::downgrade(node)));
+            node.children.borrow_mut().push(child);
+        }
+    }
+}
+
+fn remove_comments(node: &Handle) {
+    node.children
+        .borrow_mut()
+        .retain(|child| !matches!(child.data, NodeData::Comment { .. }));
+}
+
+fn inline_stylesheets(url: &str, node: &Handle, opts: &Options, ctx: &mut Context) {
+    for child in node.children.borrow_mut().iter_mut() {
+        let style = match child.data {
+            NodeData::Element { ref name, ref attrs, .. } if name.local.as_ref() == "link" => {
+                stylesheet_to_style(url, &attrs.borrow(), opts, ctx)
+            }
+            _ => None,
+        };
+
+        if let Some(style) = style {
+            style.parent.set(Some(Rc::downgrade(node)));
+            *child = style;
+        }
+    }
+}
+
+fn stylesheet_to_style(
+    url: &str,
+    attrs: &[Attribute],
+    opts: &Options,
+    ctx: &mut Context,
+) -> Option<Handle> {
+    let get_attr = |attr_name: &str| {
+        attrs
+            .iter()
+            .find(|attr| &attr.name.local == attr_name)
+            .map(|attr| attr.value.trim().to_string())
+            .unwrap_or(EMPTY_STRING.clone())
+    };
+
+    if get_attr("rel").to_lowercase() != "stylesheet"
+        || is_inactive_stylesheet(attrs)
+        || opts.action_for(AssetCategory::Style) != AssetAction::Embed
+    {
+        return None;
+    }
+
+    // Stylesheets which fail to load are left for the walker to deal with
+    let href_full_url = resolve_url(url, &get_attr("href")).ok()?;
+    let integrity = get_attr("integrity");
+    if opts.verify_integrity
+        && !integrity.is_empty()
+        && integrity_matches(&href_full_url, &integrity, "text/css", opts, ctx) == Some(false)
+    {
+        return None;
+    }
+    let css = retrieve_cached_asset(&href_full_url, false, "text/css", opts, ctx).ok()?;
+    let css = embed_css(&href_full_url, &css, opts, ctx);
+    let media = get_attr("media");
+    let css = if media.is_empty() || media.eq_ignore_ascii_case("all") {
+        css
+    } else {
+        format!("@media {} {{ {} }}", media, css)
+    };
+
+    let style = Node::new(NodeData::Element {
+        name: QualName::new(None, ns!(html), local_name!("style")),
+        attrs: RefCell::new(
+            attrs
+                .iter()
+                .filter(|attr| ["id", "nonce", "title"].contains(&attr.name.local.as_ref()))
+                .cloned()
+                .collect(),
+        ),
+        template_contents: None,
+        mathml_annotation_xml_integration_point: false,
+    });
+    let text = Node::new(NodeData::Text {
+        contents: RefCell::new(css.into()),
+    });
+    text.parent.set(Some(Rc::downgrade(&style)));
+    style.children.borrow_mut().push(text);
+
+    Some(style)
+}
+
+fn promote_noscript_contents(node: &Handle) {
+    let children: Vec<Handle> = node.children.borrow_mut().drain(..).collect();
+    let mut new_children: Vec<Handle> = Vec::new();
+
+    for child in children {
+        let is_noscript = match child.data {
+            NodeData::Element { ref name, .. } => name.local.as_ref() == "noscript",
+            _ => false,
+        };
+
+        if !is_noscript {
+            new_children.push(child);
+            continue;
+        }
+
+        // Since the document gets parsed with scripting enabled,
+        // contents of NOSCRIPT tags end up being plain text
+        for grandchild in child.children.borrow_mut().drain(..) {
+            match grandchild.data {
+                NodeData::Text { ref contents } => {
+                    let fragment = html_fragment_to_dom(&contents.borrow());
+                    let root = fragment.document.children.borrow()[0].clone();
+
+                    for fragment_node in root.children.borrow_mut().drain(..) {
+                        new_children.push(fragment_node);
+                    }
+                }
+                _ => new_children.push(grandchild),
+            }
+        }
+    }
+
+    for child in new_children.iter() {
+        child.parent.set(Some(Rc::downgrade(node)));
+    }
+
+    *node.children.borrow_mut() = new_children;
+}
+
+fn remove_noscripts(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Element { ref name, .. } => name.local.as_ref() != "noscript",
+        _ => true,
+    });
+}
+
+fn has_protocol(url: &str) -> bool {
+    HAS_PROTOCOL.is_match(&url.to_lowercase())
+}
+
+// References with a scheme other than HTTP(S), e.g. mailto: or tel:, are left exactly
+// as they are, and so is anything else which can't be resolved against the page
+fn resolve_reference(url: &str, reference: &str) -> String {
+    let trimmed = reference.trim();
+
+    if has_protocol(trimmed) && !is_valid_url(trimmed) {
+        return reference.to_string();
+    }
+
+    resolve_url(url, reference).unwrap_or(reference.to_string())
+}
+
+pub fn process_html(url: &str, html: &str, opts: &Options) -> Result<String, MonolithError> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    process_html_to_writer(url, html, opts, &mut buf)?;
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+pub fn process_html_with_stats(
+    url: &str,
+    html: &str,
+    opts: &Options,
+) -> Result<(String, ArchiveStats), MonolithError> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut stats = process_dom_to_writer(url, &html_to_dom(html), opts, &mut buf)?;
+    stats.total_size = buf.len();
+
+    Ok((String::from_utf8_lossy(&buf).to_string(), stats))
+}
+
+// Same as process_html(), except that assets found in the page get retrieved concurrently
+// and without blocking; the walk itself happens in one go once they're all in, and blocks
+// the thread it's polled on while retrieving whatever else it comes across (e.g. assets of
+// stylesheets and frames). The page only gets parsed once, which keeps the future on
+// the thread it was made on
+pub fn process_html_async(
+    url: &str,
+    html: &str,
+    opts: &Options,
+) -> impl Future<Item = String, Error = MonolithError> {
+    let dom = html_to_dom(html);
+    // Custom fetchers, delays between requests and robots.txt lookups all block,
+    // those are better off being taken care of during the walk
+    let asset_urls = if opts.fetcher.is_some() || opts.host_delay.is_some() || opts.respect_robots {
+        Vec::new()
+    } else {
+        discover_assets(url, &dom.document, opts)
+    };
+    let url = url.to_string();
+    let opts = opts.clone();
+
+    prefetch_assets(asset_urls, &opts).and_then(move |assets| {
+        let opts = Options {
+            fetcher: Some(CustomFetcher::new(PrefetchedFetcher::new(assets, &opts))),
+            ..opts
+        };
+        let mut buf: Vec<u8> = Vec::new();
+
+        process_dom_to_writer(&url, &dom, &opts, &mut buf)?;
+
+        Ok(String::from_utf8_lossy(&buf).to_string())
+    })
+}
+
+pub fn process_html_to_writer<W: Write>(
+    url: &str,
+    html: &str,
+    opts: &Options,
+    writer: W,
+) -> Result<(), MonolithError> {
+    process_dom_to_writer(url, &html_to_dom(html), opts, writer).map(|_| ())
+}
+
+fn process_dom_to_writer<W: Write>(
+    url: &str,
+    dom: &RcDom,
+    opts: &Options,
+    writer: W,
+) -> Result<ArchiveStats, MonolithError> {
+    // Relative URLs can't be resolved against a base that isn't a valid URL
+    let base = resolve_base(url, opts);
+    Url::parse(&base).map_err(|err| MonolithError::ParseError {
+        url: base.clone(),
+        source: err,
+    })?;
+
+    let stats = walk_and_embed_assets(url, &dom.document, opts);
+
+    if opts.strict {
+        if let Some(ref err) = stats.first_failure {
+            return Err(err.clone());
+        }
+    }
+
+    let result = if opts.self_extracting {
+        let mut document: Vec<u8> = Vec::new();
+        write_document(&dom.document, opts, &mut document)
+            .and_then(|_| write_self_extracting(&document, writer))
+    } else {
+        write_document(&dom.document, opts, writer)
+    };
+
+    result
+        .map(|_| stats)
+        .map_err(|err| MonolithError::WriteError(err.to_string()))
+}
+
+fn write_document<W: Write>(handle: &Handle, opts: &Options, writer: W) -> io::Result<()> {
+    if opts.pretty {
+        serialize_pretty_to_writer(handle, writer)
+    } else {
+        serialize_to_writer(handle, writer)
+    }
+}
+
+pub fn html_to_dom(data: &str) -> html5ever::rcdom::RcDom {
+    parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut data.as_bytes())
+        .unwrap()
+}
+
+fn html_fragment_to_dom(data: &str) -> html5ever::rcdom::RcDom {
+    parse_fragment(
+        RcDom::default(),
+        Default::default(),
+        QualName::new(None, ns!(html), local_name!("body")),
+        vec![],
+    )
+    .from_utf8()
+    .read_from(&mut data.as_bytes())
+    .unwrap()
+}
+
+pub fn serialize_to_writer<W: Write>(handle: &Handle, mut writer: W) -> io::Result<()> {
+    serialize(&mut writer, handle, SerializeOpts::default())?;
+    writer.flush()
+}
+
+pub fn serialize_pretty_to_writer<W: Write>(handle: &Handle, mut writer: W) -> io::Result<()> {
+    serialize_pretty(&mut writer, handle)?;
+    writer.flush()
+}
+
+pub fn print_dom(handle: &Handle) {
+    serialize_to_writer(handle, io::stdout()).unwrap();
+}
+
+fn is_icon(attr_value: &str) -> bool {
+    ICON_VALUES.is_match(&attr_value.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use http::AssetFetcher;
+    use manifest::ManifestEntry;
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use test_server::{response, serve};
+    use tokio::runtime::current_thread;
+    use utils::{data_to_dataurl, DataUrlEncoding};
+
+    #[test]
+    fn test_is_icon() {
+        assert_eq!(is_icon("icon"), true);
+        assert_eq!(is_icon("Shortcut Icon"), true);
+        assert_eq!(is_icon("ICON"), true);
+        assert_eq!(is_icon("mask-icon"), true);
+        assert_eq!(is_icon("fluid-icon"), true);
+        assert_eq!(is_icon("stylesheet"), false);
+        assert_eq!(is_icon(""), false);
+    }
+
+    #[test]
+    fn test_has_protocol() {
+        assert_eq!(has_protocol("mailto:somebody@somewhere.com?subject=hello"), true);
+        assert_eq!(has_protocol("tel:5551234567"), true);
+        assert_eq!(has_protocol("ftp:user:password@some-ftp-server.com"), true);
+        assert_eq!(has_protocol("javascript:void(0)"), true);
+        assert_eq!(has_protocol("http://news.ycombinator.com"), true);
+        assert_eq!(has_protocol("https://github.com"), true);
+        assert_eq!(has_protocol("//some-hostname.com/some-file.html"), false);
+        assert_eq!(has_protocol("some-hostname.com/some-file.html"), false);
+        assert_eq!(has_protocol("/some-file.html"), false);
+        assert_eq!(has_protocol(""), false);
+        assert_eq!(has_protocol("MAILTO:somebody@somewhere.com?subject=hello"), true);
+    }
+
+    #[test]
+    fn test_resolve_reference() {
+        let url = "https://example.com/docs/";
+
+        assert_eq!(
+            resolve_reference(&url, "mailto:Somebody@Somewhere.com"),
+            "mailto:Somebody@Somewhere.com"
+        );
+        assert_eq!(resolve_reference(&url, "tel:+1 555 1234"), "tel:+1 555 1234");
+        assert_eq!(resolve_reference(&url, "../contact"), "https://example.com/contact");
+        assert_eq!(resolve_reference(&url, "https://example.com"), "https://example.com/");
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_special_schemes() {
+        let html = "<form action=\"mailto:feedback@example.com\"></form>\
+                    <form action=\"subscribe\"></form>\
+                    <map name=\"contact\"><area href=\"tel:+1 555 1234\">\
+                    <area href=\"mailto:sales@example.com\"><area href=\"../about.html\"></map>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/docs/";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><form action=\"mailto:feedback@example.com\"></form>\
+             <form action=\"http://localhost/docs/subscribe\"></form>\
+             <map name=\"contact\"><area href=\"tel:+1 555 1234\">\
+             <area href=\"mailto:sales@example.com\">\
+             <area href=\"http://localhost/about.html\"></map></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_objects() {
+        let base_url = serve(vec![(
+            "/media/intro.swf",
+            response("application/x-shockwave-flash", b"FWS"),
+        )]);
+        let html = "<object type=\"application/x-shockwave-flash\" data=\"media/intro.swf\">\
+                    <param name=\"movie\" value=\"media/intro.swf\">\
+                    <param name=\"quality\" value=\"high\"></object>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_objects = Options {
+            embed_objects: true,
+            ..opts.clone()
+        };
+        let expected = |swf: &str| {
+            format!(
+                "<html><head></head><body>\
+                 <object type=\"application/x-shockwave-flash\" data=\"{0}\">\
+                 <param name=\"movie\" value=\"{0}\">\
+                 <param name=\"quality\" value=\"high\"></object></body></html>",
+                swf
+            )
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            expected(&format!("{}/media/intro.swf", base_url))
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_objects);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            expected(&data_to_dataurl("application/x-shockwave-flash", b"FWS"))
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_html_import() {
+        let base_url = serve(vec![
+            (
+                "/component.html",
+                response(
+                    "text/html",
+                    b"<link rel=\"import\" href=\"component.html\"><img src=\"badge.png\">",
+                ),
+            ),
+            ("/badge.png", response("image/png", b"badge")),
+        ]);
+        let html = "<link rel=\"import\" href=\"component.html\">";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_imports = Options {
+            embed_imports: true,
+            ..opts.clone()
+        };
+        let expected = |component: &str| {
+            format!(
+                "<html><head><link rel=\"import\" href=\"{}\"></head><body></body></html>",
+                component
+            )
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            expected(&format!("{}/component.html", base_url))
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_imports);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        // The component importing itself is left pointing at the web
+        let component = format!(
+            "<html><head><link rel=\"import\" href=\"{0}/component.html\"></head>\
+             <body><img src=\"{1}\"></body></html>",
+            base_url,
+            data_to_dataurl("image/png", b"badge")
+        );
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            expected(&data_to_dataurl("text/html", component.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_verify_integrity_once() {
+        // Whatever gets asked for a second time isn't what the page was made with
+        let base_url = serve(vec![
+            ("/app.js", response("application/javascript", b"null;")),
+            ("/app.js", response("application/javascript", b"steal();")),
+        ]);
+        let html = "<script src=\"app.js\" integrity=\"sha384-\
+                    +vjDmvOTkie0rvFdlr8rVD66RBDMXrUqxLeZnBSel8EVR7pkvLHQH2bVYBfF3SM+\"></script>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            verify_integrity: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><script src=\"{}\"></script></head><body></body></html>",
+                data_to_dataurl("application/javascript", b"null;")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_verify_integrity() {
+        let base_url = serve(vec![
+            ("/style.css", response("text/css", b"body {}")),
+            ("/app.js", response("application/javascript", b"null;")),
+            ("/lib.js", response("application/javascript", b"lib();")),
+            (
+                "/latin1.css",
+                response(
+                    "text/css; charset=ISO-8859-1",
+                    b"p::before { content: \"\xA7\"; }",
+                ),
+            ),
+        ]);
+        let css_integrity = "sha384-\
+                             JvbluEOKMBmUtNHx346xlZFWqKqtOmexOupPSHRCR0NbwTey4wjq9itKKoSWuGsH";
+        let js_integrity = "sha384-\
+                            +vjDmvOTkie0rvFdlr8rVD66RBDMXrUqxLeZnBSel8EVR7pkvLHQH2bVYBfF3SM+";
+        // The library got swapped for something else than what the page was made with
+        let html = format!(
+            "<link rel=\"stylesheet\" href=\"style.css\" integrity=\"{0}\">\
+             <script src=\"app.js\" integrity=\"{1}\" crossorigin=\"anonymous\"></script>\
+             <script src=\"lib.js\" integrity=\"{1}\"></script>\
+             <link rel=\"stylesheet\" href=\"latin1.css\" integrity=\"{2}\">",
+            css_integrity,
+            js_integrity,
+            // Made from the bytes as served, not the ones converted to UTF-8
+            "sha384-oIsJArhgkpkkYyzxe/nxDacn58+vwhbVV05Xd448gkYrlJfWc8SEUWQBxbKcb+PR"
+        );
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            verify_integrity: true,
+            ..Default::default()
+        };
+
+        let stats = walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{}\">\
+                 <script src=\"{}\" crossorigin=\"anonymous\"></script>\
+                 <script integrity=\"{}\"></script><link rel=\"stylesheet\" href=\"{}\">\
+                 </head><body></body></html>",
+                data_to_dataurl("text/css;charset=utf-8", b"body {}"),
+                data_to_dataurl("application/javascript", b"null;"),
+                js_integrity,
+                data_to_dataurl(
+                    "text/css;charset=utf-8",
+                    "p::before { content: \"§\"; }".as_bytes()
+                )
+            )
+        );
+        assert_eq!(
+            stats.first_failure,
+            Some(MonolithError::IntegrityMismatch {
+                url: format!("{}/lib.js", base_url),
+            })
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_image_attributes() {
+        let base_url = serve(vec![
+            ("/paper.gif", response("image/gif", b"GIF89a")),
+            ("/still.png", response("image/png", b"still")),
+        ]);
+        let html = "<body background=\"paper.gif\"><video poster=\"still.png\"></video>\
+                    <table background=\"missing.gif\"><tr><td>Cell</td></tr></table></body>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body background=\"{}\"><video poster=\"{}\"></video>\
+                 <table background=\"{}/missing.gif\"><tbody><tr><td>Cell</td></tr></tbody>\
+                 </table></body></html>",
+                data_to_dataurl("image/gif", b"GIF89a"),
+                data_to_dataurl("image/png", b"still"),
+                base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_local_paths() {
+        let html = "<a href=\"../api.html#methods\">API</a>\
+                    <a href=\"http://localhost/docs/guide/\">Guide</a>\
+                    <a href=\"../changelog.html\">Changes</a>";
+        let url = "http://localhost/docs/guide/";
+        let opts = Options::builder()
+            .local_path("http://localhost/docs/guide/", "docs/guide/index.html")
+            .local_path("http://localhost/docs/api.html", "docs/api.html")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><a href=\"../api.html#methods\">API</a>\
+             <a href=\"index.html\">Guide</a>\
+             <a href=\"http://localhost/docs/changelog.html\">Changes</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_formaction() {
+        let base_url = serve(vec![("/go.png", response("image/png", b"go"))]);
+        let html = "<form action=\"search\">\
+                    <button formaction=\"../lucky\">Lucky</button>\
+                    <input type=\"submit\" formaction=\"mailto:search@example.com\">\
+                    <input type=\"image\" src=\"/go.png\" formaction=\"advanced?q=\">\
+                    </form>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/docs/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><form action=\"{0}/docs/search\">\
+                 <button formaction=\"{0}/lucky\">Lucky</button>\
+                 <input type=\"submit\" formaction=\"mailto:search@example.com\">\
+                 <input type=\"image\" src=\"{1}\" formaction=\"{0}/docs/advanced?q=\">\
+                 </form></body></html>",
+                base_url,
+                data_to_dataurl("image/png", b"go")
+            )
+        );
+    }
+
+    fn element_name(node: &Handle) -> String {
+        match node.data {
+            NodeData::Element { ref name, .. } => name.local.as_ref().to_string(),
+            _ => EMPTY_STRING.clone(),
+        }
+    }
+
+    #[test]
+    fn test_for_each_element() {
+        let html = "<div><p>Text<!-- comment --><b></b></p><img></div><ul><li></li></ul>";
+        let dom = html_to_dom(&html);
+        let mut visited: Vec<String> = Vec::new();
+        let mut visited_post_order: Vec<String> = Vec::new();
+
+        for_each_element(&dom.document, |node| visited.push(element_name(node)));
+        for_each_element_post_order(&dom.document, |node| {
+            visited_post_order.push(element_name(node))
+        });
+
+        assert_eq!(visited, vec!["html", "head", "body", "div", "p", "b", "img", "ul", "li"]);
+        assert_eq!(
+            visited_post_order,
+            vec!["head", "b", "p", "img", "div", "li", "ul", "body", "html"]
+        );
+
+        // The embedding walk goes through the very same elements in the very same order
+        let walked: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = Options::builder().silent(true);
+        for name in visited.iter() {
+            let walked = walked.clone();
+            builder = builder.element_handler(name, move |node, _| {
+                walked.lock().unwrap().push(element_name(node))
+            });
+        }
+
+        walk_and_embed_assets("http://localhost/", &dom.document, &builder.build().unwrap());
+
+        assert_eq!(*walked.lock().unwrap(), visited);
+    }
+
+    #[test]
+    fn test_get_parent_node_name() {
+        let html = "<!doctype html><html><HEAD></HEAD><body><div><P></P></div></body></html>";
+        let dom = html_to_dom(&html);
+        let mut count = 0;
+
+        fn test_walk(node: &Handle, i: &mut i8) {
+            *i += 1;
+
+            match &node.data {
+                NodeData::Document => {
+                    for child in node.children.borrow().iter() {
+                        test_walk(child, &mut *i);
+                    }
+                }
+                NodeData::Doctype { .. } => (),
+                NodeData::Text { .. } => (),
+                NodeData::Comment { .. } => (),
+                NodeData::Element { ref name, attrs: _, .. } => {
+                    let node_name = name.local.as_ref().to_string();
+                    let parent_node_name = get_parent_node_name(node);
+                    if node_name == "head" || node_name == "body" {
+                        assert_eq!(parent_node_name, "html");
+                    } else if node_name == "div" {
+                        assert_eq!(parent_node_name, "body");
+                    } else if node_name == "p" {
+                        assert_eq!(parent_node_name, "div");
+                    }
+
+                    println!("{}", node_name);
+
+                    for child in node.children.borrow().iter() {
+                        test_walk(child, &mut *i);
+                    }
+                }
+                NodeData::ProcessingInstruction { .. } => unreachable!()
+            };
+        }
+
+        test_walk(&dom.document, &mut count);
+
+        assert_eq!(count, 7);
+    }
+
+    #[test]
+    fn test_get_parent_node_name_document() {
+        let html = "<!doctype html><html><head></head><body></body></html>";
+        let dom = html_to_dom(&html);
+
+        assert_eq!(get_parent_node_name(&dom.document), "");
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets() {
+        let html = "<div><P></P></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_js: true,
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><p></p></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_iframe() {
+        let html = "<div><P></P><iframe src=\"\"></iframe></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_js: true,
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><p></p><iframe src=\"\"></iframe></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_img() {
+        let html = "<div><img src=\"http://localhost/assets/mono_lisa.png\" /></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_js: true,
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div>\
+             <img src=\"data:image/png;base64,\
+             iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0\
+             lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=\">\
+             </div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_remove_images() {
+        let html = "<div style=\"background: url(bg.png) no-repeat\"><img src=\"logo.png\">\
+                    <picture><source srcset=\"wide.png\"><img src=\"narrow.png\"></picture>\
+                    <p>Text</p></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_images: true,
+            remove_images: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div style=\"background: none no-repeat\">\
+             <p>Text</p></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_js() {
+        let html = "<div><script src=\"http://localhost/assets/some.js\"></script>\
+                    <script>alert(1)</script></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_js: true,
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><script></script>\
+             <script></script></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_json_script() {
+        let base_url = serve(vec![
+            ("/config.json", response("text/plain", b"{\"debug\": 0}")),
+            ("/app.js", response("text/plain", b"run()")),
+        ]);
+        let html = "<script type=\"application/json\" id=\"config\" src=\"config.json\"></script>\
+                    <script src=\"app.js\"></script>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head>\
+                 <script type=\"application/json\" id=\"config\" src=\"{}\"></script>\
+                 <script src=\"{}\"></script></head><body></body></html>",
+                data_to_dataurl("application/json", b"{\"debug\": 0}"),
+                data_to_dataurl("application/javascript", b"run()")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_source_maps() {
+        let base_url = serve(vec![
+            (
+                "/app.js",
+                response(
+                    "application/javascript",
+                    b"run();\n//# sourceMappingURL=app.js.map",
+                ),
+            ),
+            (
+                "/app.js.map",
+                response("application/json", b"{\"version\":3}"),
+            ),
+        ]);
+        let html = "<script src=\"app.js\"></script>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_maps = Options {
+            source_maps: AssetAction::Embed,
+            ..opts.clone()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><script src=\"{}\"></script></head><body></body></html>",
+                data_to_dataurl("application/javascript", b"run();\n")
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_maps);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        let js = format!(
+            "run();\n//# sourceMappingURL={}",
+            data_to_dataurl("application/json", b"{\"version\":3}")
+        );
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><script src=\"{}\"></script></head><body></body></html>",
+                data_to_dataurl("application/javascript", js.as_bytes())
+            )
+        );
+    }
+
+    struct AsideRemover;
+
+    impl DomVisitor for AsideRemover {
+        fn visit(&mut self, document: &Handle, _url: &str) {
+            for_each_element(document, |node| {
+                node.children.borrow_mut().retain(|child| match child.data {
+                    NodeData::Element { ref name, .. } => name.local.as_ref() != "aside",
+                    _ => true,
+                });
+            });
+        }
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_dom_visitor() {
+        let html = "<main><p>Article</p><aside><img src=\"ad.png\"></aside></main>\
+                    <aside>Sponsored</aside>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let opts = Options::builder()
+            .dom_visitor(AsideRemover)
+            .no_images(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><main><p>Article</p></main></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_default_lang() {
+        let url = "http://localhost/";
+        let opts = Options {
+            default_lang: Some("en".to_string()),
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom("<html lang=\"de\" dir=\"ltr\"><body><p>Hallo</p></body></html>");
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html lang=\"de\" dir=\"ltr\"><head></head><body><p>Hallo</p></body></html>"
+        );
+
+        let dom = html_to_dom("<html dir=\"rtl\"><body><p>Hello</p></body></html>");
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html dir=\"rtl\" lang=\"en\"><head></head><body><p>Hello</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_no_comments() {
+        let html = "<!-- head --><html><head><!--[if IE]><script>alert(1)</script><![endif]-->\
+                    </head><body><div><!-- some note --><p></p></div></body></html>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_images: true,
+            no_comments: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><p></p></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_noscript() {
+        let html = "<div><noscript><img src=\"http://localhost/assets/mono_lisa.png\" />\
+                    </noscript><script>alert(1)</script></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_js: true,
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div>\
+             <img src=\"data:image/png;base64,\
+             iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0\
+             lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=\">\
+             <script></script></div></body></html>"
+        );
+
+        // With scripts kept, fallbacks can go instead
+        let dom = html_to_dom(&html);
+        let opts_strip = Options {
+            strip_noscript: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts_strip);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><script>alert(1)</script></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_parse_srcset() {
+        assert_eq!(
+            parse_srcset("small.png 1x,  large.png 2x"),
+            vec![
+                ("small.png".to_string(), "1x".to_string()),
+                ("large.png".to_string(), "2x".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_srcset(" data:image/png;base64,AAAA 480w,image.png"),
+            vec![
+                ("data:image/png;base64,AAAA".to_string(), "480w".to_string()),
+                ("image.png".to_string(), "".to_string()),
+            ]
+        );
+        assert_eq!(parse_srcset(""), vec![]);
+    }
+
+    #[test]
+    fn test_select_srcset_candidate() {
+        let srcset = "small.jpg 480w, medium.jpg 800w, large.jpg 1600w";
+        let sizes = "(max-width: 600px) 100vw, 50vw";
+        let select = |sizes: &str, viewport_width: u32| {
+            select_srcset_candidate(srcset, sizes, "fallback.jpg", viewport_width)
+        };
+
+        assert_eq!(select(sizes, 1280), Some("medium.jpg".to_string()));
+        assert_eq!(select(sizes, 2560), Some("large.jpg".to_string()));
+        assert_eq!(select(sizes, 400), Some("small.jpg".to_string()));
+        assert_eq!(select(sizes, 4000), Some("large.jpg".to_string()));
+        assert_eq!(select("", 1280), Some("large.jpg".to_string()));
+        assert_eq!(
+            select("(min-width: 1000px) and (max-width: 1400px) 30em, 100vw", 1280),
+            Some("small.jpg".to_string())
+        );
+        assert_eq!(
+            select_srcset_candidate("hd.png 2x", "", "sd.png", 1280),
+            Some("sd.png".to_string())
+        );
+        assert_eq!(select_srcset_candidate("", "", "", 1280), None);
+        assert_eq!(
+            select_srcset_candidate("a.png NaNx, b.png infw, c.png 0x, sd.png 1x", "", "", 1280),
+            Some("sd.png".to_string())
+        );
+    }
+
+    #[test]
+    fn test_embed_srcset() {
+        let srcset = "data:image/png;base64,AAAA 1x, data:image/png;base64,BBBB 2x";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_no_images = Options {
+            no_images: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            embed_srcset("http://localhost", &srcset, &opts, &mut Context::default()),
+            "data:image/png;base64,AAAA 1x, data:image/png;base64,BBBB 2x"
+        );
+        assert_eq!(
+            embed_srcset("http://localhost", &srcset, &opts_no_images, &mut Context::default()),
+            format!("{} 1x, {} 2x", TRANSPARENT_PIXEL, TRANSPARENT_PIXEL)
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_preload_imagesrcset() {
+        let html = "<link rel=\"preload\" as=\"image\" href=\"hero.png\" \
+                    imagesrcset=\"hero-small.png 480w, hero-large.png 1080w\" \
+                    imagesizes=\"50vw\">";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+
+        let opts = Options {
+            no_images: true,
+            silent: true,
+            insecure: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"preload\" as=\"image\" href=\"{pixel}\" \
+                 imagesrcset=\"{pixel} 480w, {pixel} 1080w\" imagesizes=\"50vw\">\
+                 </head><body></body></html>",
+                pixel = TRANSPARENT_PIXEL
+            )
+        );
+    }
+
+    #[test]
+    fn test_discover_assets() {
+        let html = "<link rel=\"stylesheet\" href=\"style.css\">\
+                    <img src=\"/images/logo.png\"><img src=\"data:image/png;base64,AAAA\">\
+                    <script src=\"https://cdn.example.com/app.js\"></script>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/page/";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_no_js = Options {
+            no_js: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts),
+            vec![
+                "http://localhost/page/style.css",
+                "http://localhost/images/logo.png",
+                "https://cdn.example.com/app.js",
+            ]
+        );
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts_no_js),
+            vec![
+                "http://localhost/page/style.css",
+                "http://localhost/images/logo.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_estimate_output_size() {
+        let base_url = serve(vec![
+            (
+                "/style.css",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 300\r\nConnection: close\r\n\r\n".to_vec(),
+            ),
+            (
+                "/logo.png",
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec(),
+            ),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo.png\">";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        // Logo's size is unknown, style.css grows by a third once base64-encoded
+        assert_eq!(
+            estimate_output_size(&format!("{}/", base_url), &html, &opts).unwrap(),
+            html.len() + 400
+        );
+
+        // Nothing listens on port 9, which doesn't spoil the rest of the estimate
+        let html = "<link rel=\"stylesheet\" href=\"style.css\">\
+                    <img src=\"http://127.0.0.1:9/logo.png\">";
+
+        assert_eq!(
+            estimate_output_size(&format!("{}/", base_url), &html, &opts).unwrap(),
+            html.len() + 400
+        );
+
+        // Stylesheets referenced twice get embedded once, ones out of scope not at all
+        let html = "<link rel=\"stylesheet\" href=\"style.css\">\
+                    <link rel=\"stylesheet\" href=\"style.css\">";
+        let opts_scope = Options {
+            scope: Some("/static/".to_string()),
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            estimate_output_size(&format!("{}/", base_url), &html, &opts).unwrap(),
+            html.len() + 400
+        );
+        assert_eq!(
+            estimate_output_size(&format!("{}/", base_url), &html, &opts_scope).unwrap(),
+            html.len()
+        );
+    }
+
+    #[test]
+    fn test_process_html_async() {
+        let base_url = serve(vec![
+            (
+                "/style.css",
+                response("text/css", b"body { background: none; }"),
+            ),
+            ("/logo.png", response("image/png", b"logo")),
+            ("/app.js", response("application/javascript", b"null;")),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo.png\">\
+                    <img src=\"missing.png\"><script src=\"app.js\"></script>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        let expected = process_html(&url, &html, &opts).unwrap();
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        let result = runtime.block_on(process_html_async(&url, &html, &opts)).unwrap();
+
+        assert_eq!(result, expected);
+        assert!(result.contains(&data_to_dataurl("image/png", b"logo")));
+    }
+
+    #[test]
+    fn test_process_html_with_stats() {
+        let base_url = serve(vec![
+            ("/style.css", response("text/css", b"body {}")),
+            ("/logo.png", response("image/png", b"logo")),
+            ("/app.js", response("application/javascript", b"null;")),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo.png\">\
+                    <img src=\"missing.png\"><script src=\"app.js\"></script>";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        let (output, stats) =
+            process_html_with_stats(&format!("{}/", base_url), &html, &opts).unwrap();
+
+        assert_eq!(stats.total_size, output.len());
+        assert_eq!(stats.embedded, 3);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(
+            stats.category_size(AssetCategory::Style),
+            data_to_dataurl("text/css;charset=utf-8", b"body {}").len()
+        );
+        assert_eq!(
+            stats.category_size(AssetCategory::Image),
+            data_to_dataurl("image/png", b"logo").len()
+        );
+        assert_eq!(
+            stats.category_size(AssetCategory::Script),
+            data_to_dataurl("application/javascript", b"null;").len()
+        );
+        assert_eq!(stats.category_size(AssetCategory::Font), 0);
+    }
+
+    #[test]
+    fn test_process_html_manifest() {
+        let base_url = serve(vec![
+            ("/style.css", response("text/css", b"body {}")),
+            (
+                "/logo",
+                b"HTTP/1.1 302 Found\r\nLocation: /logo.png\r\nContent-Length: 0\r\n\
+                  Connection: close\r\n\r\n"
+                    .to_vec(),
+            ),
+            ("/logo.png", response("image/png", b"logo")),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo\">\
+                    <img src=\"missing.png\">";
+        let opts = Options {
+            record_manifest: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        process_html(&format!("{}/", base_url), &html, &opts).unwrap();
+
+        assert_eq!(
+            opts.manifest.entries(),
+            vec![
+                ManifestEntry {
+                    url: format!("{}/style.css", base_url),
+                    resolved_url: format!("{}/style.css", base_url),
+                    mime: "text/css".to_string(),
+                    size: 7,
+                    sha256: format!("{:x}", Sha256::digest(b"body {}")),
+                },
+                ManifestEntry {
+                    url: format!("{}/logo", base_url),
+                    resolved_url: format!("{}/logo.png", base_url),
+                    mime: "image/png".to_string(),
+                    size: 4,
+                    sha256: format!("{:x}", Sha256::digest(b"logo")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_html_strict() {
+        let base_url = serve(vec![("/logo.png", response("image/png", b"logo"))]);
+        let url = format!("{}/", base_url);
+        let html = "<img src=\"logo.png\"><img src=\"missing.png\">";
+        let opts = Options {
+            silent: true,
+            strict: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            process_html(&url, &html, &opts),
+            Err(MonolithError::FetchError {
+                url: format!("{}/missing.png", base_url),
+                reason: "server responded with 404 Not Found".to_string(),
+            })
+        );
+        assert_eq!(
+            process_html(&url, &html, &Options { strict: false, ..opts }),
+            Ok(format!(
+                "<html><head></head><body><img src=\"{}\"><img src=\"{}\"></body></html>",
+                data_to_dataurl("image/png", b"logo"),
+                TRANSPARENT_PIXEL
+            ))
+        );
+    }
+
+    #[test]
+    fn test_process_html_self_extracting() {
+        let url = "http://localhost/";
+        let html = "<h1>Title</h1><p>Some text, some more text, and then some</p>";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_self_extracting = Options {
+            self_extracting: true,
+            ..opts.clone()
+        };
+
+        let shell = process_html(&url, &html, &opts_self_extracting).unwrap();
+
+        // Do what the inline script does, fetch the data URL and gunzip it
+        let start = shell.find("fetch(\"").unwrap() + "fetch(\"".len();
+        let end = start + shell[start..].find('"').unwrap();
+        let (mime, compressed) = dataurl_to_data(&shell[start..end]).unwrap();
+        let mut document = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut document).unwrap();
+
+        assert!(shell.starts_with("<!DOCTYPE html>"));
+        assert_eq!(mime, "application/gzip");
+        assert_eq!(Ok(document), process_html(&url, &html, &opts));
+    }
+
+    #[test]
+    fn test_get_local_fragment() {
+        let url = "https://example.com/docs/page.html?lang=en";
+
+        assert_eq!(
+            get_local_fragment(&url, "https://example.com/docs/page.html?lang=en#intro"),
+            Some("intro".to_string())
+        );
+        assert_eq!(
+            get_local_fragment(&url, "https://example.com/docs/page.html?lang=en"),
+            None
+        );
+        assert_eq!(
+            get_local_fragment(&url, "https://example.com/docs/other.html#intro"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_local_links() {
+        let html = "<a href=\"#top\"></a><a href=\"page.html#section\"></a>\
+                    <a href=\"other.html#section\"></a><a href=\"https://example.com/\"></a>";
+        let url = "http://localhost/docs/page.html";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_local_links = Options {
+            local_links: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><a href=\"#top\"></a>\
+             <a href=\"http://localhost/docs/page.html#section\"></a>\
+             <a href=\"http://localhost/docs/other.html#section\"></a>\
+             <a href=\"https://example.com/\"></a></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_local_links);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><a href=\"#top\"></a><a href=\"#section\"></a>\
+             <a href=\"http://localhost/docs/other.html#section\"></a>\
+             <a href=\"https://example.com/\"></a></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_picture_sources() {
+        let base_url = serve(vec![
+            ("/wide.png", response("image/png", b"wide")),
+            ("/wide-2x.png", response("image/png", b"wide-2x")),
+            ("/narrow.png", response("image/png", b"narrow")),
+        ]);
+        let html = "<picture>\
+                    <source media=\"(min-width: 800px)\" srcset=\"wide.png 1x, wide-2x.png 2x\">\
+                    <source media=\"(max-width: 799px)\" type=\"image/png\" srcset=\"narrow.png\">\
+                    </picture>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><picture>\
+                 <source media=\"(min-width: 800px)\" srcset=\"{} 1x, {} 2x\">\
+                 <source media=\"(max-width: 799px)\" type=\"image/png\" srcset=\"{}\">\
+                 </picture></body></html>",
+                data_to_dataurl("image/png", b"wide"),
+                data_to_dataurl("image/png", b"wide-2x"),
+                data_to_dataurl("image/png", b"narrow"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_picture_no_images() {
+        let html = "<picture>\
+                    <source type=\"image/avif\" srcset=\"hero.avif 1x, hero-2x.avif 2x\">\
+                    <source type=\"image/webp\" srcset=\"hero.webp 800w\" sizes=\"100vw\">\
+                    <img src=\"hero.jpg\"></picture>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let opts = Options {
+            no_images: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><picture>\
+                 <source type=\"image/avif\" srcset=\"{0}\">\
+                 <source type=\"image/webp\" srcset=\"{0}\">\
+                 <img src=\"{0}\"></picture></body></html>",
+                TRANSPARENT_PIXEL
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_flatten_pictures() {
+        let base_url = serve(vec![
+            ("/wide.png", response("image/png", b"wide")),
+            ("/narrow.png", response("image/png", b"narrow")),
+            ("/fallback.png", response("image/png", b"fallback")),
+        ]);
+        let html = "<picture>\
+                    <source media=\"(min-width: 800px)\" srcset=\"wide.png 1x, wide-2x.png 2x\">\
+                    <source media=\"(max-width: 799px)\" srcset=\"narrow.png\">\
+                    <img src=\"fallback.png\" srcset=\"fallback-2x.png 2x\" alt=\"Logo\">\
+                    </picture><picture><source srcset=\"narrow.png\"><img alt=\"\"></picture>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            flatten_pictures: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts),
+            vec![format!("{}/fallback.png", base_url)]
+        );
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img src=\"{}\" alt=\"Logo\">\
+                 <img alt=\"\" src=\"{}\"></body></html>",
+                data_to_dataurl("image/png", b"fallback"),
+                data_to_dataurl("image/png", b"narrow"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_single_image() {
+        let base_url = serve(vec![("/medium.png", response("image/png", b"medium"))]);
+        let html = "<img src=\"fallback.png\" alt=\"Photo\" \
+                    srcset=\"small.png 480w, medium.png 800w, large.png 1600w\" \
+                    sizes=\"(max-width: 600px) 100vw, 50vw\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            single_image: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts),
+            vec![format!("{}/medium.png", base_url)]
+        );
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img alt=\"Photo\" src=\"{}\"></body></html>",
+                data_to_dataurl("image/png", b"medium"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_frames_budget() {
+        let frame = "<html><head></head><body><p>Framed content</p></body></html>";
+        let base_url = serve(vec![(
+            "/frame.html",
+            response("text/html", frame.as_bytes()),
+        )]);
+        let html = "<iframe src=\"frame.html\"></iframe>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            max_frames_size: Some(frame.len()),
+            silent: true,
+            ..Default::default()
+        };
+        let opts_small_budget = Options {
+            max_frames_size: Some(frame.len() - 1),
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><iframe src=\"{}\"></iframe></body></html>",
+                data_to_dataurl("text/html", frame.as_bytes())
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_small_budget);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><iframe></iframe></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_total_budget() {
+        let base_url = serve(vec![
+            ("/first.png", response("image/png", b"first")),
+            ("/second.png", response("image/png", b"second")),
+            ("/style.css", b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()),
+        ]);
+        let html = "<img src=\"first.png\"><img src=\"second.png\">\
+                    <link rel=\"stylesheet\" href=\"style.css\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            max_total_size: Some(10),
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img src=\"{}\"><img src=\"{}/second.png\">\
+                 <link rel=\"stylesheet\" href=\"{}/style.css\"></body></html>",
+                data_to_dataurl("image/png", b"first"),
+                base_url,
+                base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_process_html() {
+        let html = "<div><img src=\"logo.png\"></div>";
+        let opts = Options {
+            no_images: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            process_html("http://localhost", &html, &opts),
+            Ok(format!(
+                "<html><head></head><body><div><img src=\"{}\"></div></body></html>",
+                TRANSPARENT_PIXEL
+            ))
+        );
+        assert_eq!(
+            process_html("http://[::1", &html, &opts),
+            Err(MonolithError::ParseError {
+                url: "http://[::1".to_string(),
+                source: ParseError::InvalidIpv6Address,
+            })
+        );
+    }
+
+    #[test]
+    fn test_process_html_schemeless_url() {
+        let html = "<a href=\"../about.html\">About</a>";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_base_url = Options::builder()
+            .base_url("https://mirror.example.com/site/")
+            .silent(true)
+            .build()
+            .unwrap();
+        let page = |href: &str| {
+            format!(
+                "<html><head></head><body><a href=\"{}\">About</a></body></html>",
+                href
+            )
+        };
+
+        assert_eq!(
+            process_html("localhost/docs/page", &html, &opts),
+            Ok(page("http://localhost/about.html"))
+        );
+        assert_eq!(
+            process_html("localhost:8080/docs/page", &html, &opts),
+            Ok(page("http://localhost:8080/about.html"))
+        );
+        // Whatever the page was, it's the base which assets get resolved against
+        assert_eq!(
+            process_html("http://[::1", &html, &opts_base_url),
+            Ok(page("https://mirror.example.com/about.html"))
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_schemeless_url() {
+        let base_url = serve(vec![("/docs/logo.png", response("image/png", b"logo"))]);
+        let html = "<img src=\"logo.png\"><a href=\"../about.html\">About</a>";
+        let url = format!("{}/docs/page", base_url.trim_start_matches("http://"));
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_https = Options::builder()
+            .default_scheme("https")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img src=\"{}\">\
+                 <a href=\"{}/about.html\">About</a></body></html>",
+                data_to_dataurl("image/png", b"logo"),
+                base_url
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        assert_eq!(
+            discover_assets("localhost/page", &dom.document, &opts_https),
+            vec!["https://localhost/logo.png".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_feeds() {
+        let feed = "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel></channel></rss>";
+        let base_url = serve(vec![(
+            "/feed.xml",
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                feed.len(),
+                feed
+            )
+            .into_bytes(),
+        )]);
+        let html = "<link rel=\"alternate\" type=\"application/rss+xml\" href=\"feed.xml\">";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_feeds = Options {
+            embed_feeds: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"alternate\" type=\"application/rss+xml\" \
+                 href=\"{}/feed.xml\"></head><body></body></html>",
+                base_url
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_feeds);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"alternate\" type=\"application/rss+xml\" \
+                 href=\"{}\"></head><body></body></html>",
+                data_to_dataurl("application/rss+xml", feed.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn test_serialize_to_writer() {
+        let html = "<!doctype html><html><head><title>Title</title></head>\
+                    <body><div><p>Paragraph</p></div></body></html>";
+        let dom = html_to_dom(&html);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+        let mut writer: Vec<u8> = Vec::new();
+        serialize_to_writer(&dom.document, &mut writer).unwrap();
+
+        assert_eq!(writer, buf);
+
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let mut writer: Vec<u8> = Vec::new();
+        process_html_to_writer("http://localhost", &html, &opts, &mut writer).unwrap();
+
+        assert_eq!(writer, buf);
+    }
+
+    #[test]
+    fn test_is_executable_script_type() {
+        assert_eq!(is_executable_script_type(""), true);
+        assert_eq!(is_executable_script_type("text/javascript"), true);
+        assert_eq!(is_executable_script_type("Application/JavaScript"), true);
+        assert_eq!(is_executable_script_type("module"), true);
+        assert_eq!(is_executable_script_type("application/ld+json"), false);
+        assert_eq!(is_executable_script_type("text/template"), false);
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_inert_scripts() {
+        let html = "<div><script src=\"/app.js\" async></script>\
+                    <script type=\"text/javascript\">alert(1)</script>\
+                    <script type=\"application/ld+json\">{\"@type\": \"Thing\"}</script></div>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost";
+        let opts = Options {
+            inert_scripts: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div>\
+             <script src=\"http://localhost/app.js\" async=\"\" type=\"text/plain\"></script>\
+             <script type=\"text/plain\">alert(1)</script>\
+             <script type=\"application/ld+json\">{\"@type\": \"Thing\"}</script>\
+             </div></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_protocol_relative() {
+        let base_url = serve(vec![("/logo.png", response("image/png", b"logo"))]);
+        // Same host and port as the page, scheme left for the page to decide
+        let html = format!(
+            "<img src=\"{}/logo.png\">",
+            base_url.trim_start_matches("http:")
+        );
+        let dom = html_to_dom(&html);
+        let url = format!("{}/page.html", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img src=\"{}\"></body></html>",
+                data_to_dataurl("image/png", b"logo")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_css() {
+        let base_url = serve(vec![
+            (
+                "/style.css",
+                response("text/css", b"body{background:url(bg.png)}"),
+            ),
+            ("/bg.png", response("image/png", b"bg")),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\">\
+                    <style>p{background:image-set(\"bg.png\" 1x)}</style>\
+                    <div style=\"background:url(bg.png)\"></div>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/page.html", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        let bg = data_to_dataurl("image/png", b"bg");
+        let css = format!("body{{background:url(\"{}\")}}", bg);
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{css}\">\
+                 <style>p{{background:image-set(\"{bg}\" 1x)}}</style>\
+                 </head><body><div style=\"background:url(&quot;{bg}&quot;)\"></div>\
+                 </body></html>",
+                css = data_to_dataurl("text/css;charset=utf-8", css.as_bytes()),
+                bg = bg
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_policy() {
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo.png\">\
+                    <script src=\"app.js\"></script>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let opts = Options::builder()
+            .policy(AssetCategory::Image, AssetAction::Keep)
+            .policy(AssetCategory::Style, AssetAction::Keep)
+            .policy(AssetCategory::Script, AssetAction::Drop)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><link rel=\"stylesheet\" href=\"http://localhost/style.css\"></head>\
+             <body><img src=\"http://localhost/logo.png\"><script></script>\
+             </body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_workers() {
+        let base_url = serve(vec![(
+            "/w.js",
+            response("application/javascript", b"onmessage=onwork;"),
+        )]);
+        let html = "<script>var worker = new Worker('w.js');</script>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/index.html", base_url);
+        let opts = Options {
+            embed_workers: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><script>var worker = new Worker('{}');</script></head>\
+                 <body></body></html>",
+                data_to_dataurl("application/javascript", b"onmessage=onwork;")
+            )
+        );
+    }
+
+    fn collapse_dom(handle: &Handle) -> String {
+        let mut result = String::new();
+
+        match handle.data {
+            NodeData::Element { ref name, ref attrs, .. } => {
+                result.push_str(&format!("<{}", name.local));
+                for attr in attrs.borrow().iter() {
+                    result.push_str(&format!(" {}={:?}", attr.name.local, attr.value.to_string()));
+                }
+                result.push('>');
+            }
+            NodeData::Text { ref contents } => {
+                result.push_str(contents.borrow().trim());
+            }
+            NodeData::Comment { ref contents } => {
+                result.push_str(&format!("<!--{}-->", contents));
+            }
+            _ => {}
+        }
+
+        for child in handle.children.borrow().iter() {
+            result.push_str(&collapse_dom(child));
+        }
+
+        result
+    }
+
+    #[test]
+    fn test_process_html_pretty() {
+        let html = "<!DOCTYPE html><html><head><title>Pretty</title>\
+                    <style>p { color: red; }</style></head>\
+                    <body><!-- content --><div class=\"a\"><p>Hello <b>world</b></p><br>\
+                    <pre>  keep\n  this</pre></div></body></html>";
+        let opts = Options {
+            pretty: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let output = process_html("http://localhost", html, &opts).unwrap();
+
+        assert_eq!(
+            output,
+            "<!DOCTYPE html>\n\
+             <html>\n\
+             \x20 <head>\n\
+             \x20   <title>\n\
+             \x20     Pretty\n\
+             \x20   </title>\n\
+             \x20   <style>p { color: red; }</style>\n\
+             \x20 </head>\n\
+             \x20 <body>\n\
+             \x20   <!-- content -->\n\
+             \x20   <div class=\"a\">\n\
+             \x20     <p>\n\
+             \x20       Hello\n\
+             \x20       <b>\n\
+             \x20         world\n\
+             \x20       </b>\n\
+             \x20     </p>\n\
+             \x20     <br>\n\
+             \x20     <pre>  keep\n  this</pre>\n\
+             \x20   </div>\n\
+             \x20 </body>\n\
+             </html>"
+        );
+        assert_eq!(
+            collapse_dom(&html_to_dom(&output).document),
+            collapse_dom(&html_to_dom(html).document)
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_inline_stylesheets() {
+        let base_url = serve(vec![
+            ("/print.css", response("text/css", b"nav{display:none}")),
+            ("/screen.css", response("text/css", b"nav{color:red}")),
+        ]);
+        let html = "<link rel=\"stylesheet\" href=\"print.css\" media=\"print\" title=\"Print\">\
+                    <link rel=\"stylesheet\" href=\"screen.css\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            inline_stylesheets: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><style title=\"Print\">@media print { nav{display:none} }</style>\
+             <style>nav{color:red}</style></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_stylesheet_relative_import() {
+        let base_url = serve(vec![
+            (
+                "/css/main.css",
+                response("text/css", b"@import \"sub/other.css\";main{}"),
+            ),
+            (
+                "/css/sub/other.css",
+                response("text/css", b"@import url(../../theme.css);other{}"),
+            ),
+            ("/theme.css", response("text/css", b"theme{}")),
+        ]);
+        // Resolved against the page rather than the stylesheet, imports would miss
+        let html = "<link rel=\"stylesheet\" href=\"../css/main.css\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/blog/post.html", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_inline = Options {
+            inline_stylesheets: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{}\"></head><body></body></html>",
+                data_to_dataurl("text/css;charset=utf-8", b"theme{}other{}main{}")
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_inline);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><style>theme{}other{}main{}</style></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_failed_fetches() {
+        let base_url = serve(vec![]);
+        let html = "<link rel=\"icon\" href=\"favicon.ico\">\
+                    <link rel=\"stylesheet\" href=\"style.css\">\
+                    <link rel=\"alternate\" type=\"application/rss+xml\" href=\"feed.xml\">\
+                    <script src=\"app.js\"></script>\
+                    <img src=\"logo.png\"><iframe src=\"frame.html\"></iframe>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            embed_feeds: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        // Neither empty src nor empty href must remain, those point back at the page itself
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"icon\"><link rel=\"stylesheet\">\
+                 <link rel=\"alternate\" type=\"application/rss+xml\"><script></script>\
+                 </head><body><img src=\"{pixel}\"><iframe></iframe>\
+                 </body></html>",
+                pixel = TRANSPARENT_PIXEL
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_mask_icon() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><path d=\"M0 0h16v16H0z\"/></svg>";
+        let base_url = serve(vec![(
+            "/pinned.svg",
+            response("text/plain", svg.as_bytes()),
+        )]);
+        let html = "<link rel=\"mask-icon\" href=\"pinned.svg\" color=\"#5bbad5\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"mask-icon\" href=\"{}\" color=\"#5bbad5\">\
+                 </head><body></body></html>",
+                data_to_dataurl("image/svg+xml", svg.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_dataurl_encoding() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+        let base_url = serve(vec![
+            ("/logo.svg", response("image/svg+xml", svg.as_bytes())),
+            ("/photo.png", response("image/png", b"\x89PNG")),
+        ]);
+        let html = "<img src=\"logo.svg\"><img src=\"photo.png\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options::builder()
+            .dataurl_encoding(AssetCategory::Image, DataUrlEncoding::Percent)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body>\
+             <img src=\"data:image/svg+xml,%3Csvg%20xmlns=%22http://www.w3.org/2000/svg%22/%3E\">\
+             <img src=\"data:image/png;base64,iVBORw==\"></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_template() {
+        let base_url = serve(vec![("/avatar.png", response("image/png", b"avatar"))]);
+        let html = "<template id=\"user\"><li><img src=\"avatar.png\"></li></template>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts),
+            vec![format!("{}/avatar.png", base_url)]
+        );
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><template id=\"user\"><li><img src=\"{}\"></li></template>\
+                 </head><body></body></html>",
+                data_to_dataurl("image/png", b"avatar")
+            )
+        );
+    }
+
+    struct StubFetcher;
+
+    impl AssetFetcher for StubFetcher {
+        fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), MonolithError> {
+            match url {
+                "http://localhost/logo.png" => Ok(("image/png".to_string(), b"logo".to_vec())),
+                "http://localhost/style.css" => {
+                    Ok(("text/css".to_string(), b"body { color: red; }".to_vec()))
+                }
+                _ => Err(MonolithError::FetchError {
+                    url: url.to_string(),
+                    reason: "not found".to_string(),
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_fetcher() {
+        let html = "<link rel=\"stylesheet\" href=\"style.css\"><img src=\"logo.png\">\
+                    <img src=\"missing.png\">";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let opts = Options {
+            fetcher: Some(CustomFetcher::new(StubFetcher)),
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{}\"></head>\
+                 <body><img src=\"{}\"><img src=\"{}\"></body></html>",
+                data_to_dataurl("text/css;charset=utf-8", b"body { color: red; }"),
+                data_to_dataurl("image/png", b"logo"),
+                TRANSPARENT_PIXEL
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_media_source() {
+        let base_url = serve(vec![
+            ("/clip-hd.mp4", response("video/mp4", b"clip-hd!")),
+            ("/clip-sd.webm", response("video/webm", b"clip")),
+            ("/clip-sd.mp4", response("video/mp4", b"clipsd")),
+        ]);
+        let html = "<video controls>\
+                    <source src=\"clip-hd.mp4\" type=\"video/mp4\">\
+                    <source src=\"clip-sd.webm\" type=\"video/webm\">\
+                    <source src=\"clip-sd.mp4\" type=\"video/mp4; codecs=avc1\">\
+                    Your browser does not support video</video>";
+        let url = format!("{}/", base_url);
+
+        for (media_source, expected) in vec![
+            (
+                MediaSource::Smallest,
+                format!(
+                    "<source src=\"{}\" type=\"video/webm\">",
+                    data_to_dataurl("video/webm", b"clip")
+                ),
+            ),
+            (
+                MediaSource::Types(vec!["video/ogg".to_string(), "VIDEO/MP4".to_string()]),
+                format!(
+                    "<source src=\"{}\" type=\"video/mp4\">",
+                    data_to_dataurl("video/mp4", b"clip-hd!")
+                ),
+            ),
+        ] {
+            let dom = html_to_dom(&html);
+            let opts = Options {
+                media_source: Some(media_source),
+                silent: true,
+                ..Default::default()
+            };
+
+            walk_and_embed_assets(&url, &dom.document, &opts);
+
+            let mut buf: Vec<u8> = Vec::new();
+            serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+            assert_eq!(
+                buf.iter().map(|&c| c as char).collect::<String>(),
+                format!(
+                    "<html><head></head><body><video controls=\"\">{}\
+                     Your browser does not support video</video></body></html>",
+                    expected
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_media_source_type() {
+        let base_url = serve(vec![(
+            "/theme",
+            response("application/octet-stream", b"OggS"),
+        )]);
+        let html = "<audio controls><source src=\"theme\" type=\"audio/ogg; codecs=vorbis\"></audio>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            media_source: Some(MediaSource::Smallest),
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><audio controls=\"\">\
+                 <source src=\"{}\" type=\"audio/ogg; codecs=vorbis\"></audio></body></html>",
+                data_to_dataurl("audio/ogg", b"OggS")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_keep_remote() {
+        let base_url = serve(vec![("/logo.png", response("image/png", b"logo"))]);
+        let html = "<img src=\"logo.png\"><video src=\"media/intro.mp4\"></video>\
+                    <audio><source src=\"media/theme\" type=\"audio/ogg\"></audio>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options::builder()
+            .keep_remote("video/*")
+            .keep_remote("audio/ogg")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><img src=\"{}\">\
+                 <video src=\"{base_url}/media/intro.mp4\"></video>\
+                 <audio><source src=\"{base_url}/media/theme\" type=\"audio/ogg\"></audio>\
+                 </body></html>",
+                data_to_dataurl("image/png", b"logo"),
+                base_url = base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_modulepreload() {
+        let base_url = serve(vec![
+            (
+                "/js/mod.js",
+                response(
+                    "application/javascript",
+                    b"import { h } from './lib/h.js';h();",
+                ),
+            ),
+            (
+                "/js/lib/h.js",
+                response("application/javascript", b"export function h(){}"),
+            ),
+        ]);
+        let html = "<link rel=\"modulepreload\" href=\"js/mod.js\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        let mod_js = format!(
+            "import {{ h }} from '{}';h();",
+            data_to_dataurl("application/javascript", b"export function h(){}")
+        );
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"modulepreload\" href=\"{}\"></head>\
+                 <body></body></html>",
+                data_to_dataurl("application/javascript", mod_js.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_preload_fetch() {
+        let base_url = serve(vec![(
+            "/api/state.json",
+            response("application/json", b"{\"page\": 1}"),
+        )]);
+        let html = "<link rel=\"preload\" as=\"fetch\" href=\"api/state.json\" crossorigin>\
+                    <link rel=\"preload\" as=\"fetch\" href=\"api/missing.json\" \
+                    crossorigin=\"use-credentials\">";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            discover_assets(&url, &dom.document, &opts),
+            vec![
+                format!("{}/api/state.json", base_url),
+                format!("{}/api/missing.json", base_url),
+            ]
+        );
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head>\
+                 <link rel=\"preload\" as=\"fetch\" href=\"{}\" crossorigin=\"\">\
+                 <link rel=\"preload\" as=\"fetch\" href=\"{}/api/missing.json\" \
+                 crossorigin=\"use-credentials\"></head><body></body></html>",
+                data_to_dataurl("application/json", b"{\"page\": 1}"),
+                base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_preload_media() {
+        let base_url = serve(vec![("/intro.webm", response("video/webm", b"intro"))]);
+        let html = "<link rel=\"preload\" as=\"video\" href=\"intro.webm\">\
+                    <link rel=\"preload\" as=\"audio\" href=\"theme.mp3\">";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_no_media = Options::builder()
+            .policy(AssetCategory::Media, AssetAction::Drop)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head>\
+                 <link rel=\"preload\" as=\"video\" href=\"{}\">\
+                 <link rel=\"preload\" as=\"audio\" href=\"{}/theme.mp3\">\
+                 </head><body></body></html>",
+                data_to_dataurl("video/webm", b"intro"),
+                base_url
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_no_media);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><link rel=\"preload\" as=\"video\">\
+             <link rel=\"preload\" as=\"audio\"></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_no_autoplay() {
+        let html = "<video autoplay muted loop preload=\"auto\"></video>\
+                    <audio autoplay=\"autoplay\" controls></audio>";
+        let url = "http://localhost/";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_no_autoplay = Options {
+            no_autoplay: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body>\
+             <video autoplay=\"\" muted=\"\" loop=\"\" preload=\"auto\"></video>\
+             <audio autoplay=\"autoplay\" controls=\"\"></audio></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_no_autoplay);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body>\
+             <video muted=\"\" loop=\"\" preload=\"none\"></video>\
+             <audio controls=\"\" preload=\"none\"></audio></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_element_handler() {
+        let html = "<my-widget src=\"widget.json\" onclick=\"alert(1)\"></my-widget>\
+                    <img src=\"data:image/png;base64,AAAA\">";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let opts = Options::builder()
+            .element_handler("my-widget", |node, _| {
+                if let NodeData::Element { ref attrs, .. } = node.data {
+                    for attr in attrs.borrow_mut().iter_mut() {
+                        if &attr.name.local == "src" {
+                            attr.value.clear();
+                            attr.value.push_slice("data:application/json,{}");
+                        }
+                    }
+                }
+            })
+            .element_handler("IMG", |node, _| {
+                if let NodeData::Element { ref attrs, .. } = node.data {
+                    attrs.borrow_mut().clear();
+                }
+            })
+            .no_js(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        // Everything that isn't specific to an element still applies
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body>\
+             <my-widget src=\"data:application/json,{}\" onclick=\"\"></my-widget>\
+             <img></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_built_in_handlers() {
+        let html = "<my-anchor href=\"page.html\"></my-anchor><a href=\"page.html\"></a>";
+        let dom = html_to_dom(&html);
+        let url = "http://localhost/";
+        let mut opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        // Built-in handlers can be taken out of the table or put to use for other elements
+        let embed_anchor = opts.element_handlers.remove("a").unwrap();
+        opts.element_handlers.insert("my-anchor".to_string(), embed_anchor);
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body>\
+             <my-anchor href=\"http://localhost/page.html\"></my-anchor>\
+             <a href=\"page.html\"></a></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_canonical() {
+        let html = "<link rel=\"canonical\" href=\"../articles/42\">";
+        let url = "http://localhost/blog/post.html";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_strip = Options {
+            strip_canonical: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><link rel=\"canonical\" href=\"http://localhost/articles/42\">\
+             </head><body></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_strip);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_inactive_stylesheets() {
+        let base_url = serve(vec![("/main.css", response("text/css", b"body {}"))]);
+        let html = "<link rel=\"stylesheet\" href=\"main.css\">\
+                    <link rel=\"alternate stylesheet\" href=\"contrast.css\" title=\"Contrast\">\
+                    <link rel=\"stylesheet\" href=\"print.css\" disabled>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_strip = Options {
+            strip_inactive_stylesheets: true,
+            ..opts.clone()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{1}\">\
+                 <link rel=\"alternate stylesheet\" href=\"{0}/contrast.css\" \
+                 title=\"Contrast\">\
+                 <link rel=\"stylesheet\" href=\"{0}/print.css\" disabled=\"\">\
+                 </head><body></body></html>",
+                base_url,
+                data_to_dataurl("text/css;charset=utf-8", b"body {}")
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_strip);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{}\"></head><body></body></html>",
+                data_to_dataurl("text/css;charset=utf-8", b"body {}")
+            )
+        );
+    }
+
+    #[test]
+    fn test_resolve_refresh() {
+        let url = "http://localhost/splash/";
+
+        assert_eq!(
+            resolve_refresh(&url, "5; url=../home.html"),
+            "5; url=http://localhost/home.html"
+        );
+        assert_eq!(
+            resolve_refresh(&url, "0;URL='banner.png'"),
+            "0; url=http://localhost/splash/banner.png"
+        );
+        assert_eq!(
+            resolve_refresh(&url, "3, next.html"),
+            "3; url=http://localhost/splash/next.html"
+        );
+        assert_eq!(resolve_refresh(&url, "30"), "30");
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_strip_refresh() {
+        let html = "<meta charset=\"utf-8\">\
+                    <meta http-equiv=\"refresh\" content=\"3; url=banner.png\">";
+        let url = "http://localhost/";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_strip_refresh = Options {
+            strip_refresh: true,
+            ..opts.clone()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><meta charset=\"utf-8\">\
+             <meta http-equiv=\"refresh\" content=\"3; url=http://localhost/banner.png\">\
+             </head><body></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_strip_refresh);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><meta charset=\"utf-8\"></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_strip_meta() {
+        let html = "<meta charset=\"utf-8\">\
+                    <meta name=\"viewport\" content=\"width=device-width\">\
+                    <meta name=\"description\" content=\"Archived page\">\
+                    <meta property=\"og:title\" content=\"Page\">";
+        let url = "http://localhost/";
+        let opts = Options {
+            strip_meta: true,
+            silent: true,
+            ..Default::default()
+        };
+        let opts_strip_viewport = Options {
+            strip_viewport: true,
+            ..opts.clone()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><meta charset=\"utf-8\">\
+             <meta name=\"viewport\" content=\"width=device-width\">\
+             </head><body></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_strip_viewport);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><meta charset=\"utf-8\"></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_microdata() {
+        let base_url = serve(vec![("/photo.png", response("image/png", b"photo"))]);
+        let html = "<div itemscope itemtype=\"https://schema.org/Person\">\
+                    <meta itemprop=\"image\" content=\"photo.png\">\
+                    <meta itemprop=\"url\" content=\"/people/1\">\
+                    <meta itemprop=\"name\" content=\"Jane\"></div>";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_microdata = Options {
+            embed_microdata: true,
+            ..opts.clone()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body>\
+                 <div itemscope=\"\" itemtype=\"https://schema.org/Person\">\
+                 <meta itemprop=\"image\" content=\"{0}/photo.png\">\
+                 <meta itemprop=\"url\" content=\"{0}/people/1\">\
+                 <meta itemprop=\"name\" content=\"Jane\"></div></body></html>",
+                base_url
+            )
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_microdata);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body>\
+                 <div itemscope=\"\" itemtype=\"https://schema.org/Person\">\
+                 <meta itemprop=\"image\" content=\"{1}\">\
+                 <meta itemprop=\"url\" content=\"{0}/people/1\">\
+                 <meta itemprop=\"name\" content=\"Jane\"></div></body></html>",
+                base_url,
+                data_to_dataurl("image/png", b"photo")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_svg_image() {
+        let gif = b"GIF89a\x20\x00\x10\x00\x00\x00\x00;";
+        let base_url = serve(vec![("/badge.gif", response("image/gif", gif))]);
+        let html = "<svg><image href=\"badge.gif\"></image>\
+                    <image xlink:href=\"badge.gif\" width=\"64\"></image>\
+                    <image href=\"missing.gif\"></image></svg>";
+        let dom = html_to_dom(&html);
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            size_svg_images: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        walk_and_embed_assets(&url, &dom.document, &opts);
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head></head><body><svg>\
+                 <image href=\"{gif}\" width=\"32\" height=\"16\"></image>\
+                 <image xlink:href=\"{gif}\" width=\"64\" height=\"16\"></image>\
+                 <image href=\"{pixel}\"></image>\
+                 </svg></body></html>",
+                gif = data_to_dataurl("image/gif", gif),
+                pixel = TRANSPARENT_PIXEL
+            )
+        );
+    }
+
+    #[test]
+    fn test_process_html_base_url() {
+        let base_url = serve(vec![("/static/logo.png", response("image/png", b"logo"))]);
+        let html = "<img src=\"static/logo.png\"><a href=\"about.html\">About</a>";
+        let opts = Options::builder()
+            .base_url(&format!("{}/", base_url))
+            .silent(true)
+            .build()
+            .unwrap();
+
+        // Nothing listens on the proxy's port, assets are only reachable through the base
+        assert_eq!(
+            process_html("http://127.0.0.1:9/cache/site/index.html", &html, &opts),
+            Ok(format!(
+                "<html><head></head><body><img src=\"{}\">\
+                 <a href=\"{}/about.html\">About</a></body></html>",
+                data_to_dataurl("image/png", b"logo"),
+                base_url
+            ))
+        );
+    }
+
+    #[test]
+    fn test_process_html_normalize() {
+        let html = "<img src=\"data:image/png;base64,AAAA\" alt=\"logo\" class=\"logo\">\
+                    <a title=\"home\" href=\"/\" id=\"home\">Home</a>";
+        let opts = Options {
+            normalize: true,
+            silent: true,
+            ..Default::default()
+        };
+        let first_run = process_html("http://localhost/", html, &opts).unwrap();
+        let second_run = process_html("http://localhost/", html, &opts).unwrap();
+
+        assert_eq!(first_run.as_bytes(), second_run.as_bytes());
+        assert_eq!(
+            first_run,
+            "<html><head></head><body>\
+             <img alt=\"logo\" class=\"logo\" src=\"data:image/png;base64,AAAA\">\
+             <a href=\"http://localhost/\" id=\"home\" title=\"home\">Home</a>\
+             </body></html>"
+        );
+    }
+
+    #[test]
+    fn test_process_html_capture_metadata() {
+        let html = "<!DOCTYPE html><meta charset=\"utf-8\"><p>Archived</p>";
+        let opts = Options {
+            capture_metadata: true,
+            inject_csp: true,
+            silent: true,
+            ..Default::default()
+        };
+        let result = process_html("http://localhost/news--today.html", &html, &opts).unwrap();
+        let (before, after) = result.split_at(result.find(" at ").unwrap());
+
+        assert_eq!(
+            before,
+            "<!DOCTYPE html><!-- Saved from http://localhost/news-%2Dtoday.html"
+        );
+        assert!(after.ends_with(&format!(
+            "Z using monolith {} --><html><head>\
+             <meta http-equiv=\"Content-Security-Policy\" \
+             content=\"default-src 'unsafe-inline' data:;\">\
+             <meta charset=\"utf-8\"></head><body><p>Archived</p></body></html>",
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn test_process_html_inject_csp() {
+        let html = "<style nonce=\"r4nd0m\">p{color:red}</style>\
+                    <p nonce=\"r4nd0m\" style=\"margin:0\">Offline</p>";
+        let opts = Options {
+            inject_csp: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            process_html("http://localhost/", &html, &opts),
+            Ok("<html><head><meta http-equiv=\"Content-Security-Policy\" \
+                content=\"default-src 'unsafe-inline' data:;\">\
+                <style>p{color:red}</style></head>\
+                <body><p style=\"margin:0\">Offline</p></body></html>"
+                .to_string())
+        );
+
+        // Images kept remote would have been blocked by it
+        let opts_keep = Options::builder()
+            .inject_csp(true)
+            .policy(AssetCategory::Image, AssetAction::Keep)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            process_html("http://localhost/", "<img src=\"logo.png\">", &opts_keep),
+            Ok("<html><head></head><body><img src=\"http://localhost/logo.png\"></body></html>"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn test_process_html_existing_csp() {
+        let html = "<meta http-equiv=\"Content-Security-Policy\" content=\"default-src 'self'; \
+                    img-src https://cdn.example.com; script-src 'nonce-r4nd0m' 'strict-dynamic'; \
+                    style-src 'self'; object-src 'none'; report-uri /csp\">\
+                    <style>p{color:red}</style>";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            process_html("http://localhost/", &html, &opts),
+            Ok("<html><head><meta http-equiv=\"Content-Security-Policy\" \
+                content=\"default-src 'self' data: 'unsafe-inline'; \
+                img-src https://cdn.example.com data:; script-src data: 'unsafe-inline'; \
+                style-src 'self' data: 'unsafe-inline'; object-src 'none'; report-uri /csp\">\
+                <style>p{color:red}</style></head><body></body></html>"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn test_relax_csp() {
+        assert_eq!(
+            relax_csp("script-src 'sha256-abc=' https:; font-src data:;", false),
+            "script-src 'sha256-abc=' https:; font-src data:"
+        );
+        assert_eq!(
+            relax_csp("Style-Src 'unsafe-inline'; upgrade-insecure-requests", true),
+            "style-src 'unsafe-inline' data:; upgrade-insecure-requests"
+        );
+    }
+
+    #[test]
+    fn test_process_html_first_party_host() {
+        let base_url = serve(vec![("/pixel.gif", response("image/gif", b"pixel"))]);
+        let port = Url::parse(&base_url).unwrap().port().unwrap();
+        // Same server under another name, which makes it a different host all the same
+        let html = format!(
+            "<img src=\"/pixel.gif\"><img src=\"http://localhost:{}/pixel.gif\">",
+            port
+        );
+        let opts = Options::builder()
+            .first_party_host("127.0.0.1")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            process_html(&base_url, &html, &opts),
+            Ok(format!(
+                "<html><head></head><body><img src=\"{}\"><img src=\"{}\"></body></html>",
+                data_to_dataurl("image/gif", b"pixel"),
+                TRANSPARENT_PIXEL
+            ))
+        );
+    }
+
+    #[test]
+    fn test_process_html_scope() {
+        let base_url = serve(vec![
+            (
+                "/docs/style.css",
+                response("text/css", b"h1 { background: url(/logo.png) }"),
+            ),
+            ("/docs/diagram.png", response("image/png", b"diagram")),
+        ]);
+        let url = format!("{}/docs/intro.html", base_url);
+        let html = "<link rel=\"stylesheet\" href=\"style.css\">\
+                    <img src=\"diagram.png\"><img src=\"/blog/photo.png\">";
+        let opts = Options::builder().scope("/docs/").silent(true).build().unwrap();
+
+        assert_eq!(
+            process_html(&url, &html, &opts),
+            Ok(format!(
+                "<html><head><link rel=\"stylesheet\" href=\"{}\"></head>\
+                 <body><img src=\"{}\"><img src=\"{}/blog/photo.png\"></body></html>",
+                data_to_dataurl(
+                    "text/css;charset=utf-8",
+                    format!("h1 {{ background: url(\"{}/logo.png\") }}", base_url).as_bytes()
+                ),
+                data_to_dataurl("image/png", b"diagram"),
+                base_url
+            ))
+        );
+    }
+
+    #[test]
+    fn test_process_html_monolithic() {
+        let frame = data_to_dataurl(
+            "text/html",
+            b"<html><head></head><body>\
+              <img src=\"data:image/gif;base64,R0lGODlhAQABAAAAACw=\"></body></html>",
+        );
+        let html = format!(
+            "<html><head>\
+             <link rel=\"stylesheet\" href=\"data:text/css;charset=utf-8,p%7Bcolor:red%7D\">\
+             <link rel=\"icon\" href=\"data:image/x-icon;base64,AAABAA==\">\
+             <script src=\"data:application/javascript,alert(1)\"></script>\
+             </head><body>\
+             <img src=\"data:image/svg+xml,<svg xmlns=&quot;http://www.w3.org/2000/svg&quot;/>\">\
+             <iframe src=\"{}\"></iframe>\
+             </body></html>",
+            frame
+        );
+        let opts = Options {
+            embed_workers: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        // Reprocessing an already monolithic document changes nothing
+        assert_eq!(process_html("http://localhost/", &html, &opts).unwrap(), html);
+    }
+
+    #[test]
+    fn test_process_html_monolithic_stale() {
+        let base_url = serve(vec![(
+            "/style.css",
+            response("text/css", b"p{color:blue}\n"),
+        )]);
+        let html = "<html><head>\
+                    <link rel=\"stylesheet\" href=\"data:text/css,p%7Bcolor:red%7D\" \
+                    data-stale=\"/style.css\">\
+                    <link rel=\"stylesheet\" href=\"data:text/css,b%7Bcolor:red%7D\">\
+                    </head><body></body></html>";
+        let url = format!("{}/", base_url);
+        let opts = Options::builder().silent(true).build().unwrap();
+        let opts_refetch = Options::builder()
+            .refetch_stale(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        // Unless asked to, stale assets are left alone just like the rest
+        assert_eq!(process_html(&url, html, &opts).unwrap(), html);
+        assert_eq!(
+            process_html(&url, html, &opts_refetch).unwrap(),
+            format!(
+                "<html><head>\
+                 <link rel=\"stylesheet\" href=\"{}\">\
+                 <link rel=\"stylesheet\" href=\"data:text/css,b%7Bcolor:red%7D\">\
+                 </head><body></body></html>",
+                data_to_dataurl("text/css;charset=utf-8", b"p{color:blue}\n")
+            )
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_resource_hints() {
+        let base_url = serve(vec![("/next.html", response("text/html", b"next"))]);
+        let html = "<link rel=\"dns-prefetch\" href=\"//cdn.example.com\">\
+                    <link rel=\"preconnect dns-prefetch\" href=\"https://fonts.example.com\">\
+                    <link rel=\"prefetch\" href=\"next.html\">";
+        let url = format!("{}/", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_embed_prefetch = Options {
+            embed_prefetch: true,
+            silent: true,
+            ..Default::default()
+        };
+        let opts_keep_hints = Options {
+            keep_network_hints: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_embed_prefetch);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            format!(
+                "<html><head><link rel=\"prefetch\" href=\"{}\"></head><body></body></html>",
+                data_to_dataurl("text/html", b"next")
+            )
+        );
+
+        // Keeping the hints has nothing to do with whether prefetched pages come along
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_keep_hints);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><link rel=\"dns-prefetch\" href=\"http://cdn.example.com/\">\
+             <link rel=\"preconnect dns-prefetch\" href=\"https://fonts.example.com/\">\
+             </head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_walk_and_embed_assets_preconnect() {
+        let html = "<link rel=\"preconnect\" href=\"https://fonts.example.com\" crossorigin>";
+        let url = "http://localhost/";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_keep_hints = Options {
+            keep_network_hints: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body></body></html>"
+        );
+
+        let dom = html_to_dom(&html);
+        walk_and_embed_assets(&url, &dom.document, &opts_keep_hints);
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head><link rel=\"preconnect\" href=\"https://fonts.example.com/\" \
+             crossorigin=\"\"></head><body></body></html>"
         );
     }
 }
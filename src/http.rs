@@ -1,9 +1,33 @@
+use brotli::Decompressor;
+use encoding_rs::{Encoding, UTF_8};
+use context::Context;
+use digest::matches_integrity;
+use error::MonolithError;
+use flate2::read::{DeflateDecoder, ZlibDecoder};
+use futures::{future, stream, Future, Stream};
+use image::{downscale_image, reencode_image};
+use opts::{ClientIdentity, Options};
 use regex::Regex;
-use reqwest::Client;
-use reqwest::header::{CONTENT_TYPE, USER_AGENT};
+use reqwest::async::Client as AsyncClient;
+use reqwest::{Client, Identity, Response};
+use robots::is_allowed_by_robots;
+use sha2::{Digest, Sha256};
+use reqwest::header::{
+    HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, USER_AGENT,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use throttle::wait_for_host;
 use url::{ParseError, Url};
-use utils::data_to_dataurl;
+use utils::{
+    data_to_dataurl_with, dataurl_to_data, detect_mimetype, guess_mimetype, read_to_dataurl,
+    strip_image_metadata, DataUrlEncoding,
+};
 
 lazy_static! {
     static ref REGEX_URL: Regex = Regex::new(r"^https?://").unwrap();
@@ -21,71 +45,1092 @@ pub fn is_valid_url(path: &str) -> bool {
 }
 
 pub fn resolve_url(from: &str, to: &str) -> Result<String, ParseError> {
+    // Attribute values often come with stray whitespace around them,
+    // while unsafe characters within get percent-encoded by the parser
+    let to = to.trim();
+    if is_data_url(to).unwrap_or(false) {
+        // (anything, data:image/png;base64,...) is left exactly as it is
+        return Ok(to.to_string());
+    }
+
     let result = if is_valid_url(to) {
         // (anything, http://site.com/css/main.css)
-        to.to_string()
+        Url::parse(to)?.to_string()
+    } else {
+        // (https://site.com, //cdn.site.com/css/main.css) inherits the scheme of the former
+        Url::parse(from.trim())?.join(to)?.to_string()
+    };
+
+    Ok(result)
+}
+
+// Certificates go unchecked either for every host or only for the listed ones
+fn is_insecure(url: &str, opts: &Options) -> bool {
+    opts.insecure
+        || Url::parse(url)
+            .ok()
+            .and_then(|parsed_url| parsed_url.host_str().map(|host| host.to_lowercase()))
+            .is_some_and(|host| opts.insecure_hosts.contains(&host))
+}
+
+// Everything a client gets built from, requests made with the same settings can share one
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ClientConfig {
+    identity: Option<ClientIdentity>,
+    insecure: bool,
+    timeout: u64,
+}
+
+fn client_config(url: &str, opts: &Options) -> ClientConfig {
+    ClientConfig {
+        identity: opts.client_identity.clone(),
+        insecure: is_insecure(url, opts),
+        timeout: opts.timeout,
+    }
+}
+
+fn build_client(config: &ClientConfig) -> Result<Client, reqwest::Error> {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .danger_accept_invalid_certs(config.insecure)
+        .gzip(true);
+
+    if let Some(ref client_identity) = config.identity {
+        builder = builder.identity(Identity::from_pkcs12_der(
+            &client_identity.pkcs12,
+            &client_identity.password,
+        )?);
+    }
+
+    builder.build()
+}
+
+fn build_async_client(config: &ClientConfig) -> Result<AsyncClient, reqwest::Error> {
+    let mut builder = AsyncClient::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .danger_accept_invalid_certs(config.insecure)
+        .gzip(true);
+
+    if let Some(ref client_identity) = config.identity {
+        builder = builder.identity(Identity::from_pkcs12_der(
+            &client_identity.pkcs12,
+            &client_identity.password,
+        )?);
+    }
+
+    builder.build()
+}
+
+// Clients keep connections alive between requests and are costly to build, so rather
+// than paying for a TLS handshake per asset, every clone of the options shares them
+#[derive(Clone, Default)]
+pub struct ClientPool(Arc<Mutex<HashMap<ClientConfig, Client>>>);
+
+impl ClientPool {
+    fn get(&self, url: &str, opts: &Options) -> Result<Client, reqwest::Error> {
+        let config = client_config(url, opts);
+        let mut clients = self.0.lock().unwrap();
+
+        if let Some(client) = clients.get(&config) {
+            return Ok(client.clone());
+        }
+
+        let client = build_client(&config)?;
+        clients.insert(config, client.clone());
+
+        Ok(client)
+    }
+}
+
+impl fmt::Debug for ClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientPool")
+    }
+}
+
+// A cache rather than a setting, it makes no difference to what the options are
+impl PartialEq for ClientPool {
+    fn eq(&self, _other: &ClientPool) -> bool {
+        true
+    }
+}
+
+// Points requests to overridden hosts at the configured address, much like /etc/hosts;
+// a certificate presented for an address couldn't be checked against the name it stands for,
+// which is why HTTPS requests to overridden hosts get refused rather than sent off unverified
+fn override_host(url: &str, opts: &Options) -> Result<Option<(String, String)>, MonolithError> {
+    let mut parsed_url = match Url::parse(url) {
+        Ok(parsed_url) => parsed_url,
+        Err(_) => return Ok(None),
+    };
+    let host = match parsed_url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return Ok(None),
+    };
+    let ip = match opts.host_overrides.get(&host) {
+        Some(ip) => *ip,
+        None => return Ok(None),
+    };
+
+    if parsed_url.scheme() != "http" {
+        return Err(MonolithError::FetchError {
+            url: url.to_string(),
+            reason: format!("address of {} can only be overridden over plain HTTP", host),
+        });
+    }
+
+    let host_header = match parsed_url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+
+    if parsed_url.set_ip_host(ip).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some((parsed_url.to_string(), host_header)))
+}
+
+// Host of the URL, provided that only first-party assets are wanted and it isn't one of them
+fn third_party_host(url: &str, opts: &Options) -> Option<String> {
+    let first_party_host = opts.first_party_host.as_ref()?;
+    let host = Url::parse(url).ok()?.host_str()?.to_lowercase();
+
+    if &host == first_party_host {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+// Scopes are either prefixes of whole URLs or, starting with a slash, of their paths
+pub fn is_in_scope(url: &str, opts: &Options) -> bool {
+    let scope = match opts.scope {
+        Some(ref scope) => scope,
+        None => return true,
+    };
+
+    if is_data_url(url).unwrap_or(false) {
+        return true;
+    }
+
+    if scope.starts_with('/') {
+        Url::parse(url).is_ok_and(|parsed_url| parsed_url.path().starts_with(scope.as_str()))
+    } else {
+        url.starts_with(scope.as_str())
+    }
+}
+
+// Query parameters which only ever bust caches, the asset behind them stays the same
+const CACHE_BUSTING_PARAMS: [&str; 9] = [
+    "_", "cachebuster", "cb", "rev", "t", "ts", "v", "ver", "version",
+];
+
+// Other parameters are left alone, those could very well change what comes back
+fn cache_key(url: &str, opts: &Options) -> String {
+    let mut parsed_url = match Url::parse(url) {
+        Ok(parsed_url) => parsed_url,
+        Err(_) => return url.to_string(),
+    };
+
+    if !opts.strip_cache_busters || parsed_url.query().is_none() {
+        return url.to_string();
+    }
+
+    let params: Vec<(String, String)> = parsed_url
+        .query_pairs()
+        .filter(|(name, _)| !CACHE_BUSTING_PARAMS.contains(&name.to_lowercase().as_str()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+
+    if params.is_empty() {
+        parsed_url.set_query(None);
+    } else {
+        parsed_url.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    parsed_url.to_string()
+}
+
+pub fn retrieve_asset(
+    url: &str,
+    as_dataurl: bool,
+    as_mime: &str,
+    opts: &Options,
+) -> Result<String, MonolithError> {
+    retrieve_cached_asset(url, as_dataurl, as_mime, opts, &mut Context::default())
+}
+
+// The page itself, which the scope has no say over, that only limits what gets embedded
+pub fn retrieve_page(url: &str, opts: &Options) -> Result<String, MonolithError> {
+    let page_opts = Options {
+        scope: None,
+        ..opts.clone()
+    };
+
+    retrieve_asset(url, false, "", &page_opts)
+}
+
+// Assets referenced more than once throughout the walk only get retrieved (and encoded) once
+pub fn retrieve_cached_asset(
+    url: &str,
+    as_dataurl: bool,
+    as_mime: &str,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Result<String, MonolithError> {
+    // Once anything has failed the archive is bound to be given up on, no use retrieving more
+    if opts.strict {
+        if let Some(ref err) = ctx.stats.first_failure {
+            return Err(err.clone());
+        }
+    }
+
+    // Data URLs are already as cheap as it gets
+    if is_data_url(url).unwrap_or(false) {
+        return fetch_asset(url, as_dataurl, as_mime, opts, ctx);
+    }
+
+    // Assets outside of the scope are referenced right where they are, which is
+    // up to the caller when it's contents that it's after rather than a data URL
+    if !is_in_scope(url, opts) {
+        return if as_dataurl {
+            Ok(url.to_string())
+        } else {
+            Err(MonolithError::OutOfScope {
+                url: url.to_string(),
+            })
+        };
+    }
+
+    // Assets retrieved before any integrity was expected of them haven't been checked
+    let integrity = ctx.integrity.get(url).map(String::as_str).unwrap_or("");
+    let key = format!("{} {} {} {}", as_dataurl, as_mime, integrity, cache_key(url, opts));
+
+    if let Some(cached) = ctx.asset_cache.get(&key) {
+        return Ok(cached.clone());
+    }
+
+    let result = match fetch_asset(url, as_dataurl, as_mime, opts, ctx) {
+        Ok(result) => result,
+        Err(err) => {
+            ctx.stats.record_failure(&err);
+            return Err(err);
+        }
+    };
+    ctx.asset_cache.insert(key, result.clone());
+
+    Ok(result)
+}
+
+// Assets get listed the way they were served, before being decoded or transformed in any way
+fn record_in_manifest(url: &str, content_type: &str, as_mime: &str, data: &[u8], opts: &Options) {
+    if !opts.record_manifest {
+        return;
+    }
+
+    let mime = if !as_mime.is_empty() {
+        as_mime
+    } else if !content_type.is_empty() {
+        content_type
+    } else {
+        guess_mimetype(url)
+    };
+
+    opts.manifest.record(url, mime.split(';').next().unwrap_or("").trim(), data);
+}
+
+fn fetch_asset(
+    url: &str,
+    as_dataurl: bool,
+    as_mime: &str,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Result<String, MonolithError> {
+    if is_data_url(url).map_err(|err| parse_error(url, err))? {
+        if as_dataurl {
+            Ok(url.to_string())
+        } else {
+            // Already embedded documents and stylesheets get processed once again
+            match dataurl_to_data(url) {
+                Some((_, data)) => {
+                    check_integrity(url, &data, ctx)?;
+                    Ok(String::from_utf8_lossy(&data).to_string())
+                }
+                None => Err(malformed_dataurl(url)),
+            }
+        }
+    } else if let Err(err) = check_allowed(url, opts) {
+        Err(err)
+    } else if is_file_url(url) {
+        retrieve_file(url, as_dataurl, as_mime, opts, ctx)
+    } else if let Some(ref fetcher) = opts.fetcher {
+        let (content_type, data) = fetcher.fetch(url)?;
+        check_integrity(url, &data, ctx)?;
+        record_in_manifest(url, &content_type, as_mime, &data, opts);
+
+        if as_dataurl {
+            Ok(fetched_to_dataurl(&content_type, as_mime, data, opts, ctx))
+        } else {
+            Ok(decode_text(&content_type, &data))
+        }
+    } else if as_dataurl {
+        let mut response = send_request(url, opts)?;
+        // Bodies which have to be checked are needed in full before anything gets made of them
+        let mimetype = if ctx.integrity.contains_key(url) {
+            None
+        } else {
+            streamable_mimetype(response.headers(), as_mime, opts)
+        };
+
+        if let Some(mimetype) = mimetype {
+            check_content_length(url, &response, opts)?;
+
+            let size_hint = response.content_length().unwrap_or(0) as usize;
+            let mut body = HashingReader::new(response.by_ref().take(max_read_size(opts)));
+            let dataurl = read_to_dataurl(&mimetype, &mut body, size_hint).map_err(|err| {
+                MonolithError::FetchError {
+                    url: url.to_string(),
+                    reason: err.to_string(),
+                }
+            })?;
+
+            // Only ever runs out when there was more to the body than the limit allows
+            if let Some(limit) = opts.max_asset_size {
+                if body.inner.limit() == 0 {
+                    return Err(too_large(url, limit + 1, limit));
+                }
+            }
+
+            if opts.record_manifest {
+                let mime = mimetype.split(';').next().unwrap_or("");
+                let sha256 = format!("{:x}", body.hasher.finalize());
+                opts.manifest.record_hash(url, mime, body.size, &sha256);
+            }
+
+            return Ok(dataurl);
+        }
+
+        let (content_type, data) = read_response(url, response, opts)?;
+        check_integrity(url, &data, ctx)?;
+        record_in_manifest(url, &content_type, as_mime, &data, opts);
+
+        Ok(fetched_to_dataurl(&content_type, as_mime, data, opts, ctx))
+    } else {
+        let (content_type, data) = read_response(url, send_request(url, opts)?, opts)?;
+        check_integrity(url, &data, ctx)?;
+        record_in_manifest(url, &content_type, as_mime, &data, opts);
+
+        Ok(decode_text(&content_type, &data))
+    }
+}
+
+// Integrity metadata is made from the body exactly the way it was served, before getting
+// decoded or transformed in any way; it's the very same body which then gets embedded
+fn check_integrity(url: &str, data: &[u8], ctx: &Context) -> Result<(), MonolithError> {
+    match ctx.integrity.get(url) {
+        Some(integrity) if !matches_integrity(integrity, data) => {
+            Err(MonolithError::IntegrityMismatch {
+                url: url.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_allowed(url: &str, opts: &Options) -> Result<(), MonolithError> {
+    if let Some(host) = third_party_host(url, opts) {
+        Err(MonolithError::BlockedHost { host })
+    } else if opts.respect_robots && !is_allowed_by_robots(url, opts) {
+        Err(MonolithError::DisallowedByRobots {
+            url: url.to_string(),
+        })
     } else {
-        Url::parse(from)?.join(to)?.to_string()
+        Ok(())
+    }
+}
+
+fn is_file_url(url: &str) -> bool {
+    url.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("file://"))
+}
+
+fn malformed_dataurl(url: &str) -> MonolithError {
+    MonolithError::FetchError {
+        url: url.to_string(),
+        reason: "malformed data URL".to_string(),
+    }
+}
+
+// Retrieves raw bytes of assets along with their MIME type,
+// the built-in implementation goes over HTTP(S)
+pub trait AssetFetcher: Send + Sync {
+    fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), MonolithError>;
+}
+
+pub struct HttpFetcher {
+    opts: Options,
+}
+
+impl HttpFetcher {
+    pub fn new(opts: &Options) -> HttpFetcher {
+        HttpFetcher { opts: opts.clone() }
+    }
+}
+
+impl AssetFetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), MonolithError> {
+        fetch_over_http(url, &self.opts)
+    }
+}
+
+fn send_request(url: &str, opts: &Options) -> Result<Response, MonolithError> {
+    if let Some(host_delay) = opts.host_delay {
+        wait_for_host(url, Duration::from_millis(host_delay), &opts.clock);
+    }
+
+    let host_override = override_host(url, opts)?;
+    let client = opts.clients.get(url, opts).map_err(|err| fetch_error(url, err))?;
+    let request = match host_override {
+        Some((pinned_url, host)) => client.get(&pinned_url).header(HOST, host),
+        None => client.get(url),
     };
+    let response = request
+        .header(USER_AGENT, opts.user_agent.as_str())
+        .header(ACCEPT_ENCODING, "gzip, deflate, br")
+        .send()
+        .map_err(|err| fetch_error(url, err))?;
+
+    let final_url = response.url().as_str();
+
+    if !opts.silent {
+        if url == final_url {
+            eprintln!("[ {} ]", &url);
+        } else {
+            eprintln!("[ {} -> {} ]", &url, &final_url);
+        }
+    }
+
+    if opts.record_manifest {
+        opts.manifest.record_resolved_url(url, final_url);
+    }
+
+    if !response.status().is_success() {
+        return Err(MonolithError::FetchError {
+            url: url.to_string(),
+            reason: format!("server responded with {}", response.status()),
+        });
+    }
+
+    Ok(response)
+}
+
+// Gzip is taken care of by reqwest, other encodings are left for us to handle
+fn content_encoding(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or("")
+        .trim()
+        .to_lowercase()
+}
+
+fn fetch_over_http(url: &str, opts: &Options) -> Result<(String, Vec<u8>), MonolithError> {
+    read_response(url, send_request(url, opts)?, opts)
+}
+
+fn read_response(
+    url: &str,
+    mut response: Response,
+    opts: &Options,
+) -> Result<(String, Vec<u8>), MonolithError> {
+    let content_encoding = content_encoding(response.headers());
+    let data = read_body(url, &mut response, opts)?;
+    let data = decompress(&content_encoding, data, opts.max_asset_size);
+    let data = within_limit(url, data, opts.max_asset_size)?;
+
+    // Attempt to obtain MIME type by reading the Content-Type header
+    let mimetype = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    Ok((mimetype, data))
+}
+
+// Chunked responses don't say how large they are until they've been read,
+// which is why reading stops one byte past the limit whether they do or not
+fn read_body(url: &str, response: &mut Response, opts: &Options) -> Result<Vec<u8>, MonolithError> {
+    let mut data: Vec<u8> = vec![];
+
+    if opts.max_asset_size.is_none() {
+        response
+            .copy_to(&mut data)
+            .map_err(|err| fetch_error(url, err))?;
+        return Ok(data);
+    }
+
+    check_content_length(url, response, opts)?;
+
+    response
+        .by_ref()
+        .take(max_read_size(opts))
+        .read_to_end(&mut data)
+        .map_err(|err| MonolithError::FetchError {
+            url: url.to_string(),
+            reason: err.to_string(),
+        })?;
+
+    within_limit(url, data, opts.max_asset_size)
+}
+
+fn check_content_length(
+    url: &str,
+    response: &Response,
+    opts: &Options,
+) -> Result<(), MonolithError> {
+    match (response.content_length(), opts.max_asset_size) {
+        (Some(size), Some(limit)) if size as usize > limit => {
+            Err(too_large(url, size as usize, limit))
+        }
+        _ => Ok(()),
+    }
+}
+
+// One byte past the limit is enough to tell that the limit has been exceeded
+fn max_read_size(opts: &Options) -> u64 {
+    opts.max_asset_size.map_or(u64::MAX, |limit| limit as u64 + 1)
+}
+
+fn within_limit(url: &str, data: Vec<u8>, limit: Option<usize>) -> Result<Vec<u8>, MonolithError> {
+    match limit {
+        Some(limit) if data.len() > limit => Err(too_large(url, data.len(), limit)),
+        _ => Ok(data),
+    }
+}
+
+fn too_large(url: &str, size: usize, limit: usize) -> MonolithError {
+    MonolithError::TooLarge {
+        url: url.to_string(),
+        size,
+        limit,
+    }
+}
+
+// Same as fetch_over_http(), only without blocking the thread it gets polled on
+fn fetch_over_http_async(
+    url: &str,
+    host_override: Option<(String, String)>,
+    client: &AsyncClient,
+    opts: &Options,
+) -> impl Future<Item = (String, Vec<u8>), Error = MonolithError> {
+    let request = match host_override {
+        Some((pinned_url, host)) => client.get(&pinned_url).header(HOST, host),
+        None => client.get(url),
+    };
+    let url = url.to_string();
+    let silent = opts.silent;
+    let max_asset_size = opts.max_asset_size;
+    let manifest = if opts.record_manifest {
+        Some(opts.manifest.clone())
+    } else {
+        None
+    };
+
+    request
+        .header(USER_AGENT, opts.user_agent.as_str())
+        .header(ACCEPT_ENCODING, "gzip, deflate, br")
+        .send()
+        .then(move |result| {
+            let response = result.map_err(|err| fetch_error(&url, err))?;
+            let final_url = response.url().as_str();
+
+            if !silent {
+                if url == final_url {
+                    eprintln!("[ {} ]", &url);
+                } else {
+                    eprintln!("[ {} -> {} ]", &url, &final_url);
+                }
+            }
+
+            if let Some(ref manifest) = manifest {
+                manifest.record_resolved_url(&url, final_url);
+            }
+
+            if !response.status().is_success() {
+                return Err(MonolithError::FetchError {
+                    url: url.to_string(),
+                    reason: format!("server responded with {}", response.status()),
+                });
+            }
+
+            Ok((url, response))
+        })
+        .and_then(move |(url, response)| {
+            let content_encoding = content_encoding(response.headers());
+            let mimetype = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|header| header.to_str().ok())
+                .unwrap_or("")
+                .to_string();
+
+            let error_url = url.clone();
+            let chunk_url = url.clone();
+
+            // Chunks are added up as they come in, a body over the limit isn't read any further
+            response
+                .into_body()
+                .map_err(move |err| fetch_error(&error_url, err))
+                .fold(Vec::new(), move |mut body, chunk| {
+                    body.extend_from_slice(&chunk);
+                    within_limit(&chunk_url, body, max_asset_size)
+                })
+                .and_then(move |body| {
+                    let data = decompress(&content_encoding, body, max_asset_size);
+                    let data = within_limit(&url, data, max_asset_size)?;
+
+                    Ok((mimetype, data))
+                })
+        })
+}
+
+// How many assets get retrieved at once when prefetching
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+pub type PrefetchedAssets = HashMap<String, Result<(String, Vec<u8>), MonolithError>>;
+
+// Retrieves the given assets concurrently, keeping whatever came of each one of them;
+// local files and third-party assets are left for the walk to deal with as it sees fit
+pub fn prefetch_assets(
+    urls: Vec<String>,
+    opts: &Options,
+) -> impl Future<Item = PrefetchedAssets, Error = MonolithError> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut clients: HashMap<ClientConfig, AsyncClient> = HashMap::new();
+    let mut fetches = Vec::new();
+
+    for url in urls {
+        if !is_valid_url(&url)
+            || third_party_host(&url, opts).is_some()
+            || !is_in_scope(&url, opts)
+            || !seen.insert(url.clone())
+        {
+            continue;
+        }
+
+        // Refused overrides get reported once the walk comes across them
+        let host_override = match override_host(&url, opts) {
+            Ok(host_override) => host_override,
+            Err(_) => continue,
+        };
+        let config = client_config(&url, opts);
+        let client = match clients.get(&config) {
+            Some(client) => client.clone(),
+            None => match build_async_client(&config) {
+                Ok(client) => {
+                    clients.insert(config, client.clone());
+                    client
+                }
+                Err(err) => return future::Either::A(future::err(fetch_error(&url, err))),
+            },
+        };
+
+        fetches.push(
+            fetch_over_http_async(&url, host_override, &client, opts)
+                .then(move |result| Ok::<_, MonolithError>((url, result))),
+        );
+    }
+
+    future::Either::B(
+        stream::iter_ok(fetches)
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .collect()
+            .map(|results| results.into_iter().collect()),
+    )
+}
+
+// Serves assets retrieved ahead of time, anything else gets retrieved the usual way
+pub struct PrefetchedFetcher {
+    assets: PrefetchedAssets,
+    opts: Options,
+}
+
+impl PrefetchedFetcher {
+    pub fn new(assets: PrefetchedAssets, opts: &Options) -> PrefetchedFetcher {
+        PrefetchedFetcher {
+            assets,
+            opts: opts.clone(),
+        }
+    }
+}
+
+impl AssetFetcher for PrefetchedFetcher {
+    fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), MonolithError> {
+        match self.assets.get(url) {
+            Some(result) => result.clone(),
+            None => match self.opts.fetcher {
+                Some(ref fetcher) => fetcher.fetch(url),
+                None => fetch_over_http(url, &self.opts),
+            },
+        }
+    }
+}
+
+// Local files are only ever read when explicitly allowed,
+// a remote page has no business embedding whatever is on the disk
+fn retrieve_file(
+    url: &str,
+    as_dataurl: bool,
+    as_mime: &str,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Result<String, MonolithError> {
+    let data = read_file(url, opts)?;
+
+    check_integrity(url, &data, ctx)?;
+    record_in_manifest(url, "", as_mime, &data, opts);
+
+    if as_dataurl {
+        Ok(fetched_to_dataurl("", as_mime, data, opts, ctx))
+    } else {
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+}
+
+fn read_file(url: &str, opts: &Options) -> Result<Vec<u8>, MonolithError> {
+    let data = fs::read(file_path(url, opts)?).map_err(|err| file_error(url, err))?;
+
+    if !opts.silent {
+        eprintln!("[ {} ]", &url);
+    }
+
+    Ok(data)
+}
+
+// Bodies embedded as they are get hashed for the manifest while being read
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: usize,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read;
+
+        Ok(read)
+    }
+}
+
+// Whatever else the data URL is going to need to be made of takes the whole body to tell,
+// anything which is embedded as it is gets base64-encoded on the go instead
+fn streamable_mimetype(headers: &HeaderMap, as_mime: &str, opts: &Options) -> Option<String> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or("");
+    let mimetype = if as_mime.is_empty() { content_type } else { as_mime };
+    let content_encoding = content_encoding(headers);
+    let transforms_data = opts.dedup_by_content
+        || opts.strip_image_metadata
+        || opts.max_image_dimension.is_some()
+        || opts.image_quality.is_some()
+        || opts.transform.is_some();
+
+    if mimetype.is_empty()
+        || is_octet_stream(mimetype)
+        || transforms_data
+        || (!content_encoding.is_empty() && content_encoding != "gzip")
+        || content_charset(content_type).is_some_and(|encoding| encoding != UTF_8)
+        || opts.dataurl_encoding_for(mimetype) != DataUrlEncoding::Base64
+    {
+        return None;
+    }
+
+    Some(normalize_mimetype(mimetype))
+}
+
+// Data URLs get the media type the way the rest of them have it,
+// e.g. "Text/CSS; charset=UTF-8" turns into "text/css;charset=utf-8"
+fn normalize_mimetype(mimetype: &str) -> String {
+    let mut params = mimetype.split(';').map(|param| param.trim());
+    let mut normalized = params.next().unwrap_or("").to_lowercase();
+
+    for param in params.filter(|param| !param.is_empty()) {
+        normalized.push(';');
+
+        if param.to_lowercase().starts_with("charset=") {
+            normalized.push_str(&param.to_lowercase().replace('"', ""));
+        } else {
+            normalized.push_str(param);
+        }
+    }
+
+    normalized
+}
+
+fn content_charset(content_type: &str) -> Option<&'static Encoding> {
+    let charset = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            if parts.next()?.trim().eq_ignore_ascii_case("charset") {
+                Some(parts.next()?.trim().trim_matches('"'))
+            } else {
+                None
+            }
+        })
+        .next()?;
+
+    Encoding::for_label(charset.as_bytes())
+}
+
+// Legacy encodings declared by the Content-Type get converted to UTF-8,
+// None for whatever is UTF-8 already or doesn't say what it is
+fn decode_to_utf8(content_type: &str, data: &[u8]) -> Option<String> {
+    let encoding = content_charset(content_type)?;
+
+    if encoding == UTF_8 {
+        return None;
+    }
+
+    Some(encoding.decode(data).0.into_owned())
+}
+
+fn decode_text(content_type: &str, data: &[u8]) -> String {
+    decode_to_utf8(content_type, data).unwrap_or_else(|| UTF_8.decode(data).0.into_owned())
+}
+
+// The MIME type asked for wins over the one the server claims, although
+// it's still the server which gets to say what charset the text is in
+fn fetched_to_dataurl(
+    content_type: &str,
+    as_mime: &str,
+    data: Vec<u8>,
+    opts: &Options,
+    ctx: &mut Context,
+) -> String {
+    // Mirrors and CDNs serve the very same bytes under different URLs,
+    // there's no point in encoding (and transforming) them all over again;
+    // header values can't span lines, which keeps them apart in the key
+    let content_key = if opts.dedup_by_content {
+        Some(format!(
+            "content {:x}\n{}\n{}",
+            Sha256::digest(&data),
+            content_type,
+            as_mime
+        ))
+    } else {
+        None
+    };
+
+    if let Some(cached) = content_key.as_ref().and_then(|key| ctx.asset_cache.get(key)) {
+        return cached.clone();
+    }
+
+    let mimetype = if !as_mime.is_empty() {
+        as_mime
+    } else if is_octet_stream(content_type) && !detect_mimetype(&data).is_empty() {
+        // Servers which don't know what they're serving (fonts more often than not)
+        // say so, in which case the bytes themselves get to tell
+        ""
+    } else {
+        content_type
+    };
+    let dataurl = match decode_to_utf8(content_type, &data) {
+        Some(text) => {
+            let mimetype = format!("{};charset=utf-8", mimetype.split(';').next().unwrap_or(""));
+            to_embedded_dataurl(&mimetype, text.into_bytes(), opts)
+        }
+        None => to_embedded_dataurl(mimetype, data, opts),
+    };
+
+    if let Some(content_key) = content_key {
+        ctx.asset_cache.insert(content_key, dataurl.clone());
+    }
+
+    dataurl
+}
+
+fn is_octet_stream(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+    essence == "application/octet-stream" || essence == "binary/octet-stream"
+}
+
+fn to_embedded_dataurl(mimetype: &str, data: Vec<u8>, opts: &Options) -> String {
+    let data = if opts.strip_image_metadata {
+        strip_image_metadata(data)
+    } else {
+        data
+    };
+    let data = match opts.max_image_dimension {
+        Some(max_image_dimension) => downscale_image(data, max_image_dimension),
+        None => data,
+    };
+    let (mimetype, data) = match opts.image_quality {
+        Some(image_quality) => reencode_image(
+            mimetype,
+            data,
+            image_quality,
+            opts.reencode_lossless,
+            opts.image_encoding,
+        ),
+        None => (mimetype.to_string(), data),
+    };
+
+    match opts.transform {
+        Some(ref transform) => {
+            let (mimetype, data) = transform.apply(&mimetype, data);
+            data_to_dataurl_with(&mimetype, &data, opts.dataurl_encoding_for(&mimetype))
+        }
+        None => data_to_dataurl_with(&mimetype, &data, opts.dataurl_encoding_for(&mimetype)),
+    }
+}
+
+fn parse_error(url: &str, err: ParseError) -> MonolithError {
+    MonolithError::ParseError {
+        url: url.to_string(),
+        source: err,
+    }
+}
+
+fn fetch_error(url: &str, err: reqwest::Error) -> MonolithError {
+    if err.is_timeout() {
+        MonolithError::Timeout {
+            url: url.to_string(),
+        }
+    } else {
+        MonolithError::FetchError {
+            url: url.to_string(),
+            reason: err.to_string(),
+        }
+    }
+}
+
+// A small body may well inflate into a huge one, which is why decompression
+// stops one byte past the limit, same as reading does
+fn decompress(content_encoding: &str, data: Vec<u8>, limit: Option<usize>) -> Vec<u8> {
+    let max_size = limit.map_or(u64::MAX, |limit| limit as u64 + 1);
+    let mut decompressed: Vec<u8> = vec![];
+    let result = match content_encoding {
+        // Supposed to be zlib-wrapped, yet some servers send raw deflate streams
+        "deflate" => ZlibDecoder::new(&data[..])
+            .take(max_size)
+            .read_to_end(&mut decompressed)
+            .or_else(|_| {
+                decompressed.clear();
+                DeflateDecoder::new(&data[..])
+                    .take(max_size)
+                    .read_to_end(&mut decompressed)
+            }),
+        "br" => Decompressor::new(&data[..], 4096)
+            .take(max_size)
+            .read_to_end(&mut decompressed),
+        _ => return data,
+    };
+
+    // Keep the body intact if it turns out not to be compressed after all
+    match result {
+        Ok(_) => decompressed,
+        Err(_) => data,
+    }
+}
+
+fn file_path(url: &str, opts: &Options) -> Result<PathBuf, MonolithError> {
+    if !opts.allow_file_urls {
+        return Err(file_error(url, "local files are not allowed"));
+    }
+
+    Url::parse(url)
+        .map_err(|err| parse_error(url, err))?
+        .to_file_path()
+        .map_err(|_| file_error(url, "not a local path"))
+}
+
+fn file_error<E: ToString>(url: &str, reason: E) -> MonolithError {
+    MonolithError::FetchError {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+pub fn retrieve_asset_size(url: &str, opts: &Options) -> Result<Option<usize>, MonolithError> {
+    if is_data_url(url).map_err(|err| parse_error(url, err))? {
+        return Ok(Some(url.len()));
+    }
+
+    // Assets which wouldn't be retrieved don't get asked about either
+    if !is_in_scope(url, opts) {
+        return Err(MonolithError::OutOfScope {
+            url: url.to_string(),
+        });
+    }
 
-    Ok(result)
-}
+    check_allowed(url, opts)?;
 
-pub fn retrieve_asset(
-    url: &str,
-    as_dataurl: bool,
-    as_mime: &str,
-    opt_user_agent: &str,
-    opt_silent: bool,
-    opt_insecure: bool,
-) -> Result<String, reqwest::Error> {
-    if is_data_url(&url).unwrap() {
-        Ok(url.to_string())
-    } else {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .danger_accept_invalid_certs(opt_insecure)
-            .build()?;
-        let mut response = client
-            .get(url)
-            .header(USER_AGENT, opt_user_agent)
-            .send()?;
-        let final_url = response.url().as_str();
-
-        if !opt_silent {
-            if url == final_url {
-                eprintln!("[ {} ]", &url);
-            } else {
-                eprintln!("[ {} -> {} ]", &url, &final_url);
-            }
-        }
+    // There's no asking local files for their size, it's right there
+    if is_file_url(url) {
+        let metadata = fs::metadata(file_path(url, opts)?).map_err(|err| file_error(url, err))?;
+        return Ok(Some(metadata.len() as usize));
+    }
 
-        if as_dataurl {
-            // Convert response into a byte array
-            let mut data: Vec<u8> = vec![];
-            response.copy_to(&mut data)?;
-
-            // Attempt to obtain MIME type by reading the Content-Type header
-            let mimetype = if as_mime == "" {
-                response
-                    .headers()
-                    .get(CONTENT_TYPE)
-                    .and_then(|header| header.to_str().ok())
-                    .unwrap_or(&as_mime)
-            } else {
-                as_mime
-            };
+    if let Some(host_delay) = opts.host_delay {
+        wait_for_host(url, Duration::from_millis(host_delay), &opts.clock);
+    }
 
-            Ok(data_to_dataurl(&mimetype, &data))
-        } else {
-            Ok(response.text().unwrap())
-        }
+    let host_override = override_host(url, opts)?;
+    let client = opts.clients.get(url, opts).map_err(|err| fetch_error(url, err))?;
+    let request = match host_override {
+        Some((pinned_url, host)) => client.head(&pinned_url).header(HOST, host),
+        None => client.head(url),
+    };
+    let response = request
+        .header(USER_AGENT, opts.user_agent.as_str())
+        .send()
+        .map_err(|err| fetch_error(url, err))?;
+
+    if !response.status().is_success() {
+        return Ok(None);
     }
+
+    Ok(response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use brotli::CompressorWriter;
+    use opts::{AssetTransform, ImageEncoding};
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use imagelib::{DynamicImage, ImageFormat, RgbImage};
+    use std::env;
+    use std::io::{Cursor, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use test_server::{client_identity, client_identity_pem, response, serve, serve_tls};
+    use tokio::runtime::Runtime;
+    use utils::data_to_dataurl;
 
     #[test]
     fn test_is_valid_url() {
@@ -168,6 +1213,92 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_resolve_url_protocol_relative() -> Result<(), ParseError> {
+        let resolved_url = resolve_url(
+            "http://www.kernel.org/category/signatures.html",
+            "//cdn.kernel.org/theme/images/logos/tux.png",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "http://cdn.kernel.org/theme/images/logos/tux.png"
+        );
+
+        let resolved_url = resolve_url(
+            "https://www.kernel.org/category/signatures.html",
+            "//cdn.kernel.org:8443/theme/images/logos/tux.png?v=2",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "https://cdn.kernel.org:8443/theme/images/logos/tux.png?v=2"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_url_query_and_fragment() -> Result<(), ParseError> {
+        let resolved_url = resolve_url("http://h/p/", "x?a=b#c")?;
+        assert_eq!(resolved_url.as_str(), "http://h/p/x?a=b#c");
+
+        // Fragment-only references stay on the very same document, query included
+        let resolved_url = resolve_url("http://h/p/index.html?page=2", "#comments")?;
+        assert_eq!(resolved_url.as_str(), "http://h/p/index.html?page=2#comments");
+
+        // Query-only references replace the query and drop the fragment of the base
+        let resolved_url = resolve_url("http://h/p/index.html?page=2#top", "?page=3")?;
+        assert_eq!(resolved_url.as_str(), "http://h/p/index.html?page=3");
+
+        let resolved_url = resolve_url("http://h/p/", "x?a=1&a=2&b=#")?;
+        assert_eq!(resolved_url.as_str(), "http://h/p/x?a=1&a=2&b=#");
+
+        let resolved_url = resolve_url("http://h/p/", "http://h/x?a=b#c")?;
+        assert_eq!(resolved_url.as_str(), "http://h/x?a=b#c");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_url_whitespace_and_encoding() -> Result<(), ParseError> {
+        let resolved_url = resolve_url(
+            "https://www.kernel.org/category/",
+            " \n  images/my logo.png\t\n",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "https://www.kernel.org/category/images/my%20logo.png"
+        );
+
+        let resolved_url = resolve_url(
+            "https://www.kernel.org/category/",
+            "images/my%20logo.png",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "https://www.kernel.org/category/images/my%20logo.png"
+        );
+
+        let resolved_url = resolve_url(
+            "https://www.kernel.org/category/",
+            "https://www.kernel.org/images/<tux>.png ",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "https://www.kernel.org/images/%3Ctux%3E.png"
+        );
+
+        let resolved_url = resolve_url(
+            "https://www.kernel.org/category/",
+            "search.html?q=linux kernel",
+        )?;
+        assert_eq!(
+            resolved_url.as_str(),
+            "https://www.kernel.org/category/search.html?q=linux%20kernel"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_is_data_url() {
         assert!(
@@ -177,4 +1308,739 @@ mod tests {
         assert!(!is_data_url("https://kernel.org").unwrap_or(false));
         assert!(!is_data_url("//kernel.org").unwrap_or(false));
     }
+
+    #[test]
+    fn test_retrieve_asset_size() {
+        let base_url = serve(vec![
+            (
+                "/known.png",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 300\r\nConnection: close\r\n\r\n".to_vec(),
+            ),
+            (
+                "/unknown.png",
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec(),
+            ),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset_size(&format!("{}/known.png", base_url), &opts).unwrap(),
+            Some(300)
+        );
+        assert_eq!(
+            retrieve_asset_size(&format!("{}/unknown.png", base_url), &opts).unwrap(),
+            None
+        );
+        assert_eq!(
+            retrieve_asset_size(&format!("{}/missing.png", base_url), &opts).unwrap(),
+            None
+        );
+
+        // Assets which wouldn't be retrieved aren't asked about
+        let opts_blocked = Options {
+            first_party_host: Some("example.com".to_string()),
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset_size(&format!("{}/known.png", base_url), &opts_blocked),
+            Err(MonolithError::BlockedHost {
+                host: "127.0.0.1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_compressed() {
+        let css = "body { background-color: #000; color: #fff; }";
+        let encoded_response = |encoding: &str, body: Vec<u8>| {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Encoding: {}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                encoding,
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            response
+        };
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(css.as_bytes()).unwrap();
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(css.as_bytes()).unwrap();
+        let mut br: Vec<u8> = Vec::new();
+        {
+            let mut brotli = CompressorWriter::new(&mut br, 4096, 11, 22);
+            brotli.write_all(css.as_bytes()).unwrap();
+        }
+
+        let base_url = serve(vec![
+            ("/gzip.css", encoded_response("gzip", gzip.finish().unwrap())),
+            ("/deflate.css", encoded_response("deflate", deflate.finish().unwrap())),
+            ("/br.css", encoded_response("br", br)),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        for path in &["/gzip.css", "/deflate.css", "/br.css"] {
+            let url = format!("{}{}", base_url, path);
+
+            assert_eq!(
+                retrieve_asset(&url, true, "", &opts).unwrap(),
+                data_to_dataurl("text/css", css.as_bytes())
+            );
+            assert_eq!(retrieve_asset(&url, false, "", &opts).unwrap(), css);
+        }
+    }
+
+    #[test]
+    fn test_retrieve_asset_compressed_too_large() {
+        let css = "body { background-color: #000; color: #fff; }\n".repeat(100);
+        let mut br: Vec<u8> = Vec::new();
+        {
+            let mut brotli = CompressorWriter::new(&mut br, 4096, 11, 22);
+            brotli.write_all(css.as_bytes()).unwrap();
+        }
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Encoding: br\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            br.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&br);
+
+        let base_url = serve(vec![("/br.css", response)]);
+        let url = format!("{}/br.css", base_url);
+        let opts = Options {
+            max_asset_size: Some(1000),
+            silent: true,
+            ..Default::default()
+        };
+
+        // Well under the limit as served, way over it once decompressed
+        assert!(br.len() < 1000);
+        assert_eq!(
+            retrieve_asset(&url, false, "", &opts),
+            Err(MonolithError::TooLarge {
+                url: url.clone(),
+                size: 1001,
+                limit: 1000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_prefetch_assets_too_large() {
+        let chunked = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\
+                        Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                        5\r\nchunk\r\n6\r\ned png\r\n0\r\n\r\n";
+        let base_url = serve(vec![("/chunked.png", chunked.to_vec())]);
+        let url = format!("{}/chunked.png", base_url);
+        let mut runtime = Runtime::new().unwrap();
+
+        for &(max_asset_size, ref expected) in &[
+            (None, Ok(("image/png".to_string(), b"chunked png".to_vec()))),
+            (
+                Some(10),
+                Err(MonolithError::TooLarge {
+                    url: url.clone(),
+                    size: 11,
+                    limit: 10,
+                }),
+            ),
+        ] {
+            let opts = Options {
+                max_asset_size,
+                silent: true,
+                ..Default::default()
+            };
+            let assets = runtime.block_on(prefetch_assets(vec![url.clone()], &opts)).unwrap();
+
+            assert_eq!(assets.get(&url), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_retrieve_asset_streaming() {
+        let data: Vec<u8> = (0..1_000_003).map(|i| (i * 7 % 256) as u8).collect();
+        let base_url = serve(vec![("/large.png", response("image/png", &data))]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&format!("{}/large.png", base_url), true, "", &opts).unwrap(),
+            data_to_dataurl("image/png", &data)
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_streaming_mimetype() {
+        let base_url = serve(vec![(
+            "/style.css",
+            response("Text/CSS; charset=\"UTF-8\"", b"body {}"),
+        )]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&format!("{}/style.css", base_url), true, "", &opts).unwrap(),
+            data_to_dataurl("text/css;charset=utf-8", b"body {}")
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_chunked() {
+        let chunked = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\
+                        Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                        5\r\nchunk\r\n6\r\ned png\r\n0\r\n\r\n";
+        let base_url = serve(vec![
+            ("/chunked.png", chunked.to_vec()),
+            ("/sized.png", response("image/png", b"chunked png")),
+        ]);
+        let url = format!("{}/chunked.png", base_url);
+
+        for max_asset_size in vec![None, Some(11)] {
+            let opts = Options {
+                max_asset_size,
+                silent: true,
+                ..Default::default()
+            };
+
+            assert_eq!(
+                retrieve_asset(&url, true, "", &opts).unwrap(),
+                data_to_dataurl("image/png", b"chunked png")
+            );
+            assert_eq!(retrieve_asset(&url, false, "", &opts).unwrap(), "chunked png");
+        }
+
+        // Without a Content-Length the limit is only found to be exceeded once read past
+        let opts = Options {
+            max_asset_size: Some(10),
+            silent: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            retrieve_asset(&url, true, "", &opts),
+            Err(MonolithError::TooLarge {
+                url: url.clone(),
+                size: 11,
+                limit: 10,
+            })
+        );
+        assert_eq!(
+            retrieve_asset(&format!("{}/sized.png", base_url), false, "", &opts),
+            Err(MonolithError::TooLarge {
+                url: format!("{}/sized.png", base_url),
+                size: 11,
+                limit: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_errors() {
+        let base_url = serve(vec![]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset("kernel.org", false, "", &opts),
+            Err(MonolithError::ParseError {
+                url: "kernel.org".to_string(),
+                source: ParseError::RelativeUrlWithoutBase,
+            })
+        );
+        assert_eq!(
+            retrieve_asset(&format!("{}/missing.css", base_url), true, "text/css", &opts),
+            Err(MonolithError::FetchError {
+                url: format!("{}/missing.css", base_url),
+                reason: "server responded with 404 Not Found".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_retrieve_page_scope() {
+        let base_url = serve(vec![("/index.html", response("text/html", b"<p>Hi</p>"))]);
+        let url = format!("{}/index.html", base_url);
+        let opts = Options::builder()
+            .scope("/static/")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            retrieve_asset(&url, false, "", &opts),
+            Err(MonolithError::OutOfScope { url: url.clone() })
+        );
+        assert_eq!(retrieve_page(&url, &opts), Ok("<p>Hi</p>".to_string()));
+    }
+
+    #[test]
+    fn test_retrieve_asset_timeout() {
+        // Accept connections but never respond
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slow.css", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+        let opts = Options {
+            silent: true,
+            timeout: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&url, true, "text/css", &opts),
+            Err(MonolithError::Timeout { url: url.clone() })
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_transform() {
+        let base_url = serve(vec![("/photo.jpg", response("image/jpeg", b"0123456789"))]);
+        let opts = Options {
+            silent: true,
+            transform: Some(AssetTransform::new(|mime, data| {
+                assert_eq!(mime, "image/jpeg");
+                // Pretend to shrink the image by half and re-encode it as WebP
+                ("image/webp".to_string(), data[..data.len() / 2].to_vec())
+            })),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&format!("{}/photo.jpg", base_url), true, "", &opts).unwrap(),
+            data_to_dataurl("image/webp", b"01234")
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_strip_image_metadata() {
+        let jpeg = b"\xFF\xD8\xFF\xE1\x00\x0CExif\x00\x00GPS!\
+                     \xFF\xDA\x00\x02\x12\x34\xFF\xD9";
+        let base_url = serve(vec![("/photo.jpg", response("image/jpeg", jpeg))]);
+        let opts = Options {
+            silent: true,
+            strip_image_metadata: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&format!("{}/photo.jpg", base_url), true, "", &opts).unwrap(),
+            data_to_dataurl("image/jpeg", b"\xFF\xD8\xFF\xDA\x00\x02\x12\x34\xFF\xD9")
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_respect_robots() {
+        let base_url = serve(vec![
+            (
+                "/robots.txt",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 31\r\nConnection: close\r\n\r\n\
+                  User-agent: *\nDisallow: /fonts/"
+                    .to_vec(),
+            ),
+            (
+                "/fonts/font.woff",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfont".to_vec(),
+            ),
+        ]);
+        let url = format!("{}/fonts/font.woff", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_robots = Options {
+            respect_robots: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(retrieve_asset(&url, false, "", &opts).unwrap(), "font");
+        assert_eq!(
+            retrieve_asset(&url, false, "", &opts_robots),
+            Err(MonolithError::DisallowedByRobots { url: url.clone() })
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_host_override() {
+        let base_url = serve(vec![(
+            "/pinned.txt",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\npinned".to_vec(),
+        )]);
+        let port = Url::parse(&base_url).unwrap().port().unwrap();
+        let opts = Options::builder()
+            .host_override("www.example.com", "127.0.0.1".parse().unwrap())
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            override_host(&format!("http://WWW.example.com:{}/pinned.txt", port), &opts),
+            Ok(Some((
+                format!("http://127.0.0.1:{}/pinned.txt", port),
+                format!("www.example.com:{}", port)
+            )))
+        );
+        assert_eq!(override_host("http://example.com/", &opts), Ok(None));
+        assert!(override_host("https://www.example.com/", &opts).is_err());
+        assert_eq!(
+            retrieve_asset(
+                &format!("http://www.example.com:{}/pinned.txt", port),
+                false,
+                "",
+                &opts
+            )
+            .unwrap(),
+            "pinned"
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_client_identity() {
+        let base_url = serve_tls(
+            vec![(
+                "/intranet.css",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\nbody {}"
+                    .to_vec(),
+            )],
+            true,
+        );
+        let url = format!("{}/intranet.css", base_url);
+        let opts = Options::builder()
+            .insecure(true)
+            .silent(true)
+            .build()
+            .unwrap();
+        let opts_identity = Options::builder()
+            .client_identity(&client_identity(), "")
+            .insecure(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let (cert, key) = client_identity_pem();
+        let opts_pem = Options::builder()
+            .client_identity_pem(&cert, &key)
+            .insecure(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert!(retrieve_asset(&url, false, "", &opts).is_err());
+        assert_eq!(retrieve_asset(&url, false, "", &opts_identity).unwrap(), "body {}");
+        assert_eq!(retrieve_asset(&url, false, "", &opts_pem).unwrap(), "body {}");
+        assert!(Options::builder()
+            .client_identity_pem(&key, &cert)
+            .build()
+            .is_err());
+        assert!(Options::builder()
+            .client_identity_pem_files("/nonexistent/client.crt", "/nonexistent/client.key")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_retrieve_asset_insecure_hosts() {
+        let base_url = serve_tls(
+            vec![(
+                "/self-signed.css",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\nbody {}"
+                    .to_vec(),
+            )],
+            false,
+        );
+        let url = format!("{}/self-signed.css", base_url);
+        let opts = Options::builder()
+            .insecure_host("intranet.local")
+            .silent(true)
+            .build()
+            .unwrap();
+        let opts_listed = Options::builder()
+            .insecure_host("127.0.0.1")
+            .silent(true)
+            .build()
+            .unwrap();
+
+        assert!(!is_insecure("https://127.0.0.1/", &opts));
+        assert!(is_insecure("https://INTRANET.local/", &opts));
+        assert!(retrieve_asset(&url, false, "", &opts).is_err());
+        assert_eq!(retrieve_asset(&url, false, "", &opts_listed).unwrap(), "body {}");
+    }
+
+    #[test]
+    fn test_retrieve_asset_strip_cache_busters() {
+        let base_url = serve(vec![
+            ("/logo.png?v=3", response("image/png", b"logo")),
+            ("/avatar.png?id=1&v=3", response("image/png", b"avatar")),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_stripped = Options {
+            strip_cache_busters: true,
+            silent: true,
+            ..Default::default()
+        };
+        let mut ctx = Context::default();
+        let mut retrieve = |path: &str, opts: &Options| {
+            retrieve_cached_asset(&format!("{}{}", base_url, path), true, "", opts, &mut ctx)
+        };
+
+        // Only the first of each is actually served, the rest has to come from the cache
+        for opts in [&opts, &opts_stripped].iter() {
+            assert_eq!(
+                retrieve("/logo.png?v=3", opts).unwrap(),
+                data_to_dataurl("image/png", b"logo")
+            );
+            assert!(retrieve("/avatar.png?id=1&v=3", opts).is_ok());
+        }
+
+        assert!(retrieve("/logo.png?v=4", &opts).is_err());
+        assert_eq!(
+            retrieve("/logo.png?v=4", &opts_stripped).unwrap(),
+            data_to_dataurl("image/png", b"logo")
+        );
+        assert_eq!(
+            retrieve("/avatar.png?v=4&id=1", &opts_stripped).unwrap(),
+            data_to_dataurl("image/png", b"avatar")
+        );
+        assert!(retrieve("/avatar.png?id=2&v=3", &opts_stripped).is_err());
+    }
+
+    #[test]
+    fn test_retrieve_asset_dedup_by_content() {
+        let logo = response("image/png", b"logo");
+        let base_url = serve(vec![
+            ("/logo.png", logo.clone()),
+            ("/cdn/logo.png", logo.clone()),
+            ("/mirror/logo.png", logo),
+        ]);
+        let encoded = Arc::new(Mutex::new(0));
+        let encoded_counter = encoded.clone();
+        let opts = Options::builder()
+            .dedup_by_content(true)
+            .transform(move |mime, data| {
+                *encoded_counter.lock().unwrap() += 1;
+                (mime.to_string(), data)
+            })
+            .silent(true)
+            .build()
+            .unwrap();
+        let mut ctx = Context::default();
+        let mut retrieve = |path: &str, as_mime: &str| {
+            retrieve_cached_asset(&format!("{}{}", base_url, path), true, as_mime, &opts, &mut ctx)
+        };
+
+        for path in ["/logo.png", "/cdn/logo.png", "/mirror/logo.png"].iter() {
+            assert_eq!(retrieve(path, "").unwrap(), data_to_dataurl("image/png", b"logo"));
+        }
+
+        // Declared differently, the same bytes make for a different data URL
+        assert_eq!(
+            retrieve("/cdn/logo.png", "image/x-icon").unwrap(),
+            data_to_dataurl("image/x-icon", b"logo")
+        );
+        assert_eq!(*encoded.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_retrieve_asset_shared_client() {
+        let base_url = serve(vec![
+            (
+                "/style.css",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\nbody {}"
+                    .to_vec(),
+            ),
+            (
+                "/script.js",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nnull;"
+                    .to_vec(),
+            ),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        retrieve_asset(&format!("{}/style.css", base_url), false, "", &opts).unwrap();
+        retrieve_asset(&format!("{}/script.js", base_url), true, "", &opts).unwrap();
+        retrieve_asset_size(&format!("{}/style.css", base_url), &opts).unwrap();
+
+        assert_eq!(opts.clients.0.lock().unwrap().len(), 1);
+
+        // Clones share the pool, only differently configured clients get added to it
+        let insecure_opts = Options {
+            insecure: true,
+            ..opts.clone()
+        };
+
+        retrieve_asset(&format!("{}/style.css", base_url), false, "", &insecure_opts).unwrap();
+        retrieve_asset(&format!("{}/script.js", base_url), false, "", &opts.clone()).unwrap();
+
+        assert_eq!(opts.clients.0.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_retrieve_asset_charset() {
+        let base_url = serve(vec![(
+            "/latin1.css",
+            response(
+                "text/css; charset=ISO-8859-1",
+                b"p::before { content: \"\xA7\"; }",
+            ),
+        )]);
+        let url = format!("{}/latin1.css", base_url);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&url, true, "text/css", &opts).unwrap(),
+            data_to_dataurl("text/css;charset=utf-8", "p::before { content: \"§\"; }".as_bytes())
+        );
+        assert_eq!(
+            retrieve_asset(&url, false, "text/css", &opts).unwrap(),
+            "p::before { content: \"§\"; }"
+        );
+    }
+
+    #[test]
+    fn test_retrieve_asset_max_image_dimension() {
+        let mut png: Vec<u8> = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 150, [120, 120, 120].into()))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let base_url = serve(vec![("/banner.png", response("image/png", &png))]);
+        let opts = Options::builder()
+            .max_image_dimension(100)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let dataurl = retrieve_asset(&format!("{}/banner.png", base_url), true, "", &opts).unwrap();
+        let (mimetype, data) = dataurl_to_data(&dataurl).unwrap();
+        let downscaled = imagelib::load_from_memory_with_format(&data, ImageFormat::Png)
+            .unwrap()
+            .to_rgb8();
+
+        assert_eq!(mimetype, "image/png");
+        assert_eq!(downscaled, RgbImage::from_pixel(100, 50, [120, 120, 120].into()));
+    }
+
+    #[test]
+    fn test_retrieve_asset_image_quality() {
+        let mut png: Vec<u8> = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            [(x * 4) as u8, (y * 4) as u8, (x * y) as u8].into()
+        }))
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+        let base_url = serve(vec![("/photo.png", response("image/png", &png))]);
+        let url = format!("{}/photo.png", base_url);
+        let opts = Options::builder()
+            .image_quality(60)
+            .silent(true)
+            .build()
+            .unwrap();
+        let opts_lossless = Options::builder()
+            .image_quality(60)
+            .reencode_lossless(true)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        // PNGs are left alone unless explicitly allowed to lose quality
+        assert_eq!(
+            retrieve_asset(&url, true, "", &opts).unwrap(),
+            data_to_dataurl("image/png", &png)
+        );
+
+        let (mimetype, data) =
+            dataurl_to_data(&retrieve_asset(&url, true, "", &opts_lossless).unwrap()).unwrap();
+        let jpeg = imagelib::load_from_memory_with_format(&data, ImageFormat::Jpeg).unwrap();
+
+        assert_eq!(mimetype, "image/jpeg");
+        assert!(data.len() < png.len());
+        assert_eq!((jpeg.width(), jpeg.height()), (64, 64));
+    }
+
+    #[test]
+    fn test_retrieve_asset_image_encoding() {
+        let mut jpeg: Vec<u8> = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            [(x * 4) as u8, (y * 4) as u8, (x * y) as u8].into()
+        }))
+        .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .unwrap();
+        let base_url = serve(vec![("/photo.jpg", response("image/jpeg", &jpeg))]);
+        let opts = Options::builder()
+            .image_encoding(ImageEncoding::Webp)
+            .image_quality(60)
+            .silent(true)
+            .build()
+            .unwrap();
+
+        let (mimetype, data) = dataurl_to_data(
+            &retrieve_asset(&format!("{}/photo.jpg", base_url), true, "", &opts).unwrap(),
+        )
+        .unwrap();
+        let webp = imagelib::load_from_memory_with_format(&data, ImageFormat::WebP).unwrap();
+
+        assert_eq!(mimetype, "image/webp");
+        assert!(data.len() < jpeg.len());
+        assert_eq!((webp.width(), webp.height()), (64, 64));
+    }
+
+    #[test]
+    fn test_retrieve_asset_file_url() {
+        let path = env::temp_dir().join("monolith-test-retrieve-asset-file-url.png");
+        fs::write(&path, b"\x89PNG\r\n\x1a\nlocal").unwrap();
+        let url = Url::from_file_path(&path).unwrap().to_string();
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let opts_files = Options {
+            allow_file_urls: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            retrieve_asset(&url, true, "", &opts),
+            Err(MonolithError::FetchError {
+                url: url.clone(),
+                reason: "local files are not allowed".to_string(),
+            })
+        );
+        assert_eq!(
+            retrieve_asset(&url, true, "", &opts_files).unwrap(),
+            data_to_dataurl("image/png", b"\x89PNG\r\n\x1a\nlocal")
+        );
+        assert!(
+            retrieve_asset(&resolve_url(&url, "missing.css").unwrap(), false, "", &opts_files)
+                .is_err()
+        );
+        assert_eq!(retrieve_asset_size(&url, &opts_files), Ok(Some(13)));
+
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,240 @@
+use imagelib::codecs::gif::GifDecoder;
+use imagelib::imageops::FilterType;
+use imagelib::codecs::jpeg::JpegEncoder;
+use imagelib::{AnimationDecoder, ImageFormat, ImageReader, RgbImage};
+use opts::ImageEncoding;
+use std::io::Cursor;
+use webp::Encoder as WebpEncoder;
+
+fn is_png(data: &[u8]) -> bool {
+    data.starts_with(b"\x89PNG\r\n\x1a\n")
+}
+
+// Scales images down so that neither side exceeds the given size, keeping the aspect ratio
+// and the format; animated GIFs, WebP images and anything small enough already are returned
+// as they are
+pub fn downscale_image(data: Vec<u8>, max_dimension: u32) -> Vec<u8> {
+    let format = match imagelib::guess_format(&data) {
+        Ok(format @ ImageFormat::Png)
+        | Ok(format @ ImageFormat::Jpeg)
+        | Ok(format @ ImageFormat::Gif) => format,
+        _ => return data,
+    };
+
+    match image_dimensions(&data) {
+        Some((width, height)) if width.max(height) > max_dimension => {}
+        _ => return data,
+    }
+
+    // Only the first frame would be left of an animation
+    if format == ImageFormat::Gif && is_animated_gif(&data) {
+        return data;
+    }
+
+    let image = match imagelib::load_from_memory_with_format(&data, format) {
+        Ok(image) => image,
+        Err(_) => return data,
+    };
+    let mut downscaled: Vec<u8> = Vec::new();
+
+    match image
+        .resize(max_dimension, max_dimension, FilterType::Triangle)
+        .write_to(&mut Cursor::new(&mut downscaled), format)
+    {
+        Ok(()) => downscaled,
+        Err(_) => data,
+    }
+}
+
+// Width and height as found in the header, without decoding the whole image
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let le16 =
+        |offset: usize| Some(*data.get(offset)? as u32 | (*data.get(offset + 1)? as u32) << 8);
+    let le24 = |offset: usize| Some(le16(offset)? | (*data.get(offset + 2)? as u32) << 16);
+
+    if is_png(data) {
+        Some((read_u32(data.get(16..20)?), read_u32(data.get(20..24)?)))
+    } else if data.starts_with(b"\xFF\xD8\xFF") {
+        // Found in whichever frame header comes first, past any metadata
+        ImageReader::with_format(Cursor::new(data), ImageFormat::Jpeg)
+            .into_dimensions()
+            .ok()
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some((le16(6)?, le16(8)?))
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        match data.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = le24(21)? | (*data.get(24)? as u32) << 24;
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+// Re-encodes JPEG images at the given quality, as JPEG or WebP, keeping whichever version
+// is smaller; PNG images only get re-encoded if losing their lossless quality is acceptable,
+// and only if they have no transparency to lose
+pub fn reencode_image(
+    mime: &str,
+    data: Vec<u8>,
+    quality: u8,
+    lossless: bool,
+    encoding: ImageEncoding,
+) -> (String, Vec<u8>) {
+    let image = match imagelib::guess_format(&data) {
+        Ok(ImageFormat::Jpeg) => decode_opaque(&data, ImageFormat::Jpeg),
+        Ok(ImageFormat::Png) if lossless => decode_opaque(&data, ImageFormat::Png),
+        _ => None,
+    };
+
+    match image.and_then(|image| encode_rgb(&image, quality, encoding)) {
+        Some((reencoded_mime, reencoded)) if reencoded.len() < data.len() => {
+            (reencoded_mime.to_string(), reencoded)
+        }
+        _ => (mime.to_string(), data),
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32
+}
+
+// Same image without its alpha channel or palette, unless some of it is see-through
+fn decode_opaque(data: &[u8], format: ImageFormat) -> Option<RgbImage> {
+    let image = imagelib::load_from_memory_with_format(data, format).ok()?;
+
+    if image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] != 255) {
+        return None;
+    }
+
+    Some(image.to_rgb8())
+}
+
+fn encode_rgb(
+    image: &RgbImage,
+    quality: u8,
+    encoding: ImageEncoding,
+) -> Option<(&'static str, Vec<u8>)> {
+    match encoding {
+        ImageEncoding::Jpeg => {
+            let mut jpeg: Vec<u8> = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, quality)
+                .encode_image(image)
+                .ok()?;
+            Some(("image/jpeg", jpeg))
+        }
+        ImageEncoding::Webp => {
+            let webp = WebpEncoder::from_rgb(image, image.width(), image.height());
+            Some(("image/webp", webp.encode(quality as f32).to_vec()))
+        }
+    }
+}
+
+fn is_animated_gif(data: &[u8]) -> bool {
+    match GifDecoder::new(Cursor::new(data)) {
+        Ok(decoder) => decoder.into_frames().take(2).count() > 1,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imagelib::codecs::gif::GifEncoder;
+    use imagelib::{DynamicImage, Frame, GrayImage, RgbaImage};
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), format).unwrap();
+        data
+    }
+
+    fn gray_image(width: u32, height: u32, value: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, [value].into()))
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        assert_eq!(
+            image_dimensions(&encode(gray_image(3, 2, 0), ImageFormat::Png)),
+            Some((3, 2))
+        );
+        assert_eq!(image_dimensions(b"GIF89a\x20\x01\x10\x00"), Some((288, 16)));
+        assert_eq!(
+            image_dimensions(b"RIFF\x00\x00\x00\x00WEBPVP8X\x0A\x00\x00\x00\x10\x00\x00\x00\
+                               \x3F\x01\x00\xC7\x00\x00"),
+            Some((320, 200))
+        );
+        assert_eq!(image_dimensions(b"<svg/>"), None);
+    }
+
+    #[test]
+    fn test_downscale_image() {
+        let png = encode(gray_image(4, 2, 100), ImageFormat::Png);
+        let small_png = encode(gray_image(2, 1, 100), ImageFormat::Png);
+        let downscaled = imagelib::load_from_memory(&downscale_image(png, 2)).unwrap();
+
+        assert_eq!(downscaled.to_luma8(), gray_image(2, 1, 100).to_luma8());
+        assert_eq!(downscale_image(small_png.clone(), 2), small_png);
+        assert_eq!(downscale_image(b"GIF89a".to_vec(), 2), b"GIF89a".to_vec());
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Gif].iter() {
+            // GIF has no grayscale of its own
+            let image = DynamicImage::ImageRgb8(gray_image(40, 20, 100).to_rgb8());
+            let downscaled = downscale_image(encode(image, *format), 10);
+
+            assert_eq!(imagelib::guess_format(&downscaled).unwrap(), *format);
+            assert_eq!(image_dimensions(&downscaled), Some((10, 5)));
+        }
+    }
+
+    #[test]
+    fn test_reencode_image() {
+        let photo = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            [(x * 4) as u8, (y * 4) as u8, 128].into()
+        }));
+        let mut data: Vec<u8> = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, 95)
+            .encode_image(&photo)
+            .unwrap();
+        let transparent = encode(
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+                [(x * 4) as u8, (y * 4) as u8, 128, 128].into()
+            })),
+            ImageFormat::Png,
+        );
+
+        let (mime, reencoded) =
+            reencode_image("image/jpeg", data.clone(), 30, false, ImageEncoding::Jpeg);
+        assert_eq!(mime, "image/jpeg");
+        assert!(reencoded.len() < data.len());
+
+        let (mime, reencoded) =
+            reencode_image("image/jpeg", data.clone(), 30, false, ImageEncoding::Webp);
+        assert_eq!(mime, "image/webp");
+        assert_eq!(image_dimensions(&reencoded), Some((64, 64)));
+
+        // Transparency has nowhere to go
+        assert_eq!(
+            reencode_image("image/png", transparent.clone(), 30, true, ImageEncoding::Jpeg),
+            ("image/png".to_string(), transparent)
+        );
+    }
+
+    #[test]
+    fn test_downscale_animated_gif() {
+        let mut gif: Vec<u8> = Vec::new();
+        {
+            let frame = || Frame::new(RgbaImage::from_pixel(40, 20, [0, 0, 0, 255].into()));
+            let mut encoder = GifEncoder::new(&mut gif);
+            encoder.encode_frames(vec![frame(), frame()]).unwrap();
+        }
+
+        assert_eq!(downscale_image(gif.clone(), 10), gif);
+    }
+}
@@ -0,0 +1,298 @@
+use context::Context;
+use http::{is_data_url, resolve_url, retrieve_cached_asset};
+use opts::{AssetAction, Options};
+use regex::{Captures, Regex};
+use url::Url;
+use utils::data_to_dataurl;
+
+lazy_static! {
+    static ref WORKER_REFERENCE: Regex = Regex::new(
+        r#"(?P<call>\bnew\s+(?:Shared)?Worker\s*\(\s*|\bimportScripts\s*\(\s*)(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)')"#
+    ).unwrap();
+    // Static imports and re-exports, i.e. import "a", import b from "a" and export * from "a"
+    static ref MODULE_IMPORT: Regex = Regex::new(
+        r#"(?P<statement>\b(?:import|export)\b[^'"`;()]*?\bfrom\s*|\bimport\s*)(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)')"#
+    ).unwrap();
+    // Both the current //# and the deprecated //@ form, each on a line of its own
+    static ref SOURCE_MAP: Regex = Regex::new(
+        r"(?m)^[ \t]*//[#@][ \t]*sourceMappingURL[ \t]*=[ \t]*(?P<url>\S+)[ \t]*\r?$"
+    ).unwrap();
+}
+
+// Workers may import scripts, which in turn may import more of them
+const MAX_WORKER_DEPTH: usize = 3;
+
+// Deep enough for most dependency graphs, and for import cycles to come to an end
+const MAX_MODULE_DEPTH: usize = 5;
+
+pub fn embed_workers(url: &str, js: &str, opts: &Options, ctx: &mut Context) -> String {
+    embed_worker_references(url, js, opts, 0, ctx)
+}
+
+fn embed_worker_references(
+    url: &str,
+    js: &str,
+    opts: &Options,
+    depth: usize,
+    ctx: &mut Context,
+) -> String {
+    if depth >= MAX_WORKER_DEPTH {
+        return js.to_string();
+    }
+
+    // Only string literals are picked up, anything computed at runtime stays as is
+    WORKER_REFERENCE
+        .replace_all(js, |caps: &Captures| {
+            let (quote, reference) = match caps.name("dq") {
+                Some(reference) => ('"', reference.as_str()),
+                None => ('\'', caps.name("sq").map(|reference| reference.as_str()).unwrap_or("")),
+            };
+
+            format!(
+                "{}{}{}{}",
+                &caps["call"],
+                quote,
+                embed_worker(url, reference, opts, depth, ctx),
+                quote
+            )
+        })
+        .to_string()
+}
+
+fn embed_worker(
+    url: &str,
+    reference: &str,
+    opts: &Options,
+    depth: usize,
+    ctx: &mut Context,
+) -> String {
+    if reference.is_empty() || is_data_url(reference).unwrap_or(false) {
+        return reference.to_string();
+    }
+
+    let full_url = match resolve_url(url, reference) {
+        Ok(full_url) => full_url,
+        Err(_) => return reference.to_string(),
+    };
+
+    match retrieve_cached_asset(&full_url, false, "application/javascript", opts, ctx) {
+        Ok(worker_js) => {
+            // Scripts imported by the worker are relative to the worker itself
+            let worker_js = embed_source_map(&full_url, &worker_js, opts, ctx);
+            let worker_js = embed_worker_references(&full_url, &worker_js, opts, depth + 1, ctx);
+            data_to_dataurl("application/javascript", worker_js.as_bytes())
+        }
+        Err(_) => full_url,
+    }
+}
+
+// Maps point to remote files which won't be there offline, unless embedded as well
+pub fn embed_source_map(url: &str, js: &str, opts: &Options, ctx: &mut Context) -> String {
+    SOURCE_MAP
+        .replace_all(js, |caps: &Captures| {
+            let full_url = match resolve_url(url, &caps["url"]) {
+                Ok(full_url) => full_url,
+                Err(_) => return String::new(),
+            };
+
+            match opts.source_maps {
+                AssetAction::Drop => String::new(),
+                AssetAction::Keep => format!("//# sourceMappingURL={}", full_url),
+                AssetAction::Embed => format!(
+                    "//# sourceMappingURL={}",
+                    retrieve_cached_asset(&full_url, true, "application/json", opts, ctx)
+                        .unwrap_or(full_url)
+                ),
+            }
+        })
+        .to_string()
+}
+
+pub fn embed_module_imports(url: &str, js: &str, opts: &Options, ctx: &mut Context) -> String {
+    embed_import_references(url, js, opts, 0, ctx)
+}
+
+fn embed_import_references(
+    url: &str,
+    js: &str,
+    opts: &Options,
+    depth: usize,
+    ctx: &mut Context,
+) -> String {
+    if depth >= MAX_MODULE_DEPTH {
+        return js.to_string();
+    }
+
+    MODULE_IMPORT
+        .replace_all(js, |caps: &Captures| {
+            let (quote, specifier) = match caps.name("dq") {
+                Some(specifier) => ('"', specifier.as_str()),
+                None => ('\'', caps.name("sq").map(|specifier| specifier.as_str()).unwrap_or("")),
+            };
+
+            format!(
+                "{}{}{}{}",
+                &caps["statement"],
+                quote,
+                embed_import(url, specifier, opts, depth, ctx),
+                quote
+            )
+        })
+        .to_string()
+}
+
+fn embed_import(
+    url: &str,
+    specifier: &str,
+    opts: &Options,
+    depth: usize,
+    ctx: &mut Context,
+) -> String {
+    // Bare specifiers are up to import maps to resolve, there's no telling where they lead
+    let is_bare = !specifier.starts_with('/')
+        && !specifier.starts_with("./")
+        && !specifier.starts_with("../")
+        && Url::parse(specifier).is_err();
+
+    if is_bare || is_data_url(specifier).unwrap_or(false) {
+        return specifier.to_string();
+    }
+
+    let full_url = match resolve_url(url, specifier) {
+        Ok(full_url) => full_url,
+        Err(_) => return specifier.to_string(),
+    };
+
+    match retrieve_cached_asset(&full_url, false, "application/javascript", opts, ctx) {
+        Ok(module_js) => {
+            // Modules import one another relative to themselves
+            let module_js = embed_source_map(&full_url, &module_js, opts, ctx);
+            let module_js = embed_import_references(&full_url, &module_js, opts, depth + 1, ctx);
+            data_to_dataurl("application/javascript", module_js.as_bytes())
+        }
+        Err(_) => full_url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{response, serve};
+
+    #[test]
+    fn test_embed_workers() {
+        let base_url = serve(vec![
+            (
+                "/js/w.js",
+                response(
+                    "application/javascript",
+                    b"importScripts(\"lib.js\");onmessage=work;",
+                ),
+            ),
+            (
+                "/js/lib.js",
+                response("application/javascript", b"function work(){}"),
+            ),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let worker_js = format!(
+            "importScripts(\"{}\");onmessage=work;",
+            data_to_dataurl("application/javascript", b"function work(){}")
+        );
+
+        assert_eq!(
+            embed_workers(
+                &format!("{}/index.html", base_url),
+                "var w = new Worker('js/w.js'); var s = new SharedWorker(src);",
+                &opts
+            , &mut Context::default()),
+            format!(
+                "var w = new Worker('{}'); var s = new SharedWorker(src);",
+                data_to_dataurl("application/javascript", worker_js.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_source_map() {
+        let base_url = serve(vec![(
+            "/js/app.js.map",
+            response("application/json", b"{\"version\":3}"),
+        )]);
+        let url = format!("{}/js/app.js", base_url);
+        let js = "run();\n//# sourceMappingURL=app.js.map\n";
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+
+        assert_eq!(embed_source_map(&url, js, &opts, &mut Context::default()), "run();\n\n");
+        assert_eq!(
+            embed_source_map(
+                &url,
+                js,
+                &Options {
+                    source_maps: AssetAction::Keep,
+                    ..opts.clone()
+                },
+                &mut Context::default()
+            ),
+            format!("run();\n//# sourceMappingURL={}/js/app.js.map\n", base_url)
+        );
+        assert_eq!(
+            embed_source_map(
+                &url,
+                js,
+                &Options {
+                    source_maps: AssetAction::Embed,
+                    ..opts
+                },
+                &mut Context::default()
+            ),
+            format!(
+                "run();\n//# sourceMappingURL={}\n",
+                data_to_dataurl("application/json", b"{\"version\":3}")
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_module_imports() {
+        let base_url = serve(vec![
+            (
+                "/js/app.js",
+                response(
+                    "application/javascript",
+                    b"export * from './util.js';run();",
+                ),
+            ),
+            (
+                "/js/util.js",
+                response("application/javascript", b"export const x = 1;"),
+            ),
+        ]);
+        let opts = Options {
+            silent: true,
+            ..Default::default()
+        };
+        let app_js = format!(
+            "export * from '{}';run();",
+            data_to_dataurl("application/javascript", b"export const x = 1;")
+        );
+
+        assert_eq!(
+            embed_module_imports(
+                &format!("{}/index.html", base_url),
+                "import { run } from \"./js/app.js\";\nimport 'lodash';\nimport(\"./lazy.js\");",
+                &opts
+            , &mut Context::default()),
+            format!(
+                "import {{ run }} from \"{}\";\nimport 'lodash';\nimport(\"./lazy.js\");",
+                data_to_dataurl("application/javascript", app_js.as_bytes())
+            )
+        );
+    }
+}
@@ -1,10 +1,42 @@
 #[macro_use]
 extern crate lazy_static;
+extern crate brotli;
+extern crate encoding_rs;
+extern crate flate2;
+extern crate futures;
+#[macro_use]
 extern crate html5ever;
+extern crate image as imagelib;
+extern crate openssl;
 extern crate regex;
 extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+#[cfg(test)]
+extern crate tokio;
 extern crate url;
+extern crate webp;
 
+pub mod clock;
+pub mod context;
+pub mod css;
+pub mod digest;
+pub mod error;
 pub mod html;
 pub mod http;
+pub mod image;
+pub mod js;
+pub mod manifest;
+pub mod opts;
+pub mod output;
+pub mod pretty;
+pub mod robots;
+pub mod shell;
+pub mod stats;
+pub mod throttle;
 pub mod utils;
+pub mod whitespace;
+
+#[cfg(test)]
+mod test_server;
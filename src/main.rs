@@ -1,13 +1,19 @@
 #[macro_use]
 extern crate clap;
 extern crate monolith;
+extern crate url;
 
 use clap::{App, Arg};
-use monolith::html::{html_to_dom, print_dom, walk_and_embed_assets};
-use monolith::http::{is_valid_url, retrieve_asset};
-
-static DEFAULT_USER_AGENT: &str =
-    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.14; rv:66.0) Gecko/20100101 Firefox/66.0";
+use monolith::error::MonolithError;
+use monolith::html::process_html_to_writer;
+use monolith::http::{is_valid_url, retrieve_page};
+use monolith::opts::{Options, DEFAULT_TIMEOUT, DEFAULT_USER_AGENT};
+use monolith::output::write_atomically;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use url::Url;
 
 fn main() {
     let command = App::new("monolith")
@@ -21,43 +27,126 @@ fn main() {
                 .index(1)
                 .help("URL to download"),
         )
+        .args_from_usage("-b, --base-url=[URL] 'Resolves relative URLs against another URL'")
+        .args_from_usage("-c, --no-comments 'Removes comments'")
+        .args_from_usage("-f, --embed-feeds 'Embeds linked RSS and Atom feeds'")
         .args_from_usage("-i, --no-images 'Removes images'")
         .args_from_usage("-j, --no-js 'Excludes JavaScript'")
         .args_from_usage("-k, --insecure 'Accept invalid X.509 (TLS) certificates'")
+        .args_from_usage("-l, --local-links 'Keeps links within the same page local'")
+        .args_from_usage("-o, --first-party 'Only embeds assets hosted by the site itself'")
+        .args_from_usage("-p, --pretty 'Indents the resulting HTML'")
+        .args_from_usage("-r, --respect-robots 'Skips assets disallowed by robots.txt'")
         .args_from_usage("-s, --silent 'Suppress verbosity'")
+        .args_from_usage("-t, --timeout=[10] 'Network request timeout in seconds'")
         .args_from_usage("-u, --user-agent=[Iceweasel] 'Custom User-Agent string'")
+        .args_from_usage("--cert=[FILE] 'Client certificate (PEM) for hosts which ask for one'")
+        .args_from_usage("--collapse-whitespace 'Removes whitespace which makes no difference'")
+        .args_from_usage("--key=[FILE] 'Private key of the client certificate, in PEM'")
+        .args_from_usage("--manifest=[FILE] 'Lists embedded assets in a JSON file'")
+        .args_from_usage("--output=[FILE] 'Writes the page to a file once it is complete'")
+        .args_from_usage("--scope=[PREFIX] 'Only embeds assets under the given URL or path'")
+        .args_from_usage("--self-extracting 'Compresses the page into one that inflates itself'")
+        .args_from_usage("--strict 'Fails instead of leaving out assets which can't be retrieved'")
+        .args_from_usage("--verify-integrity 'Leaves out assets which do not match their hashes'")
         .get_matches();
 
     // Process the command
     let arg_target = command.value_of("url").unwrap();
-    let opt_no_images = command.is_present("no-images");
-    let opt_no_js = command.is_present("no-js");
-    let opt_insecure = command.is_present("insecure");
-    let opt_silent = command.is_present("silent");
-    let opt_user_agent = command.value_of("user-agent").unwrap_or(DEFAULT_USER_AGENT);
-
-    if is_valid_url(arg_target) {
-        let data = retrieve_asset(
-                &arg_target,
-                false,
-                "",
-                opt_user_agent,
-                opt_silent,
-                opt_insecure,
-            ).unwrap();
-        let dom = html_to_dom(&data);
-
-        walk_and_embed_assets(
-            &arg_target,
-            &dom.document,
-            opt_no_js,
-            opt_no_images,
-            opt_user_agent,
-            opt_silent,
-            opt_insecure,
-        );
-
-        print_dom(&dom.document);
-        println!(); // Ensure newline at end of output
+    // Pages saved to the disk get to embed other local files, remote ones don't
+    let is_local_file = arg_target.starts_with("file://");
+    let mut builder = Options::builder()
+        .allow_file_urls(is_local_file)
+        .collapse_whitespace(command.is_present("collapse-whitespace"))
+        .embed_feeds(command.is_present("embed-feeds"))
+        .no_comments(command.is_present("no-comments"))
+        .no_images(command.is_present("no-images"))
+        .no_js(command.is_present("no-js"))
+        .insecure(command.is_present("insecure"))
+        .local_links(command.is_present("local-links"))
+        .pretty(command.is_present("pretty"))
+        .record_manifest(command.is_present("manifest"))
+        .respect_robots(command.is_present("respect-robots"))
+        .self_extracting(command.is_present("self-extracting"))
+        .silent(command.is_present("silent"))
+        .strict(command.is_present("strict"))
+        .timeout(value_t!(command, "timeout", u64).unwrap_or(DEFAULT_TIMEOUT))
+        .user_agent(command.value_of("user-agent").unwrap_or(DEFAULT_USER_AGENT))
+        .verify_integrity(command.is_present("verify-integrity"));
+
+    if let Some(base_url) = command.value_of("base-url") {
+        builder = builder.base_url(base_url);
+    }
+
+    if let Some(cert_path) = command.value_of("cert") {
+        let key_path = command.value_of("key").unwrap_or(cert_path);
+        builder = builder.client_identity_pem_files(cert_path, key_path);
+    }
+
+    if let Some(scope) = command.value_of("scope") {
+        builder = builder.scope(scope);
+    }
+
+    if command.is_present("first-party") {
+        let page_url = command.value_of("base-url").unwrap_or(arg_target);
+        let host = Url::parse(page_url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from));
+
+        if let Some(host) = host {
+            builder = builder.first_party_host(&host);
+        }
+    }
+
+    let opts = builder.build().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    if is_valid_url(arg_target) || is_local_file {
+        let data = retrieve_page(&arg_target, &opts).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+
+        // Files only show up once written out in full, pages which fail leave nothing behind
+        if let Some(output_path) = command.value_of("output") {
+            let result = write_atomically(Path::new(output_path), |temp_path| {
+                let write_error = |err: io::Error| MonolithError::WriteError(err.to_string());
+                let mut output = BufWriter::new(File::create(temp_path).map_err(write_error)?);
+                process_html_to_writer(&arg_target, &data, &opts, &mut output)?;
+                output.flush().map_err(write_error)
+            });
+
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+
+            write_manifest(command.value_of("manifest"), &opts);
+            return;
+        }
+
+        let stdout = io::stdout();
+        let mut output = BufWriter::new(stdout.lock());
+
+        if let Err(err) = process_html_to_writer(&arg_target, &data, &opts, &mut output) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+
+        // Ensure newline at end of output
+        writeln!(output).unwrap();
+
+        write_manifest(command.value_of("manifest"), &opts);
+    }
+}
+
+fn write_manifest(path: Option<&str>, opts: &Options) {
+    if let Some(path) = path {
+        if let Err(err) = fs::write(path, opts.manifest.to_json()) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
     }
 }
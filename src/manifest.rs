@@ -0,0 +1,124 @@
+use serde::Serialize;
+use serde_json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// One retrieved asset, as it was before getting embedded
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    pub url: String,
+    // Where the asset ended up being retrieved from once redirects were followed
+    pub resolved_url: String,
+    pub mime: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
+#[derive(Default)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+    resolved_urls: HashMap<String, String>,
+}
+
+// Lists assets as they get retrieved, every clone of the options shares it
+#[derive(Clone, Default)]
+pub struct ManifestCollector(Arc<Mutex<Manifest>>);
+
+impl ManifestCollector {
+    // Where URLs resolved to is kept, prefetching looks those up before the walk begins
+    pub fn clear(&self) {
+        self.0.lock().unwrap().entries.clear();
+    }
+
+    pub fn entries(&self) -> Vec<ManifestEntry> {
+        self.0.lock().unwrap().entries.clone()
+    }
+
+    pub fn record_resolved_url(&self, url: &str, resolved_url: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .resolved_urls
+            .insert(url.to_string(), resolved_url.to_string());
+    }
+
+    pub fn record(&self, url: &str, mime: &str, data: &[u8]) {
+        self.record_hash(url, mime, data.len(), &format!("{:x}", Sha256::digest(data)));
+    }
+
+    // Assets retrieved more than once, e.g. both as text and as a data URL, are listed once;
+    // bodies too large to be held on to get hashed while being read instead
+    pub fn record_hash(&self, url: &str, mime: &str, size: usize, sha256: &str) {
+        let mut manifest = self.0.lock().unwrap();
+
+        if manifest.entries.iter().any(|entry| entry.url == url) {
+            return;
+        }
+
+        let resolved_url = manifest
+            .resolved_urls
+            .get(url)
+            .cloned()
+            .unwrap_or_else(|| url.to_string());
+
+        manifest.entries.push(ManifestEntry {
+            url: url.to_string(),
+            resolved_url,
+            mime: mime.to_string(),
+            size,
+            sha256: sha256.to_string(),
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        // Nothing but strings and numbers, which always serialize
+        serde_json::to_string_pretty(&self.entries()).unwrap()
+    }
+}
+
+impl fmt::Debug for ManifestCollector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ManifestCollector")
+    }
+}
+
+impl PartialEq for ManifestCollector {
+    fn eq(&self, _other: &ManifestCollector) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_to_json() {
+        let manifest = ManifestCollector::default();
+
+        assert_eq!(manifest.to_json(), "[]");
+
+        manifest.record_resolved_url("http://localhost/logo", "http://localhost/logo.png");
+        manifest.record("http://localhost/logo", "image/png", b"abc");
+        manifest.record("http://localhost/logo", "image/png", b"abc");
+        manifest.record("http://localhost/\"quoted\".css", "text/css", b"");
+
+        assert_eq!(
+            manifest.to_json(),
+            "[\n  {\n    \"url\": \"http://localhost/logo\",\n    \
+             \"resolved_url\": \"http://localhost/logo.png\",\n    \
+             \"mime\": \"image/png\",\n    \
+             \"size\": 3,\n    \
+             \"sha256\": \"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\"\
+             \n  },\n  \
+             {\n    \"url\": \"http://localhost/\\\"quoted\\\".css\",\n    \
+             \"resolved_url\": \"http://localhost/\\\"quoted\\\".css\",\n    \
+             \"mime\": \"text/css\",\n    \
+             \"size\": 0,\n    \
+             \"sha256\": \"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\"\
+             \n  }\n]"
+        );
+    }
+}
@@ -0,0 +1,1191 @@
+use clock::{Clock, SharedClock};
+use context::Context;
+use error::MonolithError;
+use html::{built_in_element_handlers, DomVisitor};
+use html5ever::rcdom::Handle;
+use html5ever::{Attribute, QualName};
+use http::{AssetFetcher, ClientPool};
+use manifest::ManifestCollector;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use robots::RobotsCache;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use url::Url;
+use utils::DataUrlEncoding;
+
+pub static DEFAULT_USER_AGENT: &str =
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.14; rv:66.0) Gecko/20100101 Firefox/66.0";
+
+pub static DEFAULT_SCHEME: &str = "http";
+
+pub const DEFAULT_TIMEOUT: u64 = 10;
+
+pub const DEFAULT_VIEWPORT_WIDTH: u32 = 1280;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AssetCategory {
+    Image,
+    Style,
+    Script,
+    Font,
+    Media,
+    Other,
+}
+
+impl AssetCategory {
+    // Best guess for references which carry no hint other than the URL itself
+    pub fn from_url(url: &str) -> AssetCategory {
+        let path = url.split(['?', '#']).next().unwrap_or("");
+        let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+
+        match extension.as_str() {
+            "apng" | "avif" | "bmp" | "cur" | "gif" | "ico" | "jpeg" | "jpg" | "png" | "svg"
+            | "webp" => AssetCategory::Image,
+            "css" => AssetCategory::Style,
+            "js" | "mjs" => AssetCategory::Script,
+            "eot" | "otf" | "ttf" | "woff" | "woff2" => AssetCategory::Font,
+            "m4a" | "mp3" | "mp4" | "oga" | "ogg" | "ogv" | "wav" | "webm" => AssetCategory::Media,
+            _ => AssetCategory::Other,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> AssetCategory {
+        let essence = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+
+        if essence.starts_with("image/") {
+            AssetCategory::Image
+        } else if essence == "text/css" {
+            AssetCategory::Style
+        } else if essence.contains("javascript") || essence.contains("ecmascript") {
+            AssetCategory::Script
+        } else if essence.starts_with("font/") || essence.starts_with("application/font-") {
+            AssetCategory::Font
+        } else if essence.starts_with("audio/") || essence.starts_with("video/") {
+            AssetCategory::Media
+        } else {
+            AssetCategory::Other
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AssetAction {
+    Embed,
+    Keep,
+    Drop,
+}
+
+// Format images get re-encoded into when a quality is set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageEncoding {
+    Jpeg,
+    Webp,
+}
+
+// Which one of the alternative sources of audio and video gets embedded
+#[derive(Clone, Debug, PartialEq)]
+pub enum MediaSource {
+    // The one with the least bytes to download, as reported by the server
+    Smallest,
+    // The first one of the most preferred MIME type, e.g. ["video/webm", "video/mp4"]
+    Types(Vec<String>),
+}
+
+pub type TransformAsset = dyn FnMut(&str, Vec<u8>) -> (String, Vec<u8>) + Send;
+
+// Rewrites bytes (and MIME type) of retrieved assets right before they get embedded
+#[derive(Clone)]
+pub struct AssetTransform(Arc<Mutex<Box<TransformAsset>>>);
+
+impl AssetTransform {
+    pub fn new<F>(transform: F) -> AssetTransform
+    where
+        F: FnMut(&str, Vec<u8>) -> (String, Vec<u8>) + Send + 'static,
+    {
+        AssetTransform(Arc::new(Mutex::new(Box::new(transform))))
+    }
+
+    pub fn apply(&self, mime: &str, data: Vec<u8>) -> (String, Vec<u8>) {
+        let mut transform = self.0.lock().unwrap();
+        (*transform)(mime, data)
+    }
+}
+
+impl fmt::Debug for AssetTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AssetTransform")
+    }
+}
+
+impl PartialEq for AssetTransform {
+    fn eq(&self, other: &AssetTransform) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// Embeds the assets of an element found in the document at the given URL; being one
+// signature for all elements, it takes a vector even where a slice would do
+pub type EmbedElement = fn(&str, &Handle, &QualName, &mut Vec<Attribute>, &Options, &mut Context);
+
+// Handlers of library users, which get no more than the element and the walk's context
+pub type HandleElement = dyn FnMut(&Handle, &mut Context) + Send;
+
+// Takes care of elements the walker knows nothing about, or handles differently than wanted;
+// the walker's own handlers live in the same table, see built_in_element_handlers()
+#[derive(Clone)]
+pub enum ElementHandler {
+    BuiltIn(EmbedElement),
+    Custom(Arc<Mutex<Box<HandleElement>>>),
+}
+
+impl ElementHandler {
+    pub fn new<F>(handler: F) -> ElementHandler
+    where
+        F: FnMut(&Handle, &mut Context) + Send + 'static,
+    {
+        ElementHandler::Custom(Arc::new(Mutex::new(Box::new(handler))))
+    }
+
+    // Built-in handlers need more than this to go on, the walker calls them itself
+    pub fn apply(&self, node: &Handle, ctx: &mut Context) {
+        if let ElementHandler::Custom(ref handler) = *self {
+            let mut handler = handler.lock().unwrap();
+            (*handler)(node, ctx)
+        }
+    }
+}
+
+impl fmt::Debug for ElementHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ElementHandler")
+    }
+}
+
+impl PartialEq for ElementHandler {
+    fn eq(&self, other: &ElementHandler) -> bool {
+        match (self, other) {
+            (ElementHandler::BuiltIn(embed), ElementHandler::BuiltIn(other_embed)) => {
+                *embed as usize == *other_embed as usize
+            }
+            (ElementHandler::Custom(handler), ElementHandler::Custom(other_handler)) => {
+                Arc::ptr_eq(handler, other_handler)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Gets to do whatever it likes with the document once everything's been embedded into it
+#[derive(Clone)]
+pub struct CustomVisitor(Arc<Mutex<Box<dyn DomVisitor>>>);
+
+impl CustomVisitor {
+    pub fn new<V>(visitor: V) -> CustomVisitor
+    where
+        V: DomVisitor + 'static,
+    {
+        CustomVisitor(Arc::new(Mutex::new(Box::new(visitor))))
+    }
+
+    pub fn visit(&self, document: &Handle, url: &str) {
+        self.0.lock().unwrap().visit(document, url)
+    }
+}
+
+impl fmt::Debug for CustomVisitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomVisitor")
+    }
+}
+
+impl PartialEq for CustomVisitor {
+    fn eq(&self, other: &CustomVisitor) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// Takes over retrieval of assets from the built-in HTTP client
+#[derive(Clone)]
+pub struct CustomFetcher(Arc<dyn AssetFetcher>);
+
+impl CustomFetcher {
+    pub fn new<F>(fetcher: F) -> CustomFetcher
+    where
+        F: AssetFetcher + 'static,
+    {
+        CustomFetcher(Arc::new(fetcher))
+    }
+
+    pub fn fetch(&self, url: &str) -> Result<(String, Vec<u8>), MonolithError> {
+        self.0.fetch(url)
+    }
+}
+
+impl fmt::Debug for CustomFetcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomFetcher")
+    }
+}
+
+impl PartialEq for CustomFetcher {
+    fn eq(&self, other: &CustomFetcher) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// Certificate and private key presented to hosts which ask for one,
+// bundled as PKCS#12 since that is what the TLS backend understands
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ClientIdentity {
+    pub pkcs12: Vec<u8>,
+    pub password: String,
+}
+
+// The key and its password are nobody's business, options get printed every now and then
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientIdentity")
+    }
+}
+
+// PEM-encoded certificate or private key, either as it is or as a file to be read
+#[derive(Clone)]
+enum PemSource {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+impl fmt::Debug for PemSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PemSource::Bytes(_) => write!(f, "PemSource"),
+            PemSource::File(ref path) => write!(f, "PemSource({})", path.display()),
+        }
+    }
+}
+
+impl PemSource {
+    fn read(&self) -> Result<Vec<u8>, MonolithError> {
+        match *self {
+            PemSource::Bytes(ref data) => Ok(data.clone()),
+            PemSource::File(ref path) => fs::read(path).map_err(|err| {
+                MonolithError::InvalidOptions(format!("could not read {}: {}", path.display(), err))
+            }),
+        }
+    }
+}
+
+// Certificate and key get bundled up as PKCS#12 once both have been read
+fn pem_to_pkcs12(cert: &PemSource, key: &PemSource) -> Result<ClientIdentity, MonolithError> {
+    let invalid = |err| MonolithError::InvalidOptions(format!("invalid client identity: {}", err));
+    let cert = X509::from_pem(&cert.read()?).map_err(invalid)?;
+    let key = PKey::private_key_from_pem(&key.read()?).map_err(invalid)?;
+    let pkcs12 = Pkcs12::builder()
+        .name("monolith")
+        .pkey(&key)
+        .cert(&cert)
+        .build2("")
+        .and_then(|pkcs12| pkcs12.to_der())
+        .map_err(invalid)?;
+
+    Ok(ClientIdentity {
+        pkcs12,
+        password: String::new(),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub allow_file_urls: bool,
+    pub base_url: Option<String>,
+    pub capture_metadata: bool,
+    pub client_identity: Option<ClientIdentity>,
+    pub clock: SharedClock,
+    pub collapse_whitespace: bool,
+    pub dataurl_encodings: HashMap<AssetCategory, DataUrlEncoding>,
+    pub dedup_by_content: bool,
+    pub default_lang: Option<String>,
+    pub default_scheme: String,
+    pub dom_visitors: Vec<CustomVisitor>,
+    pub clients: ClientPool,
+    pub element_handlers: HashMap<String, ElementHandler>,
+    pub embed_feeds: bool,
+    pub embed_imports: bool,
+    pub embed_microdata: bool,
+    pub embed_objects: bool,
+    pub embed_prefetch: bool,
+    pub embed_workers: bool,
+    pub fetcher: Option<CustomFetcher>,
+    pub first_party_host: Option<String>,
+    pub flatten_pictures: bool,
+    pub frame_timeout: Option<u64>,
+    pub host_delay: Option<u64>,
+    pub host_overrides: HashMap<String, IpAddr>,
+    pub image_encoding: ImageEncoding,
+    pub image_quality: Option<u8>,
+    pub max_asset_size: Option<usize>,
+    pub max_frames_size: Option<usize>,
+    pub max_image_dimension: Option<u32>,
+    pub max_total_size: Option<usize>,
+    pub media_source: Option<MediaSource>,
+    pub no_autoplay: bool,
+    pub no_comments: bool,
+    pub normalize: bool,
+    pub no_images: bool,
+    pub no_js: bool,
+    pub inert_scripts: bool,
+    pub inject_csp: bool,
+    pub inline_stylesheets: bool,
+    pub policy: HashMap<AssetCategory, AssetAction>,
+    pub insecure: bool,
+    pub insecure_hosts: Vec<String>,
+    pub keep_network_hints: bool,
+    pub keep_remote: Vec<String>,
+    pub local_links: bool,
+    // Pages archived alongside this one, by their URL, and where they're saved
+    pub local_paths: HashMap<String, String>,
+    pub manifest: ManifestCollector,
+    pub pretty: bool,
+    pub record_manifest: bool,
+    pub reencode_lossless: bool,
+    pub refetch_stale: bool,
+    pub remove_images: bool,
+    pub respect_robots: bool,
+    pub robots_cache: RobotsCache,
+    pub scope: Option<String>,
+    pub self_extracting: bool,
+    pub silent: bool,
+    pub single_image: bool,
+    pub size_svg_images: bool,
+    pub source_maps: AssetAction,
+    pub strict: bool,
+    pub strip_cache_busters: bool,
+    pub strip_canonical: bool,
+    pub strip_image_metadata: bool,
+    pub strip_inactive_stylesheets: bool,
+    pub strip_meta: bool,
+    // Fallbacks for pages without scripts, kept unless the scripts are gone
+    pub strip_noscript: bool,
+    pub strip_refresh: bool,
+    pub strip_viewport: bool,
+    pub timeout: u64,
+    pub transform: Option<AssetTransform>,
+    pub user_agent: String,
+    pub verify_integrity: bool,
+    pub viewport_width: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            allow_file_urls: false,
+            base_url: None,
+            capture_metadata: false,
+            client_identity: None,
+            clock: SharedClock::default(),
+            collapse_whitespace: false,
+            dataurl_encodings: HashMap::new(),
+            dedup_by_content: false,
+            default_lang: None,
+            default_scheme: DEFAULT_SCHEME.to_string(),
+            dom_visitors: Vec::new(),
+            clients: ClientPool::default(),
+            element_handlers: built_in_element_handlers(),
+            embed_feeds: false,
+            embed_imports: false,
+            embed_microdata: false,
+            embed_objects: false,
+            embed_prefetch: false,
+            embed_workers: false,
+            fetcher: None,
+            first_party_host: None,
+            flatten_pictures: false,
+            frame_timeout: None,
+            host_delay: None,
+            host_overrides: HashMap::new(),
+            image_encoding: ImageEncoding::Jpeg,
+            image_quality: None,
+            max_asset_size: None,
+            max_frames_size: None,
+            max_image_dimension: None,
+            max_total_size: None,
+            media_source: None,
+            no_autoplay: false,
+            no_comments: false,
+            normalize: false,
+            no_images: false,
+            no_js: false,
+            inert_scripts: false,
+            inject_csp: false,
+            inline_stylesheets: false,
+            policy: HashMap::new(),
+            insecure: false,
+            insecure_hosts: Vec::new(),
+            keep_network_hints: false,
+            keep_remote: Vec::new(),
+            local_links: false,
+            local_paths: HashMap::new(),
+            manifest: ManifestCollector::default(),
+            pretty: false,
+            record_manifest: false,
+            reencode_lossless: false,
+            refetch_stale: false,
+            remove_images: false,
+            respect_robots: false,
+            robots_cache: RobotsCache::default(),
+            scope: None,
+            self_extracting: false,
+            silent: false,
+            single_image: false,
+            size_svg_images: false,
+            source_maps: AssetAction::Drop,
+            strict: false,
+            strip_cache_busters: false,
+            strip_canonical: false,
+            strip_image_metadata: false,
+            strip_inactive_stylesheets: false,
+            strip_meta: false,
+            strip_noscript: false,
+            strip_refresh: false,
+            strip_viewport: false,
+            timeout: DEFAULT_TIMEOUT,
+            transform: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            verify_integrity: false,
+            viewport_width: DEFAULT_VIEWPORT_WIDTH,
+        }
+    }
+}
+
+impl Options {
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::new()
+    }
+
+    pub fn action_for(&self, category: AssetCategory) -> AssetAction {
+        // Older flags take precedence over the policy
+        match category {
+            AssetCategory::Image if self.no_images => AssetAction::Drop,
+            // Inert scripts stay in the page, they just never get to run
+            AssetCategory::Script if self.no_js || self.inert_scripts => AssetAction::Drop,
+            _ => *self.policy.get(&category).unwrap_or(&AssetAction::Embed),
+        }
+    }
+
+    // Assets of MIME types meant to stay remote get referenced instead of embedded,
+    // those which aren't wanted at all are still left out though
+    pub fn action_for_type(&self, category: AssetCategory, mime_type: &str) -> AssetAction {
+        match self.action_for(category) {
+            AssetAction::Embed if self.keeps_remote(mime_type) => AssetAction::Keep,
+            action => action,
+        }
+    }
+
+    pub fn dataurl_encoding_for(&self, mime_type: &str) -> DataUrlEncoding {
+        *self
+            .dataurl_encodings
+            .get(&AssetCategory::from_mime(mime_type))
+            .unwrap_or(&DataUrlEncoding::Base64)
+    }
+
+    // Images which aren't wanted are normally swapped for a transparent pixel,
+    // this leaves them out of the page entirely
+    pub fn removes_images(&self) -> bool {
+        self.remove_images && self.action_for(AssetCategory::Image) == AssetAction::Drop
+    }
+
+    // Either exact types or whole families of them, e.g. "video/*"
+    pub fn keeps_remote(&self, mime_type: &str) -> bool {
+        let essence = mime_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+        !essence.is_empty()
+            && self.keep_remote.iter().any(|kept| {
+                kept == &essence
+                    || (kept.ends_with("/*") && essence.starts_with(&kept[..kept.len() - 1]))
+            })
+    }
+
+    // Whether some assets may be referenced rather than embedded on purpose
+    pub fn keeps_assets_remote(&self) -> bool {
+        self.scope.is_some()
+            || !self.keep_remote.is_empty()
+            || self
+                .policy
+                .keys()
+                .any(|&category| self.action_for(category) == AssetAction::Keep)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+    pem_identity: Option<(PemSource, PemSource)>,
+}
+
+impl OptionsBuilder {
+    pub fn new() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    pub fn allow_file_urls(mut self, allow_file_urls: bool) -> OptionsBuilder {
+        self.options.allow_file_urls = allow_file_urls;
+        self
+    }
+
+    pub fn base_url(mut self, base_url: &str) -> OptionsBuilder {
+        self.options.base_url = Some(base_url.to_string());
+        self
+    }
+
+    pub fn capture_metadata(mut self, capture_metadata: bool) -> OptionsBuilder {
+        self.options.capture_metadata = capture_metadata;
+        self
+    }
+
+    pub fn client_identity(mut self, pkcs12: &[u8], password: &str) -> OptionsBuilder {
+        self.options.client_identity = Some(ClientIdentity {
+            pkcs12: pkcs12.to_vec(),
+            password: password.to_string(),
+        });
+        self.pem_identity = None;
+        self
+    }
+
+    pub fn client_identity_pem(mut self, cert: &[u8], key: &[u8]) -> OptionsBuilder {
+        self.pem_identity = Some((
+            PemSource::Bytes(cert.to_vec()),
+            PemSource::Bytes(key.to_vec()),
+        ));
+        self
+    }
+
+    pub fn client_identity_pem_files<P, Q>(mut self, cert_path: P, key_path: Q) -> OptionsBuilder
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        self.pem_identity = Some((
+            PemSource::File(cert_path.into()),
+            PemSource::File(key_path.into()),
+        ));
+        self
+    }
+
+    pub fn clock<C>(mut self, clock: C) -> OptionsBuilder
+    where
+        C: Clock + 'static,
+    {
+        self.options.clock = SharedClock::new(clock);
+        self
+    }
+
+    pub fn collapse_whitespace(mut self, collapse_whitespace: bool) -> OptionsBuilder {
+        self.options.collapse_whitespace = collapse_whitespace;
+        self
+    }
+
+    pub fn dataurl_encoding(
+        mut self,
+        category: AssetCategory,
+        encoding: DataUrlEncoding,
+    ) -> OptionsBuilder {
+        self.options.dataurl_encodings.insert(category, encoding);
+        self
+    }
+
+    pub fn dedup_by_content(mut self, dedup_by_content: bool) -> OptionsBuilder {
+        self.options.dedup_by_content = dedup_by_content;
+        self
+    }
+
+    pub fn default_lang(mut self, default_lang: &str) -> OptionsBuilder {
+        self.options.default_lang = Some(default_lang.to_string());
+        self
+    }
+
+    pub fn default_scheme(mut self, default_scheme: &str) -> OptionsBuilder {
+        self.options.default_scheme = default_scheme.to_lowercase();
+        self
+    }
+
+    pub fn dom_visitor<V>(mut self, visitor: V) -> OptionsBuilder
+    where
+        V: DomVisitor + 'static,
+    {
+        self.options.dom_visitors.push(CustomVisitor::new(visitor));
+        self
+    }
+
+    pub fn element_handler<F>(mut self, element_name: &str, handler: F) -> OptionsBuilder
+    where
+        F: FnMut(&Handle, &mut Context) + Send + 'static,
+    {
+        self.options
+            .element_handlers
+            .insert(element_name.to_lowercase(), ElementHandler::new(handler));
+        self
+    }
+
+    pub fn embed_feeds(mut self, embed_feeds: bool) -> OptionsBuilder {
+        self.options.embed_feeds = embed_feeds;
+        self
+    }
+
+    pub fn embed_imports(mut self, embed_imports: bool) -> OptionsBuilder {
+        self.options.embed_imports = embed_imports;
+        self
+    }
+
+    pub fn embed_microdata(mut self, embed_microdata: bool) -> OptionsBuilder {
+        self.options.embed_microdata = embed_microdata;
+        self
+    }
+
+    pub fn embed_objects(mut self, embed_objects: bool) -> OptionsBuilder {
+        self.options.embed_objects = embed_objects;
+        self
+    }
+
+    pub fn embed_prefetch(mut self, embed_prefetch: bool) -> OptionsBuilder {
+        self.options.embed_prefetch = embed_prefetch;
+        self
+    }
+
+    pub fn embed_workers(mut self, embed_workers: bool) -> OptionsBuilder {
+        self.options.embed_workers = embed_workers;
+        self
+    }
+
+    pub fn fetcher<F>(mut self, fetcher: F) -> OptionsBuilder
+    where
+        F: AssetFetcher + 'static,
+    {
+        self.options.fetcher = Some(CustomFetcher::new(fetcher));
+        self
+    }
+
+    pub fn first_party_host(mut self, host: &str) -> OptionsBuilder {
+        self.options.first_party_host = Some(host.to_lowercase());
+        self
+    }
+
+    pub fn flatten_pictures(mut self, flatten_pictures: bool) -> OptionsBuilder {
+        self.options.flatten_pictures = flatten_pictures;
+        self
+    }
+
+    pub fn frame_timeout(mut self, frame_timeout: u64) -> OptionsBuilder {
+        self.options.frame_timeout = Some(frame_timeout);
+        self
+    }
+
+    pub fn host_delay(mut self, host_delay: u64) -> OptionsBuilder {
+        self.options.host_delay = Some(host_delay);
+        self
+    }
+
+    pub fn host_override(mut self, host: &str, ip: IpAddr) -> OptionsBuilder {
+        self.options.host_overrides.insert(host.to_lowercase(), ip);
+        self
+    }
+
+    pub fn image_encoding(mut self, image_encoding: ImageEncoding) -> OptionsBuilder {
+        self.options.image_encoding = image_encoding;
+        self
+    }
+
+    pub fn image_quality(mut self, image_quality: u8) -> OptionsBuilder {
+        self.options.image_quality = Some(image_quality);
+        self
+    }
+
+    pub fn max_asset_size(mut self, max_asset_size: usize) -> OptionsBuilder {
+        self.options.max_asset_size = Some(max_asset_size);
+        self
+    }
+
+    pub fn max_frames_size(mut self, max_frames_size: usize) -> OptionsBuilder {
+        self.options.max_frames_size = Some(max_frames_size);
+        self
+    }
+
+    pub fn max_image_dimension(mut self, max_image_dimension: u32) -> OptionsBuilder {
+        self.options.max_image_dimension = Some(max_image_dimension);
+        self
+    }
+
+    pub fn max_total_size(mut self, max_total_size: usize) -> OptionsBuilder {
+        self.options.max_total_size = Some(max_total_size);
+        self
+    }
+
+    pub fn media_source(mut self, media_source: MediaSource) -> OptionsBuilder {
+        self.options.media_source = Some(media_source);
+        self
+    }
+
+    pub fn no_autoplay(mut self, no_autoplay: bool) -> OptionsBuilder {
+        self.options.no_autoplay = no_autoplay;
+        self
+    }
+
+    pub fn no_comments(mut self, no_comments: bool) -> OptionsBuilder {
+        self.options.no_comments = no_comments;
+        self
+    }
+
+    pub fn normalize(mut self, normalize: bool) -> OptionsBuilder {
+        self.options.normalize = normalize;
+        self
+    }
+
+    pub fn no_images(mut self, no_images: bool) -> OptionsBuilder {
+        self.options.no_images = no_images;
+        self
+    }
+
+    pub fn no_js(mut self, no_js: bool) -> OptionsBuilder {
+        self.options.no_js = no_js;
+        self
+    }
+
+    pub fn inert_scripts(mut self, inert_scripts: bool) -> OptionsBuilder {
+        self.options.inert_scripts = inert_scripts;
+        self
+    }
+
+    pub fn inject_csp(mut self, inject_csp: bool) -> OptionsBuilder {
+        self.options.inject_csp = inject_csp;
+        self
+    }
+
+    pub fn inline_stylesheets(mut self, inline_stylesheets: bool) -> OptionsBuilder {
+        self.options.inline_stylesheets = inline_stylesheets;
+        self
+    }
+
+    pub fn policy(mut self, category: AssetCategory, action: AssetAction) -> OptionsBuilder {
+        self.options.policy.insert(category, action);
+        self
+    }
+
+    pub fn insecure(mut self, insecure: bool) -> OptionsBuilder {
+        self.options.insecure = insecure;
+        self
+    }
+
+    pub fn insecure_host(mut self, host: &str) -> OptionsBuilder {
+        self.options.insecure_hosts.push(host.to_lowercase());
+        self
+    }
+
+    pub fn keep_network_hints(mut self, keep_network_hints: bool) -> OptionsBuilder {
+        self.options.keep_network_hints = keep_network_hints;
+        self
+    }
+
+    pub fn keep_remote(mut self, mime_type: &str) -> OptionsBuilder {
+        self.options.keep_remote.push(mime_type.trim().to_lowercase());
+        self
+    }
+
+    pub fn local_links(mut self, local_links: bool) -> OptionsBuilder {
+        self.options.local_links = local_links;
+        self
+    }
+
+    pub fn local_path(mut self, url: &str, path: &str) -> OptionsBuilder {
+        // Links may well point at a different part of the page, it's the same file still
+        let url = match Url::parse(url) {
+            Ok(mut parsed_url) => {
+                parsed_url.set_fragment(None);
+                parsed_url.to_string()
+            }
+            Err(_) => url.to_string(),
+        };
+        self.options.local_paths.insert(url, path.to_string());
+        self
+    }
+
+    pub fn pretty(mut self, pretty: bool) -> OptionsBuilder {
+        self.options.pretty = pretty;
+        self
+    }
+
+    pub fn record_manifest(mut self, record_manifest: bool) -> OptionsBuilder {
+        self.options.record_manifest = record_manifest;
+        self
+    }
+
+    pub fn reencode_lossless(mut self, reencode_lossless: bool) -> OptionsBuilder {
+        self.options.reencode_lossless = reencode_lossless;
+        self
+    }
+
+    pub fn refetch_stale(mut self, refetch_stale: bool) -> OptionsBuilder {
+        self.options.refetch_stale = refetch_stale;
+        self
+    }
+
+    pub fn remove_images(mut self, remove_images: bool) -> OptionsBuilder {
+        self.options.remove_images = remove_images;
+        self
+    }
+
+    pub fn respect_robots(mut self, respect_robots: bool) -> OptionsBuilder {
+        self.options.respect_robots = respect_robots;
+        self
+    }
+
+    pub fn scope(mut self, scope: &str) -> OptionsBuilder {
+        self.options.scope = Some(scope.to_string());
+        self
+    }
+
+    pub fn self_extracting(mut self, self_extracting: bool) -> OptionsBuilder {
+        self.options.self_extracting = self_extracting;
+        self
+    }
+
+    pub fn silent(mut self, silent: bool) -> OptionsBuilder {
+        self.options.silent = silent;
+        self
+    }
+
+    pub fn single_image(mut self, single_image: bool) -> OptionsBuilder {
+        self.options.single_image = single_image;
+        self
+    }
+
+    pub fn size_svg_images(mut self, size_svg_images: bool) -> OptionsBuilder {
+        self.options.size_svg_images = size_svg_images;
+        self
+    }
+
+    pub fn source_maps(mut self, source_maps: AssetAction) -> OptionsBuilder {
+        self.options.source_maps = source_maps;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> OptionsBuilder {
+        self.options.strict = strict;
+        self
+    }
+
+    pub fn strip_cache_busters(mut self, strip_cache_busters: bool) -> OptionsBuilder {
+        self.options.strip_cache_busters = strip_cache_busters;
+        self
+    }
+
+    pub fn strip_canonical(mut self, strip_canonical: bool) -> OptionsBuilder {
+        self.options.strip_canonical = strip_canonical;
+        self
+    }
+
+    pub fn strip_image_metadata(mut self, strip_image_metadata: bool) -> OptionsBuilder {
+        self.options.strip_image_metadata = strip_image_metadata;
+        self
+    }
+
+    pub fn strip_inactive_stylesheets(
+        mut self,
+        strip_inactive_stylesheets: bool,
+    ) -> OptionsBuilder {
+        self.options.strip_inactive_stylesheets = strip_inactive_stylesheets;
+        self
+    }
+
+    pub fn strip_meta(mut self, strip_meta: bool) -> OptionsBuilder {
+        self.options.strip_meta = strip_meta;
+        self
+    }
+
+    pub fn strip_noscript(mut self, strip_noscript: bool) -> OptionsBuilder {
+        self.options.strip_noscript = strip_noscript;
+        self
+    }
+
+    pub fn strip_refresh(mut self, strip_refresh: bool) -> OptionsBuilder {
+        self.options.strip_refresh = strip_refresh;
+        self
+    }
+
+    pub fn strip_viewport(mut self, strip_viewport: bool) -> OptionsBuilder {
+        self.options.strip_viewport = strip_viewport;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u64) -> OptionsBuilder {
+        self.options.timeout = timeout;
+        self
+    }
+
+    pub fn transform<F>(mut self, transform: F) -> OptionsBuilder
+    where
+        F: FnMut(&str, Vec<u8>) -> (String, Vec<u8>) + Send + 'static,
+    {
+        self.options.transform = Some(AssetTransform::new(transform));
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> OptionsBuilder {
+        self.options.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn verify_integrity(mut self, verify_integrity: bool) -> OptionsBuilder {
+        self.options.verify_integrity = verify_integrity;
+        self
+    }
+
+    pub fn viewport_width(mut self, viewport_width: u32) -> OptionsBuilder {
+        self.options.viewport_width = viewport_width;
+        self
+    }
+
+    pub fn build(mut self) -> Result<Options, MonolithError> {
+        if let Some((ref cert, ref key)) = self.pem_identity {
+            self.options.client_identity = Some(pem_to_pkcs12(cert, key)?);
+        }
+
+        if let Some(ref base_url) = self.options.base_url {
+            if Url::parse(base_url).is_err() {
+                return Err(MonolithError::InvalidOptions(format!(
+                    "base URL {} is not an absolute URL",
+                    base_url
+                )));
+            }
+        }
+
+        for url in self.options.local_paths.keys() {
+            if Url::parse(url).is_err() {
+                return Err(MonolithError::InvalidOptions(format!(
+                    "{} has a local path but is not an absolute URL",
+                    url
+                )));
+            }
+        }
+
+        if let Some(ref scope) = self.options.scope {
+            if !scope.starts_with('/') && Url::parse(scope).is_err() {
+                return Err(MonolithError::InvalidOptions(format!(
+                    "scope {} is neither an absolute URL nor a path",
+                    scope
+                )));
+            }
+        }
+
+        let scheme = &self.options.default_scheme;
+        if !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        {
+            return Err(MonolithError::InvalidOptions(format!(
+                "{:?} is not a valid URL scheme",
+                scheme
+            )));
+        }
+
+        if self.options.timeout == 0 {
+            return Err(MonolithError::InvalidOptions(
+                "timeout must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.options.frame_timeout == Some(0) {
+            return Err(MonolithError::InvalidOptions(
+                "frame timeout must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.options.viewport_width == 0 {
+            return Err(MonolithError::InvalidOptions(
+                "viewport width must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.options.max_image_dimension == Some(0) {
+            return Err(MonolithError::InvalidOptions(
+                "max image dimension must be greater than zero".to_string(),
+            ));
+        }
+
+        if let Some(image_quality) = self.options.image_quality {
+            if image_quality == 0 || image_quality > 100 {
+                return Err(MonolithError::InvalidOptions(
+                    "image quality must be between 1 and 100".to_string(),
+                ));
+            }
+        }
+
+        Ok(self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_builder() {
+        let opts = Options::builder()
+            .no_js(true)
+            .no_images(true)
+            .silent(true)
+            .timeout(30)
+            .user_agent("Iceweasel")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            opts,
+            Options {
+                no_images: true,
+                no_js: true,
+                silent: true,
+                timeout: 30,
+                user_agent: "Iceweasel".to_string(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_options_builder_defaults() {
+        assert_eq!(OptionsBuilder::new().build().unwrap(), Options::default());
+    }
+
+    #[test]
+    fn test_options_debug_client_identity() {
+        let opts = Options::builder()
+            .client_identity(b"\x30\x82secret", "hunter2")
+            .build()
+            .unwrap();
+        let debug = format!("{:?}", opts);
+
+        assert!(debug.contains("client_identity: Some(ClientIdentity)"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_options_builder_transform() {
+        let opts = Options::builder()
+            .transform(|_, data| ("text/plain".to_string(), data))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            opts.transform.unwrap().apply("text/css", b"body {}".to_vec()),
+            ("text/plain".to_string(), b"body {}".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_options_builder_validation() {
+        assert_eq!(
+            Options::builder().timeout(0).build(),
+            Err(MonolithError::InvalidOptions(
+                "timeout must be greater than zero".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().frame_timeout(0).build(),
+            Err(MonolithError::InvalidOptions(
+                "frame timeout must be greater than zero".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().viewport_width(0).build(),
+            Err(MonolithError::InvalidOptions(
+                "viewport width must be greater than zero".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().max_image_dimension(0).build(),
+            Err(MonolithError::InvalidOptions(
+                "max image dimension must be greater than zero".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().base_url("/mirror/").build(),
+            Err(MonolithError::InvalidOptions(
+                "base URL /mirror/ is not an absolute URL".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().scope("docs/").build(),
+            Err(MonolithError::InvalidOptions(
+                "scope docs/ is neither an absolute URL nor a path".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().default_scheme("https://").build(),
+            Err(MonolithError::InvalidOptions(
+                "\"https://\" is not a valid URL scheme".to_string()
+            ))
+        );
+        assert_eq!(
+            Options::builder().image_quality(101).build(),
+            Err(MonolithError::InvalidOptions(
+                "image quality must be between 1 and 100".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_options_action_for() {
+        let opts = Options::builder()
+            .policy(AssetCategory::Font, AssetAction::Keep)
+            .policy(AssetCategory::Media, AssetAction::Drop)
+            .no_js(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(opts.action_for(AssetCategory::Image), AssetAction::Embed);
+        assert_eq!(opts.action_for(AssetCategory::Font), AssetAction::Keep);
+        assert_eq!(opts.action_for(AssetCategory::Media), AssetAction::Drop);
+        assert_eq!(opts.action_for(AssetCategory::Script), AssetAction::Drop);
+    }
+
+    #[test]
+    fn test_options_action_for_type() {
+        let opts = Options::builder()
+            .keep_remote("video/*")
+            .keep_remote("Image/GIF")
+            .policy(AssetCategory::Media, AssetAction::Drop)
+            .build()
+            .unwrap();
+
+        assert_eq!(opts.action_for_type(AssetCategory::Image, "image/gif"), AssetAction::Keep);
+        assert_eq!(opts.action_for_type(AssetCategory::Image, "image/png"), AssetAction::Embed);
+        assert_eq!(
+            opts.action_for_type(AssetCategory::Other, "video/webm; codecs=vp9"),
+            AssetAction::Keep
+        );
+        assert_eq!(opts.action_for_type(AssetCategory::Media, "video/mp4"), AssetAction::Drop);
+        assert_eq!(opts.action_for_type(AssetCategory::Image, ""), AssetAction::Embed);
+    }
+
+    #[test]
+    fn test_asset_category_from_url() {
+        assert_eq!(AssetCategory::from_url("http://site.com/a.PNG?v=2"), AssetCategory::Image);
+        assert_eq!(AssetCategory::from_url("fonts/icons.woff2#iefix"), AssetCategory::Font);
+        assert_eq!(AssetCategory::from_url("app.js"), AssetCategory::Script);
+        assert_eq!(AssetCategory::from_url("http://site.com/"), AssetCategory::Other);
+    }
+
+    #[test]
+    fn test_asset_category_from_mime() {
+        assert_eq!(AssetCategory::from_mime("image/svg+xml"), AssetCategory::Image);
+        assert_eq!(AssetCategory::from_mime("Text/CSS; charset=utf-8"), AssetCategory::Style);
+        assert_eq!(AssetCategory::from_mime("text/javascript"), AssetCategory::Script);
+        assert_eq!(AssetCategory::from_mime("font/woff2"), AssetCategory::Font);
+        assert_eq!(AssetCategory::from_mime("video/webm"), AssetCategory::Media);
+        assert_eq!(AssetCategory::from_mime("text/html"), AssetCategory::Other);
+    }
+}
@@ -0,0 +1,111 @@
+use error::MonolithError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Next to the destination so that renaming it into place never crosses filesystems,
+// named after the process so that runs writing to the same place don't collide
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.{}.part", file_name, process::id()))
+}
+
+fn remove_path(path: &Path) {
+    if path.is_dir() {
+        fs::remove_dir_all(path).ok();
+    } else if path.exists() {
+        fs::remove_file(path).ok();
+    }
+}
+
+// Hands a temporary path to whatever writes out the archive and moves it into place
+// only once that's done, replacing whatever file was there in one go; archives which
+// fail halfway through get cleaned up instead of being left behind incomplete
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<(), MonolithError>
+where
+    F: FnOnce(&Path) -> Result<(), MonolithError>,
+{
+    // Directories are never replaced, let alone removed along with everything in them
+    if path.is_dir() {
+        return Err(MonolithError::WriteError(format!("{} is a directory", path.display())));
+    }
+
+    let temp_path = temp_path(path);
+
+    // Leftovers of a previous run which got killed before it could clean up
+    remove_path(&temp_path);
+
+    let result = write(&temp_path).and_then(|_| {
+        fs::rename(&temp_path, path).map_err(|err| MonolithError::WriteError(err.to_string()))
+    });
+
+    if result.is_err() {
+        remove_path(&temp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = env::temp_dir().join(format!("monolith-test-write-atomically-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("page_files");
+
+        let result = write_atomically(&path, |temp_path| {
+            fs::create_dir(temp_path).unwrap();
+            fs::write(temp_path.join("style.css"), "body {}").unwrap();
+            Err(MonolithError::FetchError {
+                url: "http://localhost/logo.png".to_string(),
+                reason: "server responded with 404 Not Found".to_string(),
+            })
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let result = write_atomically(&path, |temp_path| {
+            fs::create_dir(temp_path).unwrap();
+            fs::write(temp_path.join("style.css"), "body {}").unwrap();
+            Ok(())
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read_to_string(path.join("style.css")).unwrap(), "body {}");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Existing directories are left as they are
+        let result = write_atomically(&path, |temp_path| {
+            fs::write(temp_path, "<html></html>").unwrap();
+            Ok(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(path.join("style.css")).unwrap(), "body {}");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Existing files get replaced
+        let path = dir.join("page.html");
+        fs::write(&path, "<html>old</html>").unwrap();
+
+        let result = write_atomically(&path, |temp_path| {
+            fs::write(temp_path, "<html>new</html>").unwrap();
+            Ok(())
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "<html>new</html>");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use html5ever::rcdom::Handle;
+use html5ever::serialize::{AttrRef, HtmlSerializer, Serialize, SerializeOpts, Serializer};
+use html5ever::QualName;
+use std::io::{self, Write};
+
+const INDENT: &str = "  ";
+
+const VOID_ELEMENTS: [&str; 18] = [
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+// Whitespace is significant within these, their contents are written out verbatim
+pub const PREFORMATTED_ELEMENTS: [&str; 5] = ["pre", "script", "style", "textarea", "xmp"];
+
+struct OpenElement {
+    is_void: bool,
+    has_children: bool,
+}
+
+// Puts every node on a line of its own, indented according to its depth
+struct PrettySerializer<W: Write> {
+    inner: HtmlSerializer<W>,
+    stack: Vec<OpenElement>,
+    preformatted: usize,
+    started: bool,
+}
+
+impl<W: Write> PrettySerializer<W> {
+    fn new_line(&mut self) -> io::Result<()> {
+        if let Some(parent) = self.stack.last_mut() {
+            parent.has_children = true;
+        }
+
+        if self.started {
+            self.break_line()?;
+        }
+        self.started = true;
+
+        Ok(())
+    }
+
+    fn break_line(&mut self) -> io::Result<()> {
+        self.inner.writer.write_all(b"\n")?;
+        for _ in 0..self.stack.len() {
+            self.inner.writer.write_all(INDENT.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Serializer for PrettySerializer<W> {
+    fn start_elem<'a, AttrIter>(&mut self, name: QualName, attrs: AttrIter) -> io::Result<()>
+    where
+        AttrIter: Iterator<Item = AttrRef<'a>>,
+    {
+        let is_void = VOID_ELEMENTS.contains(&name.local.as_ref());
+
+        if self.preformatted == 0 {
+            self.new_line()?;
+        }
+
+        if !is_void && PREFORMATTED_ELEMENTS.contains(&name.local.as_ref()) {
+            self.preformatted += 1;
+        }
+
+        self.stack.push(OpenElement {
+            is_void,
+            has_children: false,
+        });
+        self.inner.start_elem(name, attrs)
+    }
+
+    fn end_elem(&mut self, name: QualName) -> io::Result<()> {
+        let element = self.stack.pop().unwrap_or(OpenElement {
+            is_void: false,
+            has_children: false,
+        });
+
+        if !element.is_void && PREFORMATTED_ELEMENTS.contains(&name.local.as_ref()) {
+            self.preformatted -= 1;
+        } else if self.preformatted == 0 && element.has_children {
+            // Closing tags line up with their opening counterparts
+            self.break_line()?;
+        }
+
+        self.inner.end_elem(name)
+    }
+
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        if self.preformatted > 0 {
+            return self.inner.write_text(text);
+        }
+
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        self.new_line()?;
+        self.inner.write_text(text)
+    }
+
+    fn write_comment(&mut self, text: &str) -> io::Result<()> {
+        if self.preformatted == 0 {
+            self.new_line()?;
+        }
+
+        self.inner.write_comment(text)
+    }
+
+    fn write_doctype(&mut self, name: &str) -> io::Result<()> {
+        self.new_line()?;
+        self.inner.write_doctype(name)
+    }
+
+    fn write_processing_instruction(&mut self, target: &str, data: &str) -> io::Result<()> {
+        self.new_line()?;
+        self.inner.write_processing_instruction(target, data)
+    }
+}
+
+pub fn serialize_pretty<W: Write>(writer: W, handle: &Handle) -> io::Result<()> {
+    let opts = SerializeOpts::default();
+    let mut serializer = PrettySerializer {
+        inner: HtmlSerializer::new(writer, opts.clone()),
+        stack: Vec::new(),
+        preformatted: 0,
+        started: false,
+    };
+
+    handle.serialize(&mut serializer, opts.traversal_scope)
+}
@@ -0,0 +1,268 @@
+use http::retrieve_asset;
+use opts::Options;
+use regex::{escape, Regex};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RobotsRules {
+    // (pattern, is_allowed)
+    rules: Vec<(String, bool)>,
+}
+
+// User agents listed together, along with the rules that follow them
+#[derive(Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<(String, bool)>,
+}
+
+impl RobotsRules {
+    pub fn parse(robots_txt: &str, user_agent: &str) -> RobotsRules {
+        let user_agent = user_agent.to_lowercase();
+        let mut groups: Vec<Group> = Vec::new();
+        let mut reading_agents = false;
+
+        for line in robots_txt.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut parts = line.splitn(2, ':');
+            let field = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = parts.next().unwrap_or("").trim().to_string();
+
+            match field.as_str() {
+                "user-agent" => {
+                    // Consecutive User-agent lines share the rules that follow them
+                    if !reading_agents {
+                        groups.push(Group::default());
+                    }
+                    reading_agents = true;
+                    groups.last_mut().unwrap().agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    reading_agents = false;
+
+                    if let Some(group) = groups.last_mut() {
+                        // An empty Disallow means everything is allowed
+                        if !value.is_empty() {
+                            group.rules.push((value, field == "allow"));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // The most specific group naming this user agent wins, "*" is the fallback
+        let mut best_match: Option<(usize, &Vec<(String, bool)>)> = None;
+
+        for group in groups.iter() {
+            for agent in group.agents.iter() {
+                let specificity = if agent == "*" {
+                    0
+                } else if user_agent.contains(agent.as_str()) {
+                    agent.len()
+                } else {
+                    continue;
+                };
+
+                if best_match.map(|(best, _)| specificity > best).unwrap_or(true) {
+                    best_match = Some((specificity, &group.rules));
+                }
+            }
+        }
+
+        RobotsRules {
+            rules: best_match.map(|(_, rules)| rules.clone()).unwrap_or_default(),
+        }
+    }
+
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let mut verdict: Option<(usize, bool)> = None;
+
+        // The longest matching pattern decides, Allow taking precedence on ties
+        for (pattern, is_allowed) in self.rules.iter() {
+            if !matches_pattern(pattern, path) {
+                continue;
+            }
+
+            verdict = match verdict {
+                Some((length, allowed)) if length > pattern.len() => Some((length, allowed)),
+                Some((length, true)) if length == pattern.len() => Some((length, true)),
+                _ => Some((pattern.len(), *is_allowed)),
+            };
+        }
+
+        verdict.map(|(_, allowed)| allowed).unwrap_or(true)
+    }
+}
+
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let anchored = pattern.ends_with('$');
+    let pattern = pattern.trim_end_matches('$');
+    let regex = format!(
+        "^{}{}",
+        escape(pattern).replace(r"\*", ".*"),
+        if anchored { "$" } else { "" }
+    );
+
+    Regex::new(&regex)
+        .map(|regex| regex.is_match(path))
+        .unwrap_or(false)
+}
+
+// Rules get fetched once per origin and shared by everything retrieved from it, as well as by
+// every clone of the options; they're kept apart for each user agent they were read for
+#[derive(Clone, Default)]
+pub struct RobotsCache(Arc<Mutex<HashMap<(String, String), RobotsRules>>>);
+
+impl RobotsCache {
+    fn get(&self, origin: &str, user_agent: &str) -> Option<RobotsRules> {
+        let key = (origin.to_string(), user_agent.to_string());
+        self.0.lock().unwrap().get(&key).cloned()
+    }
+
+    fn insert(&self, origin: &str, user_agent: &str, rules: RobotsRules) {
+        let key = (origin.to_string(), user_agent.to_string());
+        self.0.lock().unwrap().insert(key, rules);
+    }
+}
+
+impl fmt::Debug for RobotsCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RobotsCache")
+    }
+}
+
+// Same as ClientPool, a cache makes no difference to what the options are
+impl PartialEq for RobotsCache {
+    fn eq(&self, _other: &RobotsCache) -> bool {
+        true
+    }
+}
+
+pub fn is_allowed_by_robots(url: &str, opts: &Options) -> bool {
+    let parsed_url = match Url::parse(url) {
+        Ok(parsed_url) => parsed_url,
+        Err(_) => return true,
+    };
+    let origin = parsed_url.origin().ascii_serialization();
+
+    // robots.txt only concerns HTTP(S), and it can't very well disallow itself
+    if !parsed_url.scheme().starts_with("http") || parsed_url.path() == "/robots.txt" {
+        return true;
+    }
+
+    let cached_rules = opts.robots_cache.get(&origin, &opts.user_agent);
+    let rules = match cached_rules {
+        Some(rules) => rules,
+        None => {
+            // Missing robots.txt is no failure of the page's own,
+            // and it's the whole host it has a say over, scoped or not
+            let robots_opts = Options {
+                record_manifest: false,
+                respect_robots: false,
+                scope: None,
+                ..opts.clone()
+            };
+            // Hosts without robots.txt allow everything
+            let rules = retrieve_asset(
+                &format!("{}/robots.txt", origin),
+                false,
+                "text/plain",
+                &robots_opts,
+            )
+            .map(|robots_txt| RobotsRules::parse(&robots_txt, &opts.user_agent))
+            .unwrap_or_default();
+            opts.robots_cache.insert(&origin, &opts.user_agent, rules.clone());
+            rules
+        }
+    };
+
+    let path = match parsed_url.query() {
+        Some(query) => format!("{}?{}", parsed_url.path(), query),
+        None => parsed_url.path().to_string(),
+    };
+
+    rules.is_allowed(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{response, serve};
+
+    #[test]
+    fn test_robots_rules() {
+        let robots_txt = "User-agent: *\n\
+                          Disallow: /private/\n\
+                          Allow: /private/public.png\n\
+                          Disallow: /*.js$\n\
+                          \n\
+                          User-agent: Iceweasel\n\
+                          User-agent: Otherbot\n\
+                          Disallow: /\n";
+        let rules = RobotsRules::parse(robots_txt, "Mozilla/5.0 Firefox/66.0");
+
+        assert!(rules.is_allowed("/index.html"));
+        assert!(!rules.is_allowed("/private/secret.png"));
+        assert!(rules.is_allowed("/private/public.png"));
+        assert!(!rules.is_allowed("/js/app.js"));
+        assert!(rules.is_allowed("/js/app.js?v=2"));
+
+        let rules = RobotsRules::parse(robots_txt, "Iceweasel/1.0");
+
+        assert!(!rules.is_allowed("/index.html"));
+    }
+
+    #[test]
+    fn test_is_allowed_by_robots() {
+        let base_url = serve(vec![(
+            "/robots.txt",
+            response("text/plain", b"User-agent: *\nDisallow: /private/\n"),
+        )]);
+        let opts = Options {
+            respect_robots: true,
+            silent: true,
+            ..Default::default()
+        };
+
+        assert!(is_allowed_by_robots(&format!("{}/style.css", base_url), &opts));
+        assert!(!is_allowed_by_robots(&format!("{}/private/logo.png", base_url), &opts));
+        assert!(is_allowed_by_robots("data:text/plain,hello", &opts));
+
+        // The scope limits assets, not where their rules are read from
+        let opts_scope = Options {
+            respect_robots: true,
+            scope: Some("/private/".to_string()),
+            silent: true,
+            ..Default::default()
+        };
+
+        assert!(!is_allowed_by_robots(&format!("{}/private/logo.png", base_url), &opts_scope));
+    }
+
+    #[test]
+    fn test_is_allowed_by_robots_user_agents() {
+        let base_url = serve(vec![(
+            "/robots.txt",
+            response("text/plain", b"User-agent: Otherbot\nDisallow: /private/\n"),
+        )]);
+        let url = format!("{}/private/logo.png", base_url);
+        let opts = Options {
+            respect_robots: true,
+            silent: true,
+            ..Default::default()
+        };
+        let opts_otherbot = Options {
+            user_agent: "Otherbot/1.0".to_string(),
+            ..opts.clone()
+        };
+
+        // Rules read for one user agent don't get applied to another
+        assert!(is_allowed_by_robots(&url, &opts));
+        assert!(!is_allowed_by_robots(&url, &opts_otherbot));
+        assert!(is_allowed_by_robots(&url, &opts));
+    }
+}
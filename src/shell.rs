@@ -0,0 +1,34 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Write};
+use utils::data_to_dataurl;
+
+// Browsers fetch data URLs no matter where the page was opened from,
+// which leaves inflating the document to the streams API
+const INFLATE_SCRIPT: &str = "fetch(\"{document}\").then(function (response) {\n\
+     var stream = response.body.pipeThrough(new DecompressionStream(\"gzip\"));\n\
+     return new Response(stream).text();\n\
+     }).then(function (html) {\n\
+     document.open();\n\
+     document.write(html);\n\
+     document.close();\n\
+     });";
+
+// Gzips the document and puts it into a page which writes it back out once opened
+pub fn write_self_extracting<W: Write>(document: &[u8], mut writer: W) -> io::Result<()> {
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+    gzip.write_all(document)?;
+    let compressed = gzip.finish()?;
+
+    let script = INFLATE_SCRIPT.replace(
+        "{document}",
+        &data_to_dataurl("application/gzip", &compressed),
+    );
+
+    write!(
+        writer,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"></head>\
+         <body><script>\n{}\n</script></body></html>",
+        script
+    )
+}
@@ -0,0 +1,123 @@
+use error::MonolithError;
+use html::for_each_element;
+use html5ever::rcdom::{Handle, NodeData};
+use opts::AssetCategory;
+use regex::Regex;
+use std::collections::HashMap;
+use utils::TRANSPARENT_PIXEL;
+
+lazy_static! {
+    // Embedded data URLs are either base64 or percent-encoded, with whitespace, quotes
+    // and parens escaped, see percent_encode(); either way those mark where they end
+    static ref DATA_URL: Regex =
+        Regex::new(r#"(?i)data:(?P<mime>[^;,\s"')]*)[^\s"')]*"#).unwrap();
+}
+
+// What the archive is made of, to tell what it is that makes it so large
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArchiveStats {
+    pub total_size: usize,
+    // Bytes taken up by data URLs of each kind, assets embedded into those included
+    pub category_sizes: HashMap<AssetCategory, usize>,
+    pub embedded: usize,
+    pub failed: usize,
+    pub first_failure: Option<MonolithError>,
+}
+
+impl ArchiveStats {
+    pub fn category_size(&self, category: AssetCategory) -> usize {
+        *self.category_sizes.get(&category).unwrap_or(&0)
+    }
+
+    pub fn record_failure(&mut self, err: &MonolithError) {
+        self.failed += 1;
+
+        if self.first_failure.is_none() {
+            self.first_failure = Some(err.clone());
+        }
+    }
+
+    fn record_embedded(&mut self, mime: &str, size: usize) {
+        self.embedded += 1;
+        *self
+            .category_sizes
+            .entry(AssetCategory::from_mime(mime))
+            .or_insert(0) += size;
+    }
+}
+
+// Counts data URLs the way they ended up in the document, placeholders of
+// images which couldn't be retrieved aside; scripts are only ever looked at
+// through their src, their code may well mention data URLs without using any
+pub fn count_embedded_assets(node: &Handle, stats: &mut ArchiveStats) {
+    for_each_element(node, |node| {
+        for_each_data_url(node, |mime, size| stats.record_embedded(mime, size));
+    });
+}
+
+// Same as what count_embedded_assets() makes of the element, its descendants aside
+pub fn embedded_size(node: &Handle) -> usize {
+    let mut embedded_size = 0;
+
+    for_each_data_url(node, |_, size| embedded_size += size);
+
+    embedded_size
+}
+
+fn for_each_data_url<F: FnMut(&str, usize)>(node: &Handle, mut f: F) {
+    if let NodeData::Element { ref name, ref attrs, .. } = node.data {
+        for attr in attrs.borrow().iter() {
+            scan_data_urls(&attr.value, &mut f);
+        }
+
+        if name.local.as_ref() == "style" {
+            for child in node.children.borrow().iter() {
+                if let NodeData::Text { ref contents } = child.data {
+                    scan_data_urls(&contents.borrow(), &mut f);
+                }
+            }
+        }
+    }
+}
+
+fn scan_data_urls<F: FnMut(&str, usize)>(text: &str, f: &mut F) {
+    for caps in DATA_URL.captures_iter(text) {
+        if &caps[0] != TRANSPARENT_PIXEL {
+            f(&caps["mime"], caps[0].len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html::html_to_dom;
+    use utils::{data_to_dataurl_with, DataUrlEncoding};
+
+    #[test]
+    fn test_count_embedded_assets() {
+        let svg = data_to_dataurl_with(
+            "image/svg+xml",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\" style=\"fill: url(#a)\"/>",
+            DataUrlEncoding::Percent,
+        );
+        let html = format!(
+            "<style>body{{background:url(\"data:image/gif;base64,R0lG\")}}</style>\
+             <link rel=\"stylesheet\" href=\"data:text/css;base64,Ym9keXt9\">\
+             <img srcset=\"data:image/png;base64,AAAA 1x, data:image/png;base64,BBBBBB 2x\">\
+             <img src=\"{}\"><script>var url = 'data:text/plain,hello';</script>\
+             <img src=\"{}\">",
+            TRANSPARENT_PIXEL,
+            svg
+        );
+        let dom = html_to_dom(&html);
+        let mut stats = ArchiveStats::default();
+
+        count_embedded_assets(&dom.document, &mut stats);
+
+        assert_eq!(stats.embedded, 5);
+        assert_eq!(stats.category_size(AssetCategory::Image), 26 + 26 + 28 + svg.len());
+        assert_eq!(stats.category_size(AssetCategory::Style), 29);
+        assert_eq!(stats.category_size(AssetCategory::Script), 0);
+    }
+}
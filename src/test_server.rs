@@ -0,0 +1,158 @@
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameBuilder, X509};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+// Serves canned raw HTTP responses on a random local port, returns the base URL;
+// paths listed more than once get their responses served in turn, the last one for good
+pub fn serve(routes: Vec<(&'static str, Vec<u8>)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        let mut hits: HashMap<String, usize> = HashMap::new();
+
+        for stream in listener.incoming() {
+            if let Ok(mut stream) = stream {
+                respond(&mut stream, &routes, &mut hits);
+            }
+        }
+    });
+
+    base_url
+}
+
+// Plain 200 response to be served, with the given type and body
+pub fn response(content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Same as serve(), but over TLS with a self-signed certificate,
+// optionally turning away clients which don't present a certificate of their own
+pub fn serve_tls(routes: Vec<(&'static str, Vec<u8>)>, require_client_cert: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("https://{}", listener.local_addr().unwrap());
+    let (key, cert) = self_signed_certificate("localhost");
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    if require_client_cert {
+        // Any certificate will do, all that matters is that one gets presented
+        acceptor.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            |_, _| true,
+        );
+    }
+    let acceptor = Arc::new(acceptor.build());
+
+    thread::spawn(move || {
+        let mut hits: HashMap<String, usize> = HashMap::new();
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                respond(&mut stream, &routes, &mut hits);
+            }
+        }
+    });
+
+    base_url
+}
+
+// PKCS#12 bundle (with an empty password) of a freshly made client certificate
+pub fn client_identity() -> Vec<u8> {
+    let (key, cert) = self_signed_certificate("client");
+
+    Pkcs12::builder()
+        .name("client")
+        .pkey(&key)
+        .cert(&cert)
+        .build2("")
+        .unwrap()
+        .to_der()
+        .unwrap()
+}
+
+// The same sort of client certificate and its private key, both PEM-encoded
+pub fn client_identity_pem() -> (Vec<u8>, Vec<u8>) {
+    let (key, cert) = self_signed_certificate("client");
+
+    (
+        cert.to_pem().unwrap(),
+        key.private_key_to_pem_pkcs8().unwrap(),
+    )
+}
+
+fn self_signed_certificate(common_name: &str) -> (PKey<Private>, X509) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (key, cert.build())
+}
+
+fn respond<S: Read + Write>(
+    stream: &mut S,
+    routes: &[(&'static str, Vec<u8>)],
+    hits: &mut HashMap<String, usize>,
+) {
+    // Read request headers
+    let mut request: Vec<u8> = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request).to_string();
+    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let responses: Vec<&Vec<u8>> = routes
+        .iter()
+        .filter(|route| route.0 == path)
+        .map(|route| &route.1)
+        .collect();
+    let hit = hits.entry(path).or_insert(0);
+    let response = responses
+        .get(*hit)
+        .or_else(|| responses.last())
+        .map(|response| response.to_vec())
+        .unwrap_or(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+        );
+    *hit += 1;
+
+    stream.write_all(&response).ok();
+}
@@ -0,0 +1,117 @@
+use clock::SharedClock;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+lazy_static! {
+    static ref HOST_THROTTLE: Mutex<HostThrottle> = Mutex::new(HostThrottle::default());
+}
+
+// Keeps track of when each host is next due to be requested
+#[derive(Debug, Default)]
+pub struct HostThrottle {
+    next_request: HashMap<String, Instant>,
+}
+
+impl HostThrottle {
+    // Books the next slot for the host, returns how long to wait until it comes
+    pub fn reserve(&mut self, host: &str, delay: Duration, now: Instant) -> Duration {
+        let slot = match self.next_request.get(host) {
+            Some(&next_request) if next_request > now => next_request,
+            _ => now,
+        };
+
+        self.next_request.insert(host.to_string(), slot + delay);
+
+        slot - now
+    }
+}
+
+pub fn wait_for_host(url: &str, delay: Duration, clock: &SharedClock) {
+    let host = match Url::parse(url) {
+        Ok(parsed_url) => match parsed_url.host_str() {
+            Some(host) => format!("{}:{}", host, parsed_url.port_or_known_default().unwrap_or(0)),
+            None => return,
+        },
+        Err(_) => return,
+    };
+
+    // Sleep without holding the lock so that other hosts don't have to wait
+    let wait = HOST_THROTTLE
+        .lock()
+        .unwrap()
+        .reserve(&host, delay, clock.now());
+
+    if wait > Duration::from_millis(0) {
+        clock.sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::Clock;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // Time only moves when asked to wait, which it then does right away
+    struct MockClock {
+        start: Instant,
+        slept: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + self.slept.lock().unwrap().iter().sum::<Duration>()
+        }
+
+        fn system_time(&self) -> SystemTime {
+            UNIX_EPOCH
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+        }
+    }
+
+    #[test]
+    fn test_host_throttle() {
+        let mut throttle = HostThrottle::default();
+        let delay = Duration::from_millis(200);
+        let start = Instant::now();
+
+        assert_eq!(throttle.reserve("a.com:80", delay, start), Duration::from_millis(0));
+        assert_eq!(throttle.reserve("b.com:80", delay, start), Duration::from_millis(0));
+
+        // Same host right away, then once more before the first wait is over
+        assert_eq!(throttle.reserve("a.com:80", delay, start), Duration::from_millis(200));
+        assert_eq!(
+            throttle.reserve("a.com:80", delay, start + Duration::from_millis(50)),
+            Duration::from_millis(350)
+        );
+
+        // Nothing to wait for once enough time has passed
+        assert_eq!(
+            throttle.reserve("b.com:80", delay, start + Duration::from_millis(300)),
+            Duration::from_millis(0)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_host() {
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let clock = SharedClock::new(MockClock {
+            start: Instant::now(),
+            slept: slept.clone(),
+        });
+        let delay = Duration::from_secs(60);
+
+        wait_for_host("http://throttled.test/a.png", delay, &clock);
+        wait_for_host("http://throttled.test/b.png", delay, &clock);
+        wait_for_host("http://throttled.test/c.png", delay, &clock);
+        wait_for_host("data:text/plain,no-host", delay, &clock);
+
+        assert_eq!(*slept.lock().unwrap(), vec![delay, delay]);
+    }
+}
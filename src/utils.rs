@@ -1,8 +1,13 @@
 extern crate base64;
 
-use self::base64::encode;
+use self::base64::{decode, encode, encode_config_buf, STANDARD};
+use std::io::{self, Read};
+use std::str;
 
-static MAGIC: [[&[u8]; 2]; 19]  = [
+pub const TRANSPARENT_PIXEL: &str = "data:image/png;base64,\
+iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+static MAGIC: [[&[u8]; 2]; 24]  = [
     // Image
     [b"GIF87a", b"image/gif"],
     [b"GIF89a", b"image/gif"],
@@ -25,18 +30,151 @@ static MAGIC: [[&[u8]; 2]; 19]  = [
     [b"\x00\x00\x01\x0B", b"video/mpeg"],
     [b"....moov", b"video/quicktime"],
     [b"\x1A\x45\xDF\xA3", b"video/webm"],
+    // Font
+    [b"OTTO", b"font/otf"],
+    [b"\x00\x01\x00\x00", b"font/ttf"],
+    [b"true", b"font/ttf"],
+    [b"wOFF", b"font/woff"],
+    [b"wOF2", b"font/woff2"],
 ];
 
+// Percent-encoding makes for smaller data URLs than base64 as long as there's
+// mostly text to encode, e.g. SVG; anything binary gets base64-encoded regardless
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataUrlEncoding {
+    Base64,
+    Percent,
+}
+
 pub fn data_to_dataurl(mime: &str, data: &[u8]) -> String {
+    data_to_dataurl_with(mime, data, DataUrlEncoding::Base64)
+}
+
+pub fn data_to_dataurl_with(mime: &str, data: &[u8], encoding: DataUrlEncoding) -> String {
     let mimetype = if mime == "" {
         detect_mimetype(data)
     } else {
         mime.to_string()
     };
-    format!("data:{};base64,{}", mimetype, encode(data))
+
+    match encoding {
+        DataUrlEncoding::Percent if str::from_utf8(data).is_ok() => {
+            format!("data:{},{}", mimetype, percent_encode(data))
+        }
+        _ => format!("data:{};base64,{}", mimetype, encode(data)),
+    }
+}
+
+// Chunks of a length divisible by three encode without padding,
+// which makes it possible to encode them one by one as they come
+const BASE64_CHUNK_SIZE: usize = 3 * 16 * 1024;
+
+// Same as data_to_dataurl(), except that the data never needs to be held in memory
+// all at once; size_hint is only there to have the data URL allocated upfront
+pub fn read_to_dataurl<R: Read>(
+    mime: &str,
+    reader: &mut R,
+    size_hint: usize,
+) -> io::Result<String> {
+    let mut dataurl = format!("data:{};base64,", mime);
+    let mut chunk = vec![0; BASE64_CHUNK_SIZE];
+
+    dataurl.reserve(size_hint.div_ceil(3) * 4);
+
+    loop {
+        let mut filled = 0;
+
+        while filled < chunk.len() {
+            match reader.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        encode_config_buf(&chunk[..filled], STANDARD, &mut dataurl);
+
+        if filled < chunk.len() {
+            return Ok(dataurl);
+        }
+    }
 }
 
-fn detect_mimetype(data: &[u8]) -> String {
+// Quotes, parentheses and whitespace get encoded too, for the result
+// to be safe to put into unquoted CSS url() values as well
+fn percent_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len());
+
+    for &byte in data {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&*+,;=:@/?".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    result
+}
+
+pub fn dataurl_to_data(url: &str) -> Option<(String, Vec<u8>)> {
+    let url = url.trim();
+
+    if !url.get(..5).is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:")) {
+        return None;
+    }
+
+    let comma = url.find(',')?;
+    let header = &url[5..comma];
+    let payload = &url[comma + 1..];
+    let (mediatype, is_base64) = if header.to_lowercase().ends_with(";base64") {
+        (&header[..header.len() - 7], true)
+    } else {
+        (header, false)
+    };
+    // Data URLs without a media type are plain text by definition
+    let mimetype = if mediatype.is_empty() {
+        "text/plain;charset=US-ASCII".to_string()
+    } else if mediatype.starts_with(';') {
+        format!("text/plain{}", mediatype)
+    } else {
+        mediatype.to_string()
+    };
+    let data = percent_decode(payload);
+
+    if is_base64 {
+        let data: Vec<u8> = data.into_iter().filter(|b| !b.is_ascii_whitespace()).collect();
+        decode(&data).ok().map(|data| (mimetype, data))
+    } else {
+        Some((mimetype, data))
+    }
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut result: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let high = (bytes[i + 1] as char).to_digit(16);
+            let low = (bytes[i + 2] as char).to_digit(16);
+
+            if let (Some(high), Some(low)) = (high, low) {
+                result.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+
+        result.push(bytes[i]);
+        i += 1;
+    }
+
+    result
+}
+
+pub fn detect_mimetype(data: &[u8]) -> String {
     let mut re = String::new();
 
     for item in MAGIC.iter()  {
@@ -49,6 +187,190 @@ fn detect_mimetype(data: &[u8]) -> String {
     re
 }
 
+// Best guess for references which come with nothing but the URL to tell what they are
+pub fn guess_mimetype(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+
+    match path.rsplit('.').next().unwrap_or("").to_lowercase().as_str() {
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "jpeg" | "jpg" => "image/jpeg",
+        "png" => "image/png",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "css" => "text/css",
+        "js" | "mjs" => "application/javascript",
+        "otf" => "font/otf",
+        "ttf" => "font/ttf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "m4a" => "audio/mp4",
+        "mp3" => "audio/mpeg",
+        "oga" | "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "ogv" => "video/ogg",
+        "webm" => "video/webm",
+        _ => "",
+    }
+}
+
+const JPEG_METADATA_MARKERS: [u8; 3] = [
+    0xE1, // APP1 (Exif, XMP)
+    0xED, // APP13 (IPTC)
+    0xFE, // COM
+];
+
+const PNG_METADATA_CHUNKS: [&[u8]; 5] = [b"eXIf", b"iTXt", b"tEXt", b"tIME", b"zTXt"];
+
+pub fn strip_image_metadata(data: Vec<u8>) -> Vec<u8> {
+    let stripped = if data.starts_with(b"\xFF\xD8\xFF") {
+        strip_jpeg_metadata(&data)
+    } else if data.starts_with(b"\x89PNG\x0D\x0A\x1A\x0A") {
+        strip_png_metadata(&data)
+    } else {
+        None
+    };
+
+    // Malformed images are left as they are
+    stripped.unwrap_or(data)
+}
+
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut result: Vec<u8> = data[..2].to_vec();
+    let mut offset = 2;
+
+    while offset + 4 <= data.len() {
+        if data[offset] != 0xFF {
+            return None;
+        }
+
+        let marker = data[offset + 1];
+
+        // Everything after the start of scan is image data
+        if marker == 0xDA {
+            result.extend_from_slice(&data[offset..]);
+            return Some(result);
+        }
+
+        let length = ((data[offset + 2] as usize) << 8) | data[offset + 3] as usize;
+        let end = offset + 2 + length;
+
+        if length < 2 || end > data.len() {
+            return None;
+        }
+
+        if !JPEG_METADATA_MARKERS.contains(&marker) {
+            result.extend_from_slice(&data[offset..end]);
+        }
+
+        offset = end;
+    }
+
+    None
+}
+
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut result: Vec<u8> = data[..8].to_vec();
+    let mut offset = 8;
+
+    while offset + 12 <= data.len() {
+        let length = ((data[offset] as usize) << 24)
+            | ((data[offset + 1] as usize) << 16)
+            | ((data[offset + 2] as usize) << 8)
+            | data[offset + 3] as usize;
+        let chunk_type = &data[offset + 4..offset + 8];
+        let end = offset + 12 + length;
+
+        if end > data.len() {
+            return None;
+        }
+
+        if !PNG_METADATA_CHUNKS.contains(&chunk_type) {
+            result.extend_from_slice(&data[offset..end]);
+        }
+
+        offset = end;
+    }
+
+    Some(result)
+}
+
+// References which didn't go through the HTML parser, e.g. candidates of srcsets within
+// stylesheets, may still have their ampersands and such escaped as character references
+pub fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let decoded = match &rest[1..end] {
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                name if name.starts_with("#x") || name.starts_with("#X") => {
+                    u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32)
+                }
+                name if name.starts_with('#') => {
+                    name[1..].parse::<u32>().ok().and_then(char::from_u32)
+                }
+                _ => None,
+            };
+
+            decoded.map(|decoded| (decoded, end))
+        });
+
+        match decoded {
+            Some((decoded, end)) => {
+                result.push(decoded);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+// Seconds since the Unix epoch as an ISO 8601 UTC date and time
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Shift the epoch to March 1st of year 0, leap days then fall at the end of a year
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,6 +386,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_read_to_dataurl() {
+        // Spans a few chunks and ends halfway through one
+        let data: Vec<u8> = (0..BASE64_CHUNK_SIZE * 3 + 7).map(|i| (i % 251) as u8).collect();
+
+        assert_eq!(
+            read_to_dataurl("application/octet-stream", &mut &data[..], data.len()).unwrap(),
+            data_to_dataurl("application/octet-stream", &data)
+        );
+        assert_eq!(
+            read_to_dataurl("text/plain", &mut &b""[..], 0).unwrap(),
+            "data:text/plain;base64,"
+        );
+    }
+
+    #[test]
+    fn test_data_to_dataurl_with() {
+        let svg = b"<svg xmlns='http://www.w3.org/2000/svg'/>";
+        let datauri = data_to_dataurl_with("image/svg+xml", svg, DataUrlEncoding::Percent);
+
+        assert_eq!(
+            &datauri,
+            "data:image/svg+xml,%3Csvg%20xmlns=%27http://www.w3.org/2000/svg%27/%3E"
+        );
+        assert_eq!(
+            dataurl_to_data(&datauri),
+            Some(("image/svg+xml".to_string(), svg.to_vec()))
+        );
+        assert_eq!(
+            data_to_dataurl_with("image/png", b"\x89PNG", DataUrlEncoding::Percent),
+            "data:image/png;base64,iVBORw=="
+        );
+    }
+
     #[test]
     fn test_detect_mimetype() {
         // Image
@@ -88,5 +444,99 @@ mod tests {
         assert_eq!(detect_mimetype(b"\x00\x00\x01\x0B"), "video/mpeg");
         assert_eq!(detect_mimetype(b"....moov"), "video/quicktime");
         assert_eq!(detect_mimetype(b"\x1A\x45\xDF\xA3"), "video/webm");
+        // Font
+        assert_eq!(detect_mimetype(b"OTTO"), "font/otf");
+        assert_eq!(detect_mimetype(b"\x00\x01\x00\x00"), "font/ttf");
+        assert_eq!(detect_mimetype(b"true"), "font/ttf");
+        assert_eq!(detect_mimetype(b"wOFF"), "font/woff");
+        assert_eq!(detect_mimetype(b"wOF2"), "font/woff2");
+    }
+
+    #[test]
+    fn test_guess_mimetype() {
+        assert_eq!(guess_mimetype("https://cdn.site.com/clip.MP4?t=30#hd"), "video/mp4");
+        assert_eq!(guess_mimetype("fonts/icons.woff2"), "font/woff2");
+        assert_eq!(guess_mimetype("logo.svg"), "image/svg+xml");
+        assert_eq!(guess_mimetype("https://site.com/"), "");
+    }
+
+    #[test]
+    fn test_strip_image_metadata_jpeg() {
+        let jfif = b"\xFF\xE0\x00\x07JFIF\x00";
+        let exif = b"\xFF\xE1\x00\x0CExif\x00\x00GPS!";
+        let quantization = b"\xFF\xDB\x00\x04\x00\x01";
+        let scan = b"\xFF\xDA\x00\x02\x12\x34\xFF\xD9";
+        let jpeg = [&b"\xFF\xD8"[..], jfif, exif, quantization, scan].concat();
+
+        assert_eq!(
+            strip_image_metadata(jpeg),
+            [&b"\xFF\xD8"[..], jfif, quantization, scan].concat()
+        );
+    }
+
+    #[test]
+    fn test_strip_image_metadata_png() {
+        let signature = b"\x89PNG\x0D\x0A\x1A\x0A";
+        let header = b"\x00\x00\x00\x01IHDR\x00CRC!";
+        let text = b"\x00\x00\x00\x06tEXtAuthorCRC!";
+        let end = b"\x00\x00\x00\x00IENDCRC!";
+        let png = [&signature[..], header, text, end].concat();
+
+        assert_eq!(
+            strip_image_metadata(png),
+            [&signature[..], header, end].concat()
+        );
+    }
+
+    #[test]
+    fn test_strip_image_metadata_other() {
+        assert_eq!(strip_image_metadata(b"GIF89a".to_vec()), b"GIF89a".to_vec());
+        assert_eq!(
+            strip_image_metadata(b"\xFF\xD8\xFF\xE1\xFF\xFF".to_vec()),
+            b"\xFF\xD8\xFF\xE1\xFF\xFF".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1791302399), "2026-10-06T15:59:59Z");
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("a.png?w=1&amp;h=2"), "a.png?w=1&h=2");
+        assert_eq!(decode_entities("&quot;a&#39;b&#x26;c&quot;"), "\"a'b&c\"");
+        assert_eq!(decode_entities("a&b=1&copy;&amp"), "a&b=1&copy;&amp");
+        assert_eq!(decode_entities("&#xD800;&amp;amp;"), "&#xD800;&amp;");
+    }
+
+    #[test]
+    fn test_dataurl_to_data() {
+        let data = b"\x89PNG\x0D\x0A\x1A\x0A\x00\xFF";
+
+        assert_eq!(
+            dataurl_to_data(&data_to_dataurl("image/png", data)),
+            Some(("image/png".to_string(), data.to_vec()))
+        );
+        assert_eq!(
+            dataurl_to_data(&data_to_dataurl("", b"GIF89a")),
+            Some(("image/gif".to_string(), b"GIF89a".to_vec()))
+        );
+        assert_eq!(
+            dataurl_to_data("data:text/html;charset=utf-8,%3Cp%3EHi%21%3C%2Fp%3E"),
+            Some(("text/html;charset=utf-8".to_string(), b"<p>Hi!</p>".to_vec()))
+        );
+        assert_eq!(
+            dataurl_to_data("DATA:,100%25%"),
+            Some(("text/plain;charset=US-ASCII".to_string(), b"100%%".to_vec()))
+        );
+        assert_eq!(
+            dataurl_to_data("data:;charset=utf-8;base64,SGk="),
+            Some(("text/plain;charset=utf-8".to_string(), b"Hi".to_vec()))
+        );
+        assert_eq!(dataurl_to_data("data:image/png;base64,!!!"), None);
+        assert_eq!(dataurl_to_data("https://example.com/,"), None);
     }
 }
@@ -0,0 +1,120 @@
+use html::DomVisitor;
+use html5ever::rcdom::{Handle, NodeData};
+use pretty::PREFORMATTED_ELEMENTS;
+
+// Whitespace next to these, or in between them, doesn't make it onto the screen
+const BLOCK_ELEMENTS: [&str; 49] = [
+    "address", "article", "aside", "base", "blockquote", "body", "caption", "dd", "details",
+    "dialog", "div", "dl", "dt", "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2",
+    "h3", "h4", "h5", "h6", "head", "header", "hgroup", "hr", "legend", "li", "link", "main",
+    "menu", "meta", "nav", "ol", "p", "pre", "section", "summary", "table", "tbody", "td", "tfoot",
+    "th", "thead", "title", "tr", "ul",
+];
+
+// Text right within these is never rendered, whitespace included
+const NO_TEXT_ELEMENTS: [&str; 9] = [
+    "colgroup", "head", "html", "select", "table", "tbody", "tfoot", "thead", "tr",
+];
+
+// Takes out whitespace-only text which makes no difference to how the page looks,
+// e.g. indentation between blocks; whitespace between inline elements is kept,
+// albeit cut down to a single space, and preformatted text is left as it is
+pub struct WhitespaceCollapser;
+
+impl DomVisitor for WhitespaceCollapser {
+    fn visit(&mut self, document: &Handle, _url: &str) {
+        collapse_whitespace(document);
+    }
+}
+
+fn collapse_whitespace(node: &Handle) {
+    let name = element_name(node).unwrap_or("");
+
+    if PREFORMATTED_ELEMENTS.contains(&name) {
+        return;
+    }
+
+    let children: Vec<Handle> = node.children.borrow().clone();
+    let mut kept: Vec<Handle> = Vec::with_capacity(children.len());
+
+    for (i, child) in children.iter().enumerate() {
+        if let NodeData::Text { ref contents } = child.data {
+            if is_blank(child) {
+                // Comments and other whitespace aside, what matters is what is on either side
+                let previous = children[..i].iter().rev().find(|node| !is_invisible(node));
+                let next = children[i + 1..].iter().find(|node| !is_invisible(node));
+
+                if NO_TEXT_ELEMENTS.contains(&name)
+                    || (is_block_boundary(previous) && is_block_boundary(next))
+                {
+                    continue;
+                }
+
+                contents.replace(" ".into());
+            }
+        }
+
+        kept.push(child.clone());
+    }
+
+    for child in kept.iter() {
+        collapse_whitespace(child);
+    }
+
+    *node.children.borrow_mut() = kept;
+}
+
+fn element_name(node: &Handle) -> Option<&str> {
+    match node.data {
+        NodeData::Element { ref name, .. } => Some(name.local.as_ref()),
+        _ => None,
+    }
+}
+
+fn is_blank(node: &Handle) -> bool {
+    match node.data {
+        NodeData::Text { ref contents } => {
+            contents.borrow().bytes().all(|b| b.is_ascii_whitespace())
+        }
+        _ => false,
+    }
+}
+
+fn is_invisible(node: &Handle) -> bool {
+    match node.data {
+        NodeData::Comment { .. } => true,
+        _ => is_blank(node),
+    }
+}
+
+fn is_block_boundary(node: Option<&Handle>) -> bool {
+    match node {
+        Some(node) => element_name(node).is_some_and(|name| BLOCK_ELEMENTS.contains(&name)),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html::html_to_dom;
+    use html5ever::serialize::{serialize, SerializeOpts};
+
+    #[test]
+    fn test_collapse_whitespace() {
+        let html = "<div>\n  <p>One</p>\n  <!-- two -->\n  <p>Two <b>bold</b>\n    \
+                    <!-- and --> <i>italic</i></p>\n</div>\n<pre>  keep\n    this\n</pre>";
+        let dom = html_to_dom(&html);
+
+        WhitespaceCollapser.visit(&dom.document, "http://localhost/");
+
+        let mut buf: Vec<u8> = Vec::new();
+        serialize(&mut buf, &dom.document, SerializeOpts::default()).unwrap();
+
+        assert_eq!(
+            buf.iter().map(|&c| c as char).collect::<String>(),
+            "<html><head></head><body><div><p>One</p><!-- two --><p>Two <b>bold</b> \
+             <!-- and --> <i>italic</i></p></div><pre>  keep\n    this\n</pre></body></html>"
+        );
+    }
+}